@@ -0,0 +1,343 @@
+// map tooling that works on files directly, no server needed. formats are
+// picked by extension like everywhere else
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dandelion::server::config::Config;
+use dandelion::server::formats::{self, anvil, palette::Palette, MapFormat, LAST_CPE_BLOCK};
+use dandelion::server::game::dmf_map::DmfMap;
+use dandelion::server::map_builder::{Dimensions, MapBuilder, NoiseLayer, PresetParams};
+
+const USAGE: &str = "usage: dandelion-map <command>
+
+  info <map>                                           size, spawn, metadata and blocks
+  convert <input> <output>                             change the format
+  anvil <region folder> <x1> <y1> <z1> <x2> <y2> <z2> <output>
+                                                       a box of a java edition world
+  generate <flat|noise|island> <output> [options]      a new map
+      --size <x>x<y>x<z>          256x64x256 if not given
+      --ground <height>           flat only, half the height if not given
+      --layer <scale>:<weight>    noise and island, can be repeated
+  resize <input> <output> <x> <y> <z>                  grow or shrink from 0, 0, 0
+  crop <input> <output> <x1> <y1> <z1> <x2> <y2> <z2>  keep the blocks between two corners
+  check <map>...                                       look for damaged maps";
+
+const DEFAULT_SIZE: (i16, i16, i16) = (256, 64, 256);
+// how far the noise layers reach above and below the middle of the map
+const DEFAULT_HILLS: f64 = 0.15;
+
+type CliResult<T = ()> = Result<T, Box<dyn std::error::Error>>;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some((command, args)) = args.split_first() else {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    };
+    let result = match command.as_str() {
+        "info" => info(args),
+        "convert" => convert(args),
+        "anvil" => import_anvil(args),
+        "generate" => generate(args),
+        "resize" => resize(args),
+        "crop" => crop(args),
+        "check" => check(args),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(format!("unknown command {}\n\n{}", command, USAGE).into()),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn info(args: &[String]) -> CliResult {
+    let [path] = args else {
+        return Err("usage: dandelion-map info <map>".into());
+    };
+    let path = Path::new(path);
+    let map = formats::load(path)?;
+    let format = MapFormat::of(path).map_or("?", MapFormat::extension);
+    let volume = map.blocks.len();
+
+    println!("{} (.{})", path.display(), format);
+    println!(
+        "size: {}x{}x{}, {} blocks",
+        map.x_size, map.y_size, map.z_size, volume
+    );
+    println!(
+        "spawn: {}, {}, {} facing {}, {}",
+        map.x_spawn, map.y_spawn, map.z_spawn, map.metadata.spawn_yaw, map.metadata.spawn_pitch
+    );
+    if let Some(author) = &map.metadata.author {
+        println!("author: {}", author);
+    }
+    if let Some(generator) = &map.metadata.generator {
+        println!("generator: {}", generator);
+    }
+    if let Some(seed) = map.metadata.seed {
+        println!("seed: {}", seed);
+    }
+    if let Some(created_at) = map.metadata.created_at {
+        println!("created: {} (unix time)", created_at);
+    }
+    if !map.metadata.block_definitions.is_empty() {
+        println!("custom blocks: {}", map.metadata.block_definitions.len());
+    }
+
+    let mut counts: Vec<(u8, usize)> = map
+        .block_counts()
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0)
+        .map(|(block, count)| (block as u8, *count))
+        .collect();
+    counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let palette = Palette::default();
+    println!("blocks:");
+    for (block, count) in counts {
+        println!(
+            "  {:>3} {:<24} {:>10} {:>6.2}%",
+            block,
+            block_name(&map, &palette, block),
+            count,
+            count as f64 * 100.0 / volume as f64
+        );
+    }
+    Ok(())
+}
+
+// custom blocks go by their definition, the rest by the java edition block
+// they stand for
+fn block_name(map: &DmfMap, palette: &Palette, block: u8) -> String {
+    if let Some(definition) = map
+        .metadata
+        .block_definitions
+        .iter()
+        .find(|definition| definition.id == block)
+    {
+        return definition.name.clone();
+    }
+    if block > LAST_CPE_BLOCK {
+        return "unknown".to_string();
+    }
+    let state = palette.modern(block);
+    let name = state.split('[').next().unwrap_or(state);
+    name.trim_start_matches("minecraft:").to_string()
+}
+
+fn convert(args: &[String]) -> CliResult {
+    let [input, output] = args else {
+        return Err("usage: dandelion-map convert <input> <output>".into());
+    };
+    formats::convert(Path::new(input), Path::new(output))?;
+    Ok(())
+}
+
+// the block mapping comes from the server config when there is one next to us
+fn import_anvil(args: &[String]) -> CliResult {
+    let usage = "usage: dandelion-map anvil <region folder> <x1> <y1> <z1> <x2> <y2> <z2> <output>";
+    let [dir, corners @ .., output] = args else {
+        return Err(usage.into());
+    };
+    let corners = corners
+        .iter()
+        .map(|coordinate| coordinate.parse::<i32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| usage)?;
+    let [x1, y1, z1, x2, y2, z2] = corners[..] else {
+        return Err(usage.into());
+    };
+
+    // reading the config shouldn't write a default one next to a world
+    let config = if Path::new("server-config.yml").exists() {
+        Config::load("server-config.yml")?
+    } else {
+        Config::default()
+    };
+    let palette = Palette::new(&config.block_palette).with_mapping(&config.modern_block_mapping);
+    let map = anvil::import(Path::new(dir), (x1, y1, z1), (x2, y2, z2), &palette)?;
+    formats::save(&map, Path::new(output))?;
+    Ok(())
+}
+
+fn generate(args: &[String]) -> CliResult {
+    let usage = "usage: dandelion-map generate <flat|noise|island> <output> [--size <x>x<y>x<z>] [--ground <height>] [--layer <scale>:<weight>]...";
+    let [preset, output, options @ ..] = args else {
+        return Err(usage.into());
+    };
+    if !matches!(preset.as_str(), "flat" | "noise" | "island") {
+        return Err(format!("unknown preset {}, there is flat, noise and island", preset).into());
+    }
+
+    let mut size = DEFAULT_SIZE;
+    let mut ground = None;
+    let mut layers = Vec::new();
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let Some(value) = options.next() else {
+            return Err(usage.into());
+        };
+        match option.as_str() {
+            "--size" => size = parse_size(value)?,
+            "--ground" => {
+                ground = Some(
+                    value
+                        .parse::<u32>()
+                        .map_err(|_| format!("bad ground height {}", value))?,
+                )
+            }
+            "--layer" => layers.push(parse_layer(value)?),
+            _ => return Err(usage.into()),
+        }
+    }
+
+    let (x, y, z) = size;
+    let ground = ground.unwrap_or(y as u32 / 2);
+    // the builder leaves 4 blocks of dirt and grass on top of the ground or
+    // the hills, and the island preset fills water up to 31
+    match preset.as_str() {
+        "flat" if ground + 4 > y as u32 => {
+            return Err(format!(
+                "a ground of {} doesn't fit in {} blocks of height",
+                ground, y
+            )
+            .into())
+        }
+        "island" if y < 34 => return Err("island maps need to be at least 34 blocks high".into()),
+        _ => {}
+    }
+    if layers.is_empty() {
+        let hills = y as f64 * DEFAULT_HILLS;
+        layers = vec![
+            NoiseLayer {
+                scale: 150.0,
+                weight: hills * 0.75,
+            },
+            NoiseLayer {
+                scale: 40.0,
+                weight: hills * 0.25,
+            },
+        ];
+    }
+    let reach: f64 = layers.iter().map(|layer| layer.weight.abs()).sum();
+    if preset != "flat" && y as f64 / 2.0 + reach + 4.0 > y as f64 {
+        return Err(format!(
+            "layers reaching {:.0} blocks don't fit in {} blocks of height",
+            reach, y
+        )
+        .into());
+    }
+
+    let params = PresetParams {
+        flat_ground_level: ground,
+        noise_layers: layers,
+        dimensions: Dimensions { x, y, z },
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    let mut map = runtime.block_on(MapBuilder::create_map(preset, Some(params)));
+    if !map.contains(map.x_spawn, map.y_spawn, map.z_spawn) {
+        let (x, y, z) = map.ground_spawn();
+        map.set_spawn_point(x, y, z);
+    }
+    map.metadata.generator = Some(preset.clone());
+    map.metadata.created_at = Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+    formats::save(&map, Path::new(output))?;
+    Ok(())
+}
+
+fn parse_size(value: &str) -> CliResult<(i16, i16, i16)> {
+    let sizes: Vec<i16> = value
+        .split('x')
+        .map(|size| size.parse::<i16>().ok().filter(|size| *size > 0))
+        .collect::<Option<_>>()
+        .ok_or_else(|| format!("bad size {}, sizes go from 1 to {}", value, i16::MAX))?;
+    let [x, y, z] = sizes[..] else {
+        return Err(format!("bad size {}, expected <x>x<y>x<z>", value).into());
+    };
+    Ok((x, y, z))
+}
+
+fn parse_layer(value: &str) -> CliResult<NoiseLayer> {
+    let layer = value.split_once(':').and_then(|(scale, weight)| {
+        Some(NoiseLayer {
+            scale: scale.parse().ok().filter(|scale: &f64| *scale > 0.0)?,
+            weight: weight
+                .parse()
+                .ok()
+                .filter(|weight: &f64| weight.is_finite())?,
+        })
+    });
+    layer.ok_or_else(|| format!("bad layer {}, expected <scale>:<weight>", value).into())
+}
+
+fn resize(args: &[String]) -> CliResult {
+    let usage = "usage: dandelion-map resize <input> <output> <x> <y> <z>";
+    let [input, output, x, y, z] = args else {
+        return Err(usage.into());
+    };
+    let size = parse_size(&format!("{}x{}x{}", x, y, z))?;
+    let map = formats::load(Path::new(input))?;
+    formats::save(&map.cropped((0, 0, 0), size), Path::new(output))?;
+    Ok(())
+}
+
+fn crop(args: &[String]) -> CliResult {
+    let usage = "usage: dandelion-map crop <input> <output> <x1> <y1> <z1> <x2> <y2> <z2>";
+    let [input, output, corners @ ..] = args else {
+        return Err(usage.into());
+    };
+    let corners = corners
+        .iter()
+        .map(|coordinate| coordinate.parse::<i16>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| usage)?;
+    let [x1, y1, z1, x2, y2, z2] = corners[..] else {
+        return Err(usage.into());
+    };
+
+    let map = formats::load(Path::new(input))?;
+    let from = (x1.min(x2), y1.min(y2), z1.min(z2));
+    let to = (x1.max(x2), y1.max(y2), z1.max(z2));
+    if !map.contains(from.0, from.1, from.2) || !map.contains(to.0, to.1, to.2) {
+        return Err(format!(
+            "both corners need to be inside the map, it is {}x{}x{}",
+            map.x_size, map.y_size, map.z_size
+        )
+        .into());
+    }
+    let size = (to.0 - from.0 + 1, to.1 - from.1 + 1, to.2 - from.2 + 1);
+    formats::save(&map.cropped(from, size), Path::new(output))?;
+    Ok(())
+}
+
+// every map is read in full, so broken headers, checksums and block data all
+// show up, along with things that load but would confuse clients
+fn check(args: &[String]) -> CliResult {
+    if args.is_empty() {
+        return Err("usage: dandelion-map check <map>...".into());
+    }
+    let mut damaged = 0;
+    for path in args {
+        let problems = match formats::load(Path::new(path)) {
+            Ok(map) => map.problems(),
+            Err(e) => vec![e.to_string()],
+        };
+        if problems.is_empty() {
+            println!("{}: ok", path);
+        } else {
+            damaged += 1;
+            for problem in problems {
+                println!("{}: {}", path, problem);
+            }
+        }
+    }
+    if damaged > 0 {
+        return Err(format!("{} of {} maps have problems", damaged, args.len()).into());
+    }
+    Ok(())
+}
@@ -1,8 +1,6 @@
 use std::sync::Arc;
 
-use server::server::Server;
-
-mod server;
+use dandelion::server::server::Server;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -0,0 +1,213 @@
+use crate::server::files::copy_atomically;
+use crate::server::formats::MapFormat;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// timestamped copies of saved maps in <dir>/<map name>/, only the newest
+// `keep` of each map are kept
+#[derive(Debug, Clone)]
+pub struct Backups {
+    dir: PathBuf,
+    keep: usize,
+}
+
+impl Backups {
+    pub fn new(dir: impl Into<PathBuf>, keep: usize) -> Self {
+        Self {
+            dir: dir.into(),
+            keep,
+        }
+    }
+
+    // copies the map file and returns the id of the new backup, the copy
+    // keeps the map's format
+    pub fn create(&self, map_name: &str, map_file: &Path) -> io::Result<String> {
+        let map_dir = self.dir.join(map_name);
+        fs::create_dir_all(&map_dir)?;
+
+        let id = self.free_id(map_name, SystemTime::now())?;
+        let mut backup = map_dir.join(&id);
+        if let Some(extension) = map_file.extension() {
+            backup.set_extension(extension);
+        }
+        copy_atomically(map_file, &backup)?;
+        self.prune(map_name)?;
+        Ok(id)
+    }
+
+    // backup ids of a map, newest first
+    pub fn list(&self, map_name: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .entries(map_name)?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect())
+    }
+
+    // only ids that were listed, so a player can't point us outside the folder
+    pub fn path_of(&self, map_name: &str, id: &str) -> io::Result<Option<PathBuf>> {
+        Ok(self
+            .entries(map_name)?
+            .into_iter()
+            .find(|(listed, _)| listed == id)
+            .map(|(_, path)| path))
+    }
+
+    // the timestamp, with _2, _3 and so on after it when there already is a
+    // backup from that second
+    fn free_id(&self, map_name: &str, time: SystemTime) -> io::Result<String> {
+        let taken = self.list(map_name)?;
+        let time = timestamp(time);
+        let mut id = time.clone();
+        let mut n = 1;
+        while taken.contains(&id) {
+            n += 1;
+            id = format!("{}_{}", time, n);
+        }
+        Ok(id)
+    }
+
+    fn entries(&self, map_name: &str) -> io::Result<Vec<(String, PathBuf)>> {
+        let entries = match fs::read_dir(self.dir.join(map_name)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut backups = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if MapFormat::of(&path).is_none() {
+                continue;
+            }
+            if let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) {
+                backups.push((id.to_string(), path.clone()));
+            }
+        }
+        backups.sort_unstable_by(|a, b| sort_key(&b.0).cmp(&sort_key(&a.0)));
+        Ok(backups)
+    }
+
+    fn prune(&self, map_name: &str) -> io::Result<()> {
+        for (_, path) in self.entries(map_name)?.iter().skip(self.keep.max(1)) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+// utc, in a form that is safe in file names and sorts by time
+fn timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let seconds_of_day = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}_{:02}-{:02}-{:02}",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+// timestamps sort the same as their text, the number after a second backup
+// in the same second doesn't
+fn sort_key(id: &str) -> (&str, u32) {
+    id.rsplit_once('_')
+        .and_then(|(time, n)| Some((time, n.parse().ok()?)))
+        .unwrap_or((id, 1))
+}
+
+// days since 1970-01-01 to a calendar date, see
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn formats_timestamps() {
+        let at = |secs| timestamp(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "1970-01-01_00-00-00");
+        assert_eq!(at(951_782_400), "2000-02-29_00-00-00");
+        assert_eq!(at(1_700_000_000), "2023-11-14_22-13-20");
+    }
+
+    #[test]
+    fn keeps_only_the_newest_backups() {
+        let dir = std::env::temp_dir().join(format!("dandelion-backups-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let backups = Backups::new(&dir, 2);
+        let map_dir = dir.join("hub");
+        fs::create_dir_all(&map_dir).unwrap();
+        for id in ["2001-01-01_00-00-00", "2002-01-01_00-00-00"] {
+            fs::write(map_dir.join(format!("{}.dmf", id)), id).unwrap();
+        }
+        let map_file = dir.join("hub.dmf");
+        fs::write(&map_file, b"current").unwrap();
+
+        let id = backups.create("hub", &map_file).unwrap();
+        assert_eq!(
+            backups.list("hub").unwrap(),
+            vec![id.clone(), "2002-01-01_00-00-00".to_string()]
+        );
+        let path = backups.path_of("hub", &id).unwrap().unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"current");
+        assert_eq!(backups.path_of("hub", "2001-01-01_00-00-00").unwrap(), None);
+        assert_eq!(backups.path_of("hub", "../hub").unwrap(), None);
+        assert!(backups.list("nothing").unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn numbers_backups_made_in_the_same_second() {
+        let dir =
+            std::env::temp_dir().join(format!("dandelion-same-second-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let backups = Backups::new(&dir, 20);
+        let map_dir = dir.join("hub");
+        fs::create_dir_all(&map_dir).unwrap();
+        let at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        for n in 1..=11 {
+            let id = backups.free_id("hub", at).unwrap();
+            let expected = match n {
+                1 => "2023-11-14_22-13-20".to_string(),
+                n => format!("2023-11-14_22-13-20_{}", n),
+            };
+            assert_eq!(id, expected);
+            fs::write(map_dir.join(format!("{}.dmf", id)), b"").unwrap();
+        }
+        fs::write(map_dir.join("2023-11-14_22-13-21.dmf"), b"").unwrap();
+        let listed = backups.list("hub").unwrap();
+        assert_eq!(listed[0], "2023-11-14_22-13-21");
+        assert_eq!(listed[1], "2023-11-14_22-13-20_11");
+        assert_eq!(listed[2], "2023-11-14_22-13-20_10");
+        assert_eq!(listed[11], "2023-11-14_22-13-20");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,44 +1,183 @@
-use serde::{Deserialize, Serialize};
+use crate::server::game::player::Rank;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
 use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 
+// what happens when someone logs in with a name that is already playing
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateLoginPolicy {
+    // the old session is kicked and the new one takes over where it left off
+    #[default]
+    KickOld,
+    // the new connection is turned away
+    RejectNew,
+}
+
+// one address to accept players on
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ListenerConfig {
+    // "0.0.0.0:25565" for ipv4, "[::]:25565" for ipv6 (and ipv4 too, unless
+    // another listener takes ipv4 on the same port)
+    pub addr: SocketAddr,
+    // only takes connections from private addresses and lets them in without
+    // verifying their name
+    #[serde(default)]
+    pub lan_only: bool,
+    // web clients can connect here as well as regular ones
+    #[serde(default)]
+    pub websocket: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 pub struct Config {
-    pub addr: String,
-    pub port: u16,
-    pub heartbeat_url: String,
+    pub listeners: Vec<ListenerConfig>,
+    // the old single address, turned into one listener when listeners isn't set
+    #[serde(skip_serializing)]
+    pub addr: Option<IpAddr>,
+    #[serde(skip_serializing)]
+    pub port: Option<u16>,
+    // server lists to announce to, a single string (the old heartbeat_url) works too
+    #[serde(alias = "heartbeat_url", deserialize_with = "one_or_many")]
+    pub heartbeat_urls: Vec<String>,
+    // the play urls handed back by the lists end up in here
+    pub play_url_file: String,
     pub name: String,
     pub motd: String,
     pub public: bool,
     pub do_user_auth: bool,
     pub max_players: u32,
     pub default_map: String,
+    // maps that stay loaded even with nobody on them, the default map always does
+    pub pinned_maps: Vec<String>,
+    // seconds a map has to be empty before it is saved and unloaded
+    pub map_idle_unload_secs: u64,
+    // seconds between saves of maps that changed
+    pub map_save_interval_secs: u64,
+    // least seconds between two backups of the same map, 0 turns them off
+    pub map_backup_interval_secs: u64,
+    // backups kept per map, older ones are deleted
+    pub map_backups_kept: usize,
+    // classic block id -> modern block state, on top of the built-in table
+    // schematics are translated with
+    pub block_palette: HashMap<u8, String>,
+    // modern block -> classic id for java edition imports, with or without
+    // [properties], checked before the built-in table
+    pub modern_block_mapping: HashMap<String, u8>,
+    // how many movement updates per second are sent out for each player
+    pub movement_broadcast_rate: u32,
+    // players further away than this many blocks are not spawned for each other
+    pub view_distance: u32,
+    // seconds a client gets to identify and finish loading before being kicked
+    pub login_timeout_secs: u64,
+    // slots out of max_players that only staff can take
+    pub reserved_slots: u32,
+    // how many players can wait for a free slot, 0 kicks them right away
+    pub join_queue_size: u32,
+    // username -> rank, everyone else is a guest
+    pub ranks: HashMap<String, Rank>,
+    pub duplicate_login: DuplicateLoginPolicy,
+    // open connections allowed from one address, 0 for no limit
+    pub max_connections_per_ip: u32,
+    // new connections one address can open per minute, 0 for no limit
+    pub connections_per_minute: u32,
+    // packets one address can keep sending every second over all of its
+    // connections, plus how many it may send at once on top of that
+    pub packets_per_second: u32,
+    pub packet_burst: u32,
+    // chat messages one address can send every second, plus a short burst
+    pub chat_per_second: u32,
+    pub chat_burst: u32,
+    // how long an address that broke one of the limits above stays blocked
+    pub ip_block_secs: u64,
+    // expect a PROXY protocol header (v1 or v2) on every connection and use
+    // the address in it, connections without one are dropped
+    pub proxy_protocol: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
-            addr: "0.0.0.0".to_string(),
-            port: 25565,
-            heartbeat_url: "https://www.classicube.net/server/heartbeat".to_string(),
+            listeners: vec![ListenerConfig {
+                addr: SocketAddr::from(([0, 0, 0, 0], 25565)),
+                lan_only: false,
+                websocket: false,
+            }],
+            addr: None,
+            port: None,
+            heartbeat_urls: vec!["https://www.classicube.net/server/heartbeat".to_string()],
+            play_url_file: "play-url.txt".to_string(),
             name: "A classic server".to_string(),
             motd: "dandelion powered".to_string(),
             public: true,
             do_user_auth: true,
             max_players: 64,
             default_map: "default".to_string(),
+            pinned_maps: Vec::new(),
+            map_idle_unload_secs: 300,
+            map_save_interval_secs: 90,
+            map_backup_interval_secs: 3600,
+            map_backups_kept: 24,
+            block_palette: HashMap::new(),
+            modern_block_mapping: HashMap::new(),
+            movement_broadcast_rate: 20,
+            view_distance: 128,
+            login_timeout_secs: 10,
+            reserved_slots: 0,
+            join_queue_size: 0,
+            ranks: HashMap::new(),
+            duplicate_login: DuplicateLoginPolicy::KickOld,
+            max_connections_per_ip: 5,
+            connections_per_minute: 20,
+            packets_per_second: 60,
+            packet_burst: 120,
+            chat_per_second: 2,
+            chat_burst: 5,
+            ip_block_secs: 60,
+            proxy_protocol: false,
         }
     }
 }
 
 impl Config {
+    // the listener server lists send players to, the first one that isn't lan only
+    pub fn public_listener(&self) -> Option<&ListenerConfig> {
+        self.listeners.iter().find(|listener| !listener.lan_only)
+    }
+
+    pub fn rank_of(&self, username: &str) -> Rank {
+        self.ranks
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(username))
+            .map(|(_, rank)| *rank)
+            .unwrap_or_default()
+    }
+
+    fn from_yaml(content: &str) -> Result<Self, serde_yaml::Error> {
+        let mut config: Config = serde_yaml::from_str(content)?;
+        let legacy = (config.addr.take(), config.port.take());
+        if legacy != (None, None) && config.listeners == Config::default().listeners {
+            let default = Config::default().listeners.remove(0);
+            config.listeners = vec![ListenerConfig {
+                addr: SocketAddr::new(
+                    legacy.0.unwrap_or(default.addr.ip()),
+                    legacy.1.unwrap_or(default.addr.port()),
+                ),
+                ..default
+            }];
+        }
+        Ok(config)
+    }
+
     pub fn load(file_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
         if Path::new(file_path).exists() {
             let config_content = fs::read_to_string(file_path)?;
-            let config = serde_yaml::from_str(&config_content)?;
-            Ok(config)
+            Ok(Self::from_yaml(&config_content)?)
         } else {
             let default_config = Config::default();
             let config_yaml = serde_yaml::to_string(&default_config)?;
@@ -48,3 +187,58 @@ impl Config {
         }
     }
 }
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turns_the_old_addr_and_port_into_a_listener() {
+        let config = Config::from_yaml("addr: 127.0.0.1\nport: 25570\n").unwrap();
+        assert_eq!(
+            config.listeners,
+            vec![ListenerConfig {
+                addr: SocketAddr::from(([127, 0, 0, 1], 25570)),
+                lan_only: false,
+                websocket: false,
+            }]
+        );
+
+        let config = Config::from_yaml("port: 25570\n").unwrap();
+        assert_eq!(
+            config.listeners[0].addr,
+            SocketAddr::from(([0, 0, 0, 0], 25570))
+        );
+    }
+
+    #[test]
+    fn listeners_win_over_the_old_keys() {
+        let config =
+            Config::from_yaml("port: 25570\nlisteners:\n- addr: 127.0.0.1:25600\n").unwrap();
+        assert_eq!(config.listeners.len(), 1);
+        assert_eq!(
+            config.listeners[0].addr,
+            SocketAddr::from(([127, 0, 0, 1], 25600))
+        );
+    }
+
+    #[test]
+    fn the_old_keys_are_not_written_back() {
+        let yaml = serde_yaml::to_string(&Config::default()).unwrap();
+        assert!(!yaml.contains("\nport:") && !yaml.contains("\naddr:"));
+    }
+}
@@ -0,0 +1,50 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // a packet frame ended before everything in it could be read
+    TruncatedPacket { needed: usize, remaining: usize },
+    InvalidMap(String),
+    // missing or broken PROXY protocol header
+    ProxyProtocol(String),
+    // a server list didn't take our heartbeat
+    Heartbeat(String),
+    // the websocket handshake with a web client failed
+    WebSocket(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::TruncatedPacket { needed, remaining } => write!(
+                f,
+                "packet too short, needed {} more bytes but only {} were left",
+                needed, remaining
+            ),
+            Error::InvalidMap(reason) => write!(f, "invalid map: {}", reason),
+            Error::ProxyProtocol(reason) => write!(f, "bad proxy header: {}", reason),
+            Error::Heartbeat(reason) => write!(f, "heartbeat rejected: {}", reason),
+            Error::WebSocket(reason) => write!(f, "websocket handshake failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
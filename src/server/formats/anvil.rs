@@ -0,0 +1,487 @@
+use crate::server::error::{Error, Result};
+use crate::server::formats::nbt::{self, Compound, Tag};
+use crate::server::formats::palette::Palette;
+use crate::server::formats::read_exact;
+use crate::server::game::dmf_map::DmfMap;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+// java edition worlds from 1.13 on, as anvil region files. each r.X.Z.mca
+// holds 32x32 chunks behind a table of where each one starts, and every chunk
+// is compressed nbt with its blocks in 16³ sections of palette indices
+
+const SECTOR_SIZE: u64 = 4096;
+const CHUNKS_PER_REGION: i32 = 32;
+const SECTION_VOLUME: usize = 16 * 16 * 16;
+
+const COMPRESSION_GZIP: u8 = 1;
+const COMPRESSION_ZLIB: u8 = 2;
+const COMPRESSION_NONE: u8 = 3;
+// the chunk didn't fit in the region and lives in a c.X.Z.mcc file of its own
+const COMPRESSION_EXTERNAL: u8 = 0x80;
+
+// 1.18 moved sections out of the Level compound and renamed them
+const DATA_VERSION_1_18: i64 = 2844;
+// before 1.16 palette indices could be split across two longs
+const DATA_VERSION_1_16: i64 = 2529;
+
+const AIR: u8 = 0x00;
+// every block of the box is allocated before the first chunk is read, so a
+// box past this is turned away rather than risking the memory
+const MAX_VOLUME: u64 = 1 << 28;
+
+// blocks between two corners of a world, given in any order and both
+// included, read from the region files in `dir`. the lowest corner ends up
+// at 0, 0, 0 of the map
+pub fn import(
+    dir: &Path,
+    from: (i32, i32, i32),
+    to: (i32, i32, i32),
+    palette: &Palette,
+) -> Result<DmfMap> {
+    let min = (from.0.min(to.0), from.1.min(to.1), from.2.min(to.2));
+    let max = (from.0.max(to.0), from.1.max(to.1), from.2.max(to.2));
+    let size = |min: i32, max: i32| {
+        let length = i64::from(max) - i64::from(min) + 1;
+        i16::try_from(length)
+            .map_err(|_| Error::InvalidMap(format!("{} blocks is too big for a map", length)))
+    };
+    let (x_size, y_size, z_size) = (
+        size(min.0, max.0)?,
+        size(min.1, max.1)?,
+        size(min.2, max.2)?,
+    );
+    let volume = x_size as u64 * y_size as u64 * z_size as u64;
+    if volume > MAX_VOLUME {
+        return Err(Error::InvalidMap(format!(
+            "{}x{}x{} is {} blocks, more than the {} a map can be imported with",
+            x_size, y_size, z_size, volume, MAX_VOLUME
+        )));
+    }
+    let mut map = DmfMap::new(0, 0, 0, x_size, y_size, z_size);
+
+    let mut regions = Regions::new(dir);
+    let mut states: HashMap<String, u8> = HashMap::new();
+    for chunk_z in min.2.div_euclid(16)..=max.2.div_euclid(16) {
+        for chunk_x in min.0.div_euclid(16)..=max.0.div_euclid(16) {
+            let Some(chunk) = regions.chunk(chunk_x, chunk_z)? else {
+                continue;
+            };
+            for section in sections(&chunk)? {
+                let section_y = section.y * 16;
+                if section_y + 15 < min.1 || section_y > max.1 {
+                    continue;
+                }
+                let blocks: Vec<u8> = section
+                    .palette
+                    .iter()
+                    .map(|state| {
+                        *states
+                            .entry(state.clone())
+                            .or_insert_with(|| palette.nearest(state))
+                    })
+                    .collect();
+                let indices = section.indices()?;
+
+                // section blocks are y, then z, then x like ours
+                for (index, &palette_index) in indices.iter().enumerate() {
+                    let x = chunk_x * 16 + (index % 16) as i32;
+                    let z = chunk_z * 16 + (index / 16 % 16) as i32;
+                    let y = section_y + (index / 256) as i32;
+                    if x < min.0 || x > max.0 || y < min.1 || y > max.1 || z < min.2 || z > max.2 {
+                        continue;
+                    }
+                    let block = blocks.get(palette_index as usize).copied().unwrap_or(AIR);
+                    map.set_block(
+                        (x - min.0) as i16,
+                        (y - min.1) as i16,
+                        (z - min.2) as i16,
+                        block,
+                    );
+                }
+            }
+        }
+    }
+
+    let (x_spawn, y_spawn, z_spawn) = map.ground_spawn();
+    map.set_spawn_point(x_spawn, y_spawn, z_spawn);
+    map.metadata.generator = Some("Anvil".to_string());
+    map.mark_dirty();
+    Ok(map)
+}
+
+struct Section {
+    y: i32,
+    palette: Vec<String>,
+    data: Vec<i64>,
+    // whether indices can be split across two longs
+    spanning: bool,
+}
+
+impl Section {
+    fn indices(&self) -> Result<Vec<u32>> {
+        // a section of a single block has no data at all
+        if self.palette.len() <= 1 {
+            return Ok(vec![0; SECTION_VOLUME]);
+        }
+        let bits = (usize::BITS - (self.palette.len() - 1).leading_zeros()).max(4) as usize;
+        let mask = (1u64 << bits) - 1;
+        let needed = if self.spanning {
+            (SECTION_VOLUME * bits).div_ceil(64)
+        } else {
+            SECTION_VOLUME.div_ceil(64 / bits)
+        };
+        if self.data.len() < needed {
+            return Err(Error::InvalidMap(format!(
+                "chunk section {} has {} longs of blocks, needs {}",
+                self.y,
+                self.data.len(),
+                needed
+            )));
+        }
+
+        let data = &self.data;
+        Ok((0..SECTION_VOLUME)
+            .map(|index| {
+                let value = if self.spanning {
+                    let bit = index * bits;
+                    let (long, offset) = (bit / 64, bit % 64);
+                    let mut value = data[long] as u64 >> offset;
+                    if offset + bits > 64 {
+                        value |= (data[long + 1] as u64) << (64 - offset);
+                    }
+                    value
+                } else {
+                    let per_long = 64 / bits;
+                    data[index / per_long] as u64 >> (index % per_long * bits)
+                };
+                (value & mask) as u32
+            })
+            .collect())
+    }
+}
+
+// block sections of a chunk in both layouts, sections without blocks (only
+// light) are left out
+fn sections(chunk: &Compound) -> Result<Vec<Section>> {
+    let data_version = chunk.int("DataVersion").unwrap_or(0);
+    let spanning = data_version < DATA_VERSION_1_16;
+    let (list, old_layout) = match chunk.compound("Level") {
+        Some(level) if data_version < DATA_VERSION_1_18 => (level.list("Sections"), true),
+        _ => (chunk.list("sections"), false),
+    };
+    let Some(list) = list else {
+        return Ok(Vec::new());
+    };
+    if old_layout && list.iter().any(|section| matches!(section, Tag::Compound(section) if section.bytes("Blocks").is_some())) {
+        return Err(Error::InvalidMap(
+            "worlds from before 1.13 are not supported".to_string(),
+        ));
+    }
+
+    let mut sections = Vec::new();
+    for section in list {
+        let Tag::Compound(section) = section else {
+            continue;
+        };
+        let (palette, data) = if old_layout {
+            (section.list("Palette"), section.long_array("BlockStates"))
+        } else {
+            let states = section.compound("block_states");
+            (
+                states.and_then(|states| states.list("palette")),
+                states.and_then(|states| states.long_array("data")),
+            )
+        };
+        let Some(palette) = palette else {
+            continue;
+        };
+        sections.push(Section {
+            y: section.int("Y").unwrap_or(0) as i32,
+            palette: palette.iter().map(block_state).collect(),
+            data: data.unwrap_or_default().to_vec(),
+            spanning,
+        });
+    }
+    Ok(sections)
+}
+
+// "minecraft:oak_stairs[facing=east,half=bottom]", properties sorted the way
+// the game writes them
+fn block_state(entry: &Tag) -> String {
+    let Tag::Compound(entry) = entry else {
+        return "minecraft:air".to_string();
+    };
+    let name = entry.string("Name").unwrap_or("minecraft:air");
+    let mut properties: Vec<(&str, &str)> = entry
+        .compound("Properties")
+        .map(|properties| {
+            properties
+                .iter()
+                .filter_map(|(key, value)| match value {
+                    Tag::String(value) => Some((key, value.as_str())),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    if properties.is_empty() {
+        return name.to_string();
+    }
+    properties.sort_unstable();
+    let properties: Vec<String> = properties
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    format!("{}[{}]", name, properties.join(","))
+}
+
+struct Region {
+    file: File,
+    // sector offset << 8 | sector count, for each chunk
+    locations: Vec<u32>,
+}
+
+// region files are opened once and kept while the import runs, a missing
+// one is just a part of the world nobody went to
+struct Regions {
+    dir: PathBuf,
+    open: HashMap<(i32, i32), Option<Region>>,
+}
+
+impl Regions {
+    fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            open: HashMap::new(),
+        }
+    }
+
+    fn chunk(&mut self, chunk_x: i32, chunk_z: i32) -> Result<Option<Compound>> {
+        let region_x = chunk_x.div_euclid(CHUNKS_PER_REGION);
+        let region_z = chunk_z.div_euclid(CHUNKS_PER_REGION);
+        if !self.open.contains_key(&(region_x, region_z)) {
+            let region = self.open_region(region_x, region_z)?;
+            self.open.insert((region_x, region_z), region);
+        }
+        let Some(Some(region)) = self.open.get_mut(&(region_x, region_z)) else {
+            return Ok(None);
+        };
+
+        let index = chunk_z.rem_euclid(CHUNKS_PER_REGION) * CHUNKS_PER_REGION
+            + chunk_x.rem_euclid(CHUNKS_PER_REGION);
+        let location = region.locations[index as usize];
+        let (sector, sectors) = (u64::from(location >> 8), u64::from(location & 0xFF));
+        if sector == 0 {
+            return Ok(None);
+        }
+
+        region.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        let mut header = [0u8; 5];
+        read_exact(&mut region.file, &mut header)?;
+        let length = u64::from(u32::from_be_bytes(header[..4].try_into().unwrap()));
+        let compression = header[4];
+        if length == 0 || length > sectors * SECTOR_SIZE {
+            return Err(Error::InvalidMap(format!(
+                "chunk {}, {} claims to be {} bytes",
+                chunk_x, chunk_z, length
+            )));
+        }
+
+        let data: Box<dyn Read> = if compression & COMPRESSION_EXTERNAL != 0 {
+            let path = self.dir.join(format!("c.{}.{}.mcc", chunk_x, chunk_z));
+            Box::new(io::BufReader::new(File::open(path)?))
+        } else {
+            let mut data = Vec::new();
+            (&mut region.file).take(length - 1).read_to_end(&mut data)?;
+            Box::new(io::Cursor::new(data))
+        };
+        let (_, chunk) = match compression & !COMPRESSION_EXTERNAL {
+            COMPRESSION_GZIP => nbt::read(&mut GzDecoder::new(data))?,
+            COMPRESSION_ZLIB => nbt::read(&mut ZlibDecoder::new(data))?,
+            COMPRESSION_NONE => nbt::read(&mut { data })?,
+            other => {
+                return Err(Error::InvalidMap(format!(
+                    "chunk {}, {} uses compression {} we can't read",
+                    chunk_x, chunk_z, other
+                )))
+            }
+        };
+        Ok(Some(chunk))
+    }
+
+    fn open_region(&self, region_x: i32, region_z: i32) -> Result<Option<Region>> {
+        let path = self.dir.join(format!("r.{}.{}.mca", region_x, region_z));
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        // a region nothing was ever saved to can be empty
+        if file.metadata()?.len() < SECTOR_SIZE {
+            return Ok(None);
+        }
+        let mut table = vec![0u8; SECTOR_SIZE as usize];
+        read_exact(&mut file, &mut table)?;
+        let locations = table
+            .chunks_exact(4)
+            .map(|entry| u32::from_be_bytes(entry.try_into().unwrap()))
+            .collect();
+        Ok(Some(Region { file, locations }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::fs;
+
+    fn palette_entry(state: &str) -> Tag {
+        let (name, properties) = match state.split_once('[') {
+            Some((name, properties)) => (name, properties.trim_end_matches(']')),
+            None => (state, ""),
+        };
+        let mut entry = Compound::new().with("Name", Tag::String(name.to_string()));
+        if !properties.is_empty() {
+            let mut compound = Compound::new();
+            for property in properties.split(',') {
+                let (key, value) = property.split_once('=').unwrap();
+                compound.insert(key, Tag::String(value.to_string()));
+            }
+            entry.insert("Properties", Tag::Compound(compound));
+        }
+        Tag::Compound(entry)
+    }
+
+    // a 1.18+ chunk with one section at y 0..16: stone at the bottom, a
+    // grass block at 3, 2, 5 and an oak stair at 4, 2, 5
+    fn chunk() -> Compound {
+        let states = [
+            "minecraft:air",
+            "minecraft:stone",
+            "minecraft:grass_block[snowy=false]",
+            "minecraft:oak_stairs[half=bottom,facing=east]",
+        ];
+        let mut indices = vec![0u64; SECTION_VOLUME];
+        for index in indices.iter_mut().take(256) {
+            *index = 1;
+        }
+        indices[(2 * 16 + 5) * 16 + 3] = 2;
+        indices[(2 * 16 + 5) * 16 + 4] = 3;
+        // 4 bits each, 16 to a long
+        let data: Vec<i64> = indices
+            .chunks(16)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0u64, |long, (i, index)| long | index << (i * 4)) as i64
+            })
+            .collect();
+
+        let section = Compound::new().with("Y", Tag::Byte(0)).with(
+            "block_states",
+            Tag::Compound(
+                Compound::new()
+                    .with(
+                        "palette",
+                        Tag::List(states.iter().map(|state| palette_entry(state)).collect()),
+                    )
+                    .with("data", Tag::LongArray(data)),
+            ),
+        );
+        let air = Compound::new().with("Y", Tag::Byte(-1)).with(
+            "block_states",
+            Tag::Compound(
+                Compound::new().with("palette", Tag::List(vec![palette_entry("minecraft:air")])),
+            ),
+        );
+        Compound::new().with("DataVersion", Tag::Int(3465)).with(
+            "sections",
+            Tag::List(vec![Tag::Compound(air), Tag::Compound(section)]),
+        )
+    }
+
+    // region 0, 0 with the chunk at 1, 0
+    fn write_region(dir: &Path) {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        nbt::write(&mut encoder, "", &chunk()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut region = vec![0u8; 2 * SECTOR_SIZE as usize];
+        let sectors = (compressed.len() + 5).div_ceil(SECTOR_SIZE as usize);
+        region[4..8].copy_from_slice(&(2 << 8 | sectors as u32).to_be_bytes());
+        region.extend_from_slice(&(compressed.len() as u32 + 1).to_be_bytes());
+        region.push(COMPRESSION_ZLIB);
+        region.extend_from_slice(&compressed);
+        region.resize((2 + sectors) * SECTOR_SIZE as usize, 0);
+        fs::write(dir.join("r.0.0.mca"), region).unwrap();
+    }
+
+    #[test]
+    fn imports_a_slice_of_a_region() {
+        let dir = std::env::temp_dir().join(format!("dandelion-anvil-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        write_region(&dir);
+
+        // world x 18..=21, y -2..=3, z 4..=6, partly in chunks nobody saved
+        let map = import(&dir, (21, 3, 6), (18, -2, 4), &Palette::default()).unwrap();
+        assert_eq!((map.x_size, map.y_size, map.z_size), (4, 6, 3));
+        assert_eq!(map.get_block(0, 1, 0), AIR);
+        assert_eq!(map.get_block(0, 2, 0), 1);
+        assert_eq!(map.get_block(3, 2, 2), 1);
+        assert_eq!(map.get_block(1, 4, 1), 2);
+        assert_eq!(map.get_block(2, 4, 1), 5);
+        assert_eq!(map.get_block(1, 5, 1), AIR);
+        assert_eq!((map.x_spawn, map.y_spawn, map.z_spawn), (2, 5, 1));
+
+        // nothing saved there at all
+        let empty = import(&dir, (-100, 0, -100), (-90, 4, -90), &Palette::default()).unwrap();
+        assert!(empty.blocks.iter().all(|block| *block == AIR));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn turns_away_boxes_too_big_to_import() {
+        let dir = std::env::temp_dir();
+        let palette = Palette::default();
+        for (from, to) in [
+            ((-2_000_000_000, 0, 0), (2_000_000_000, 0, 0)),
+            ((0, 0, 0), (29_999, 29_999, 29_999)),
+        ] {
+            assert!(matches!(
+                import(&dir, from, to, &palette),
+                Err(Error::InvalidMap(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn unpacks_spanning_indices() {
+        // 5 bits each, the 13th index starts at bit 60 of the first long
+        let mut data = vec![0i64; (SECTION_VOLUME * 5).div_ceil(64)];
+        data[0] = (0b10110u64 << 60) as i64;
+        data[1] = 0b1;
+        let section = Section {
+            y: 0,
+            palette: (0..17).map(|i| i.to_string()).collect(),
+            data,
+            spanning: true,
+        };
+        let indices = section.indices().unwrap();
+        assert_eq!(indices[12], 0b10110);
+        assert_eq!(indices[13], 0);
+
+        let short = Section {
+            data: vec![0; 10],
+            spanning: false,
+            ..section
+        };
+        assert!(short.indices().is_err());
+    }
+}
@@ -0,0 +1,459 @@
+use crate::server::error::{Error, Result};
+use crate::server::formats::nbt::{self, Compound, Tag};
+use crate::server::game::dmf_map::DmfMap;
+use crate::server::game::map_metadata::{BlockDefinition, MapMetadata};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::io::{self, Read, Write};
+
+// ClassicWorld, the gzipped nbt format ClassiCube and most classic servers
+// use. see https://wiki.vg/ClassicWorld_file_format
+
+const ROOT_NAME: &str = "ClassicWorld";
+const FORMAT_VERSION: i64 = 1;
+const SOFTWARE: &str = "Dandelion";
+// extended block ids don't fit in a byte, they keep the map's shape as stone
+const FALLBACK_BLOCK: u8 = 0x01;
+
+impl DmfMap {
+    pub fn from_classicworld(data: impl Read) -> Result<Self> {
+        let (_, root) = nbt::read(&mut GzDecoder::new(data))?;
+        let (x_size, y_size, z_size) = dimensions(&root)?;
+        let total_blocks = x_size as usize * y_size as usize * z_size as usize;
+        let blocks = root
+            .bytes("BlockArray")
+            .ok_or_else(|| Error::InvalidMap("BlockArray is missing".to_string()))?;
+        if blocks.len() != total_blocks {
+            return Err(Error::InvalidMap(format!(
+                "expected {} blocks, found {}",
+                total_blocks,
+                blocks.len()
+            )));
+        }
+
+        let spawn = root.compound("Spawn");
+        let spawn_at = |axis: &str, default: i16| {
+            spawn
+                .and_then(|spawn| spawn.int(axis))
+                .map_or(default, |value| value as i16)
+        };
+        let mut map = DmfMap::new(
+            spawn_at("X", x_size / 2),
+            spawn_at("Y", y_size),
+            spawn_at("Z", z_size / 2),
+            x_size,
+            y_size,
+            z_size,
+        );
+
+        map.blocks.copy_from_slice(blocks);
+        if let Some(upper) = root
+            .bytes("BlockArray2")
+            .filter(|upper| upper.len() == total_blocks)
+        {
+            for (block, upper) in map.blocks.iter_mut().zip(upper) {
+                if *upper != 0 {
+                    *block = FALLBACK_BLOCK;
+                }
+            }
+        }
+
+        map.metadata = read_metadata(&root);
+        if let Some(spawn) = spawn {
+            map.metadata.spawn_yaw = spawn.int("H").unwrap_or(0) as u8;
+            map.metadata.spawn_pitch = spawn.int("P").unwrap_or(0) as u8;
+        }
+        map.mark_clean();
+        Ok(map)
+    }
+
+    pub fn to_classicworld(&self, name: &str) -> io::Result<Vec<u8>> {
+        let metadata = &self.metadata;
+        let mut root = Compound::new()
+            .with("FormatVersion", Tag::Byte(FORMAT_VERSION as i8))
+            .with("Name", Tag::String(name.to_string()))
+            .with("UUID", Tag::ByteArray(rand::random::<[u8; 16]>().to_vec()))
+            .with("X", Tag::Short(self.x_size))
+            .with("Y", Tag::Short(self.y_size))
+            .with("Z", Tag::Short(self.z_size))
+            .with(
+                "Spawn",
+                Tag::Compound(
+                    Compound::new()
+                        .with("X", Tag::Short(self.x_spawn))
+                        .with("Y", Tag::Short(self.y_spawn))
+                        .with("Z", Tag::Short(self.z_spawn))
+                        .with("H", Tag::Byte(metadata.spawn_yaw as i8))
+                        .with("P", Tag::Byte(metadata.spawn_pitch as i8)),
+                ),
+            )
+            .with("BlockArray", Tag::ByteArray(self.blocks.clone()));
+
+        if let Some(author) = &metadata.author {
+            root.insert(
+                "CreatedBy",
+                Tag::Compound(
+                    Compound::new()
+                        .with("Service", Tag::String(SOFTWARE.to_string()))
+                        .with("Username", Tag::String(author.clone())),
+                ),
+            );
+        }
+        if metadata.generator.is_some() || metadata.seed.is_some() {
+            let mut generator = Compound::new().with("Software", Tag::String(SOFTWARE.to_string()));
+            if let Some(name) = &metadata.generator {
+                generator.insert("MapGeneratorName", Tag::String(name.clone()));
+            }
+            if let Some(seed) = metadata.seed {
+                generator.insert("Seed", Tag::Long(seed));
+            }
+            root.insert("MapGenerator", Tag::Compound(generator));
+        }
+        if let Some(created_at) = metadata.created_at {
+            root.insert("TimeCreated", Tag::Long(created_at as i64));
+        }
+        root.insert(
+            "Metadata",
+            Tag::Compound(Compound::new().with("CPE", Tag::Compound(write_cpe(metadata)))),
+        );
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        nbt::write(&mut encoder, ROOT_NAME, &root)?;
+        encoder.flush()?;
+        encoder.finish()
+    }
+}
+
+// only reads the small fields, for indexing maps without their blocks
+pub fn read_dimensions(data: impl Read) -> Result<(i16, i16, i16)> {
+    let (_, root) = nbt::read_without_arrays(&mut GzDecoder::new(data))?;
+    dimensions(&root)
+}
+
+fn dimensions(root: &Compound) -> Result<(i16, i16, i16)> {
+    let version = root.int("FormatVersion").unwrap_or(FORMAT_VERSION);
+    if version != FORMAT_VERSION {
+        return Err(Error::InvalidMap(format!(
+            "unsupported ClassicWorld version {}",
+            version
+        )));
+    }
+    let size = |axis: &str| {
+        root.int(axis)
+            .filter(|size| (1..=i16::MAX as i64).contains(size))
+            .map(|size| size as i16)
+            .ok_or_else(|| Error::InvalidMap(format!("{} is missing or out of range", axis)))
+    };
+    Ok((size("X")?, size("Y")?, size("Z")?))
+}
+
+fn read_metadata(root: &Compound) -> MapMetadata {
+    let mut metadata = MapMetadata {
+        created_at: root
+            .int("TimeCreated")
+            .and_then(|time| u64::try_from(time).ok()),
+        author: root
+            .compound("CreatedBy")
+            .and_then(|created_by| created_by.string("Username"))
+            .map(str::to_string),
+        ..MapMetadata::default()
+    };
+    if let Some(generator) = root.compound("MapGenerator") {
+        metadata.generator = generator
+            .string("MapGeneratorName")
+            .or_else(|| generator.string("Software"))
+            .map(str::to_string);
+        metadata.seed = generator.int("Seed");
+    }
+
+    let Some(cpe) = root
+        .compound("Metadata")
+        .and_then(|metadata| metadata.compound("CPE"))
+    else {
+        return metadata;
+    };
+    if let Some(colors) = cpe.compound("EnvColors") {
+        metadata.environment.sky_color = read_color(colors, "Sky");
+        metadata.environment.cloud_color = read_color(colors, "Cloud");
+        metadata.environment.fog_color = read_color(colors, "Fog");
+    }
+    if let Some(appearance) = cpe.compound("EnvMapAppearance") {
+        metadata.environment.side_block = appearance.int("SideBlock").map(|block| block as u8);
+        metadata.environment.edge_block = appearance.int("EdgeBlock").map(|block| block as u8);
+        metadata.environment.edge_height = appearance
+            .int("SideLevel")
+            .map(|level| level as i16)
+            .filter(|level| *level >= 0);
+    }
+    metadata.environment.weather = cpe
+        .compound("EnvWeatherType")
+        .and_then(|weather| weather.int("WeatherType"))
+        .map(|weather| weather as u8);
+    if let Some(definitions) = cpe.compound("BlockDefinitions") {
+        metadata.block_definitions = definitions
+            .iter()
+            .filter_map(|(_, definition)| match definition {
+                Tag::Compound(definition) => read_block_definition(definition),
+                _ => None,
+            })
+            .collect();
+    }
+    metadata
+}
+
+// -1 in any channel means the client default
+fn read_color(colors: &Compound, name: &str) -> Option<[u8; 3]> {
+    let color = colors.compound(name)?;
+    let channel = |channel: &str| {
+        color
+            .int(channel)
+            .filter(|value| (0..=255).contains(value))
+            .map(|value| value as u8)
+    };
+    Some([channel("R")?, channel("G")?, channel("B")?])
+}
+
+fn read_block_definition(definition: &Compound) -> Option<BlockDefinition> {
+    let id = u8::try_from(definition.int("ID2").or_else(|| definition.int("ID"))?).ok()?;
+    let byte = |name: &str| definition.int(name).unwrap_or(0) as u8;
+    let textures = definition.bytes("Textures").unwrap_or(&[]);
+    let texture = |index: usize| textures.get(index).copied().unwrap_or(0);
+    let fog = definition.bytes("Fog").unwrap_or(&[]);
+    let fog_at = |index: usize| fog.get(index).copied().unwrap_or(0);
+    Some(BlockDefinition {
+        id,
+        name: definition.string("Name").unwrap_or_default().to_string(),
+        solidity: byte("CollideType"),
+        speed: speed_to_byte(definition.float("Speed").unwrap_or(1.0)),
+        // textures are top, bottom, then the four sides
+        top_texture: texture(0),
+        side_texture: texture(2),
+        bottom_texture: texture(1),
+        transmits_light: byte("TransmitsLight") != 0,
+        walk_sound: byte("WalkSound"),
+        full_bright: byte("FullBright") != 0,
+        shape: byte("Shape"),
+        draw: byte("BlockDraw"),
+        fog_density: fog_at(0),
+        fog_color: [fog_at(1), fog_at(2), fog_at(3)],
+    })
+}
+
+fn write_cpe(metadata: &MapMetadata) -> Compound {
+    let environment = &metadata.environment;
+    let mut cpe = Compound::new();
+
+    let mut colors = Compound::new();
+    for (name, color) in [
+        ("Sky", environment.sky_color),
+        ("Cloud", environment.cloud_color),
+        ("Fog", environment.fog_color),
+    ] {
+        if let Some([r, g, b]) = color {
+            colors.insert(
+                name,
+                Tag::Compound(
+                    Compound::new()
+                        .with("R", Tag::Short(r.into()))
+                        .with("G", Tag::Short(g.into()))
+                        .with("B", Tag::Short(b.into())),
+                ),
+            );
+        }
+    }
+    if colors.iter().next().is_some() {
+        cpe.insert(
+            "EnvColors",
+            Tag::Compound(colors.with("ExtensionVersion", Tag::Int(1))),
+        );
+    }
+
+    let mut appearance = Compound::new();
+    if let Some(block) = environment.side_block {
+        appearance.insert("SideBlock", Tag::Byte(block as i8));
+    }
+    if let Some(block) = environment.edge_block {
+        appearance.insert("EdgeBlock", Tag::Byte(block as i8));
+    }
+    if let Some(height) = environment.edge_height {
+        appearance.insert("SideLevel", Tag::Short(height));
+    }
+    if appearance.iter().next().is_some() {
+        cpe.insert(
+            "EnvMapAppearance",
+            Tag::Compound(
+                appearance
+                    .with("ExtensionVersion", Tag::Int(1))
+                    .with("TextureURL", Tag::String(String::new())),
+            ),
+        );
+    }
+
+    if let Some(weather) = environment.weather {
+        cpe.insert(
+            "EnvWeatherType",
+            Tag::Compound(
+                Compound::new()
+                    .with("ExtensionVersion", Tag::Int(1))
+                    .with("WeatherType", Tag::Byte(weather as i8)),
+            ),
+        );
+    }
+
+    if !metadata.block_definitions.is_empty() {
+        let mut definitions = Compound::new().with("ExtensionVersion", Tag::Int(1));
+        for block in &metadata.block_definitions {
+            definitions.insert(
+                &format!("Block{}", block.id),
+                Tag::Compound(write_block_definition(block)),
+            );
+        }
+        cpe.insert("BlockDefinitions", Tag::Compound(definitions));
+    }
+    cpe
+}
+
+fn write_block_definition(block: &BlockDefinition) -> Compound {
+    let side = block.side_texture;
+    // sprites have no height, everything else is as tall as its shape
+    let height = if block.shape == 0 { 16 } else { block.shape };
+    Compound::new()
+        .with("ID", Tag::Byte(block.id as i8))
+        .with("Name", Tag::String(block.name.clone()))
+        .with("CollideType", Tag::Byte(block.solidity as i8))
+        .with("Speed", Tag::Float(byte_to_speed(block.speed)))
+        .with(
+            "Textures",
+            Tag::ByteArray(vec![
+                block.top_texture,
+                block.bottom_texture,
+                side,
+                side,
+                side,
+                side,
+            ]),
+        )
+        .with("TransmitsLight", Tag::Byte(block.transmits_light as i8))
+        .with("WalkSound", Tag::Byte(block.walk_sound as i8))
+        .with("FullBright", Tag::Byte(block.full_bright as i8))
+        .with("Shape", Tag::Byte(block.shape as i8))
+        .with("BlockDraw", Tag::Byte(block.draw as i8))
+        .with(
+            "Fog",
+            Tag::ByteArray(vec![
+                block.fog_density,
+                block.fog_color[0],
+                block.fog_color[1],
+                block.fog_color[2],
+            ]),
+        )
+        .with("Coords", Tag::ByteArray(vec![0, 0, 0, 16, height, 16]))
+}
+
+// the cpe packet sends speed as 2^((byte - 128) / 64), files store the multiplier
+fn speed_to_byte(speed: f64) -> u8 {
+    if speed <= 0.0 {
+        return 0;
+    }
+    (128.0 + 64.0 * speed.log2()).round().clamp(0.0, 255.0) as u8
+}
+
+fn byte_to_speed(byte: u8) -> f32 {
+    2f32.powf((byte as f32 - 128.0) / 64.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::game::map_metadata::Environment;
+
+    fn sample_map() -> DmfMap {
+        let mut map = DmfMap::new(3, 9, 5, 16, 10, 8);
+        map.set_block(0, 0, 0, 0x07);
+        map.set_block(15, 9, 7, 0x14);
+        map.metadata = MapMetadata {
+            spawn_yaw: 64,
+            spawn_pitch: 200,
+            created_at: Some(1_700_000_000),
+            author: Some("flafmg".to_string()),
+            generator: Some("classic".to_string()),
+            seed: Some(1234),
+            environment: Environment {
+                sky_color: Some([10, 20, 30]),
+                edge_block: Some(8),
+                edge_height: Some(5),
+                weather: Some(2),
+                ..Environment::default()
+            },
+            block_definitions: vec![BlockDefinition {
+                id: 66,
+                name: "Lamp".to_string(),
+                solidity: 2,
+                speed: 128,
+                top_texture: 1,
+                side_texture: 2,
+                bottom_texture: 3,
+                full_bright: true,
+                shape: 8,
+                ..BlockDefinition::default()
+            }],
+            ..MapMetadata::default()
+        };
+        map
+    }
+
+    #[test]
+    fn round_trips_blocks_spawn_and_metadata() {
+        let map = sample_map();
+        let data = map.to_classicworld("sample").unwrap();
+        let loaded = DmfMap::from_classicworld(data.as_slice()).unwrap();
+        assert_eq!((loaded.x_size, loaded.y_size, loaded.z_size), (16, 10, 8));
+        assert_eq!((loaded.x_spawn, loaded.y_spawn, loaded.z_spawn), (3, 9, 5));
+        assert_eq!(loaded.blocks, map.blocks);
+        assert_eq!(loaded.metadata, map.metadata);
+        assert_eq!(read_dimensions(data.as_slice()).unwrap(), (16, 10, 8));
+    }
+
+    #[test]
+    fn reads_files_from_other_software() {
+        // the minimum ClassiCube needs, with an extended block in it
+        let root = Compound::new()
+            .with("FormatVersion", Tag::Byte(1))
+            .with("X", Tag::Short(2))
+            .with("Y", Tag::Short(1))
+            .with("Z", Tag::Short(2))
+            .with("BlockArray", Tag::ByteArray(vec![1, 2, 3, 4]))
+            .with("BlockArray2", Tag::ByteArray(vec![0, 0, 1, 0]));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        nbt::write(&mut encoder, ROOT_NAME, &root).unwrap();
+        let data = encoder.finish().unwrap();
+
+        let map = DmfMap::from_classicworld(data.as_slice()).unwrap();
+        assert_eq!(map.blocks, vec![1, 2, FALLBACK_BLOCK, 4]);
+        assert_eq!((map.x_spawn, map.y_spawn, map.z_spawn), (1, 1, 1));
+        assert!(!map.is_dirty());
+    }
+
+    #[test]
+    fn rejects_sizes_the_blocks_dont_fill() {
+        let root = Compound::new()
+            .with("X", Tag::Short(i16::MAX))
+            .with("Y", Tag::Short(i16::MAX))
+            .with("Z", Tag::Short(i16::MAX))
+            .with("BlockArray", Tag::ByteArray(vec![1, 2, 3, 4]));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        nbt::write(&mut encoder, ROOT_NAME, &root).unwrap();
+        let data = encoder.finish().unwrap();
+        assert!(matches!(
+            DmfMap::from_classicworld(data.as_slice()),
+            Err(Error::InvalidMap(_))
+        ));
+    }
+
+    #[test]
+    fn converts_speeds() {
+        assert_eq!(speed_to_byte(1.0), 128);
+        assert_eq!(speed_to_byte(2.0), 192);
+        assert_eq!(speed_to_byte(0.25), 0);
+        assert_eq!(byte_to_speed(192), 2.0);
+    }
+}
@@ -0,0 +1,343 @@
+use crate::server::error::{Error, Result};
+use crate::server::formats::java::{self, Value};
+use crate::server::formats::{read_exact, STONE};
+use crate::server::game::dmf_map::DmfMap;
+use flate2::read::GzDecoder;
+use std::io::{self, Read};
+
+// levels saved by the original Minecraft Classic, as .dat or .mine. all are
+// gzipped, and after the magic number come either a plain header and the
+// blocks or a java serialized com.mojang.minecraft.level.Level
+
+const MAGIC: u32 = 0x271B_B788;
+const VERSION_PLAIN: u8 = 1;
+const VERSION_SERIALIZED: u8 = 2;
+
+// the first releases saved nothing but the blocks of a level this size
+const HEADERLESS_SIZE: (i16, i16, i16) = (256, 64, 256);
+
+// classic stops at obsidian
+const LAST_CLASSIC_BLOCK: u8 = 49;
+
+struct Level {
+    // classic calls these width, depth and height
+    size: (i16, i16, i16),
+    blocks: Vec<u8>,
+    spawn: Option<(i16, i16, i16)>,
+    yaw: u8,
+    creator: Option<String>,
+    created_at: Option<u64>,
+}
+
+impl DmfMap {
+    pub fn from_classic_dat(data: impl Read) -> Result<Self> {
+        let level = read_level(GzDecoder::new(data), false)?;
+        let (x_size, y_size, z_size) = level.size;
+        let expected = x_size as usize * y_size as usize * z_size as usize;
+        if level.blocks.len() != expected {
+            return Err(Error::InvalidMap(format!(
+                "expected {} blocks, found {}",
+                expected,
+                level.blocks.len()
+            )));
+        }
+
+        let mut map = DmfMap::new(0, 0, 0, x_size, y_size, z_size);
+        // both store blocks y, then z, then x
+        for (block, raw) in map.blocks.iter_mut().zip(&level.blocks) {
+            *block = if *raw > LAST_CLASSIC_BLOCK {
+                STONE
+            } else {
+                *raw
+            };
+        }
+        let (x_spawn, y_spawn, z_spawn) = level
+            .spawn
+            .filter(|&(x, y, z)| map.contains(x, y, z))
+            .unwrap_or_else(|| map.ground_spawn());
+        map.x_spawn = x_spawn;
+        map.y_spawn = y_spawn;
+        map.z_spawn = z_spawn;
+        map.metadata.spawn_yaw = level.yaw;
+        map.metadata.author = level.creator;
+        map.metadata.created_at = level.created_at;
+        map.metadata.generator = Some("Minecraft Classic".to_string());
+
+        // there is no writing .dat back, the next save stores it as our own format
+        map.mark_dirty();
+        Ok(map)
+    }
+}
+
+pub fn read_dimensions(data: impl Read) -> Result<(i16, i16, i16)> {
+    Ok(read_level(GzDecoder::new(data), true)?.size)
+}
+
+fn read_level(mut reader: impl Read, skip_blocks: bool) -> Result<Level> {
+    let mut magic = [0u8; 4];
+    let read = read_up_to(&mut reader, &mut magic)?;
+    if read < magic.len() || u32::from_be_bytes(magic) != MAGIC {
+        let mut blocks = magic[..read].to_vec();
+        if !skip_blocks {
+            // one byte past a full level is enough to tell it is too long
+            let (x, y, z) = HEADERLESS_SIZE;
+            let length = x as u64 * y as u64 * z as u64;
+            reader
+                .take(length + 1 - read as u64)
+                .read_to_end(&mut blocks)?;
+        }
+        return Ok(Level {
+            size: HEADERLESS_SIZE,
+            blocks,
+            spawn: None,
+            yaw: 0,
+            creator: None,
+            created_at: None,
+        });
+    }
+
+    let mut version = [0u8; 1];
+    read_exact(&mut reader, &mut version)?;
+    match version[0] {
+        VERSION_PLAIN => read_plain(&mut reader, skip_blocks),
+        VERSION_SERIALIZED => {
+            let value = if skip_blocks {
+                java::read_without_arrays(&mut reader)?
+            } else {
+                java::read(&mut reader)?
+            };
+            read_serialized(&value)
+        }
+        other => Err(Error::InvalidMap(format!(
+            "unknown classic level version {}",
+            other
+        ))),
+    }
+}
+
+// name, creator, creation time, then the sizes as shorts
+fn read_plain(reader: &mut impl Read, skip_blocks: bool) -> Result<Level> {
+    let _name = read_utf(reader)?;
+    let creator = read_utf(reader)?;
+    let mut created = [0u8; 8];
+    read_exact(reader, &mut created)?;
+    let mut sizes = [0u8; 6];
+    read_exact(reader, &mut sizes)?;
+    let short_at = |at: usize| i16::from_be_bytes([sizes[at], sizes[at + 1]]);
+    let size = dimensions(short_at(0).into(), short_at(4).into(), short_at(2).into())?;
+
+    let mut blocks = Vec::new();
+    if !skip_blocks {
+        let length = size.0 as u64 * size.1 as u64 * size.2 as u64;
+        reader.take(length).read_to_end(&mut blocks)?;
+    }
+    Ok(Level {
+        size,
+        blocks,
+        spawn: None,
+        yaw: 0,
+        creator: Some(creator).filter(|creator| !creator.is_empty()),
+        created_at: millis_to_secs(i64::from_be_bytes(created)),
+    })
+}
+
+fn read_serialized(value: &Value) -> Result<Level> {
+    let Value::Object(level) = value else {
+        return Err(Error::InvalidMap(
+            "classic level isn't a serialized object".to_string(),
+        ));
+    };
+    let int = |name: &str| {
+        level
+            .int(name)
+            .ok_or_else(|| Error::InvalidMap(format!("classic level has no {}", name)))
+    };
+    let size = dimensions(int("width")?, int("depth")?, int("height")?)?;
+    let blocks = level
+        .bytes("blocks")
+        .ok_or_else(|| Error::InvalidMap("classic level has no blocks".to_string()))?
+        .to_vec();
+
+    let spawn = match (
+        level.int("xSpawn"),
+        level.int("ySpawn"),
+        level.int("zSpawn"),
+    ) {
+        (Some(x), Some(y), Some(z)) => Some((
+            i16::try_from(x).unwrap_or(-1),
+            i16::try_from(y).unwrap_or(-1),
+            i16::try_from(z).unwrap_or(-1),
+        )),
+        _ => None,
+    };
+    // degrees to the 256 steps of a circle clients use
+    let yaw = level.float("rotSpawn").map_or(0, |degrees| {
+        (degrees.rem_euclid(360.0) * 256.0 / 360.0) as u8
+    });
+
+    Ok(Level {
+        size,
+        blocks,
+        spawn,
+        yaw,
+        creator: level
+            .string("creator")
+            .filter(|creator| !creator.is_empty())
+            .map(str::to_string),
+        created_at: level.int("createTime").and_then(millis_to_secs),
+    })
+}
+
+fn dimensions(x: i64, y: i64, z: i64) -> Result<(i16, i16, i16)> {
+    let size = |size: i64| {
+        i16::try_from(size)
+            .ok()
+            .filter(|size| *size > 0)
+            .ok_or_else(|| Error::InvalidMap(format!("bad dimensions {}x{}x{}", x, y, z)))
+    };
+    Ok((size(x)?, size(y)?, size(z)?))
+}
+
+fn millis_to_secs(millis: i64) -> Option<u64> {
+    u64::try_from(millis / 1000).ok().filter(|secs| *secs > 0)
+}
+
+fn read_utf(reader: &mut impl Read) -> Result<String> {
+    let mut length = [0u8; 2];
+    read_exact(reader, &mut length)?;
+    let mut buf = vec![0u8; u16::from_be_bytes(length) as usize];
+    read_exact(reader, &mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+// like read_exact, but a short file is fine and says how much there was
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::Io(e)),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn utf(out: &mut Vec<u8>, value: &str) {
+        out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+
+    // 4 wide, 2 deep and 3 high in classic's words
+    fn blocks() -> Vec<u8> {
+        let mut blocks = vec![0u8; 4 * 2 * 3];
+        blocks[0] = 1;
+        blocks[4 * 3 + 4 + 2] = 49;
+        blocks[23] = 120;
+        blocks
+    }
+
+    fn serialized_level() -> Vec<u8> {
+        let mut out = MAGIC.to_be_bytes().to_vec();
+        out.extend_from_slice(&[VERSION_SERIALIZED, 0xAC, 0xED, 0x00, 0x05, 0x73, 0x72]);
+        utf(&mut out, "com.mojang.minecraft.level.Level");
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&[0x02, 0, 10]);
+        for name in ["depth", "height", "width", "xSpawn", "ySpawn", "zSpawn"] {
+            out.push(b'I');
+            utf(&mut out, name);
+        }
+        out.push(b'F');
+        utf(&mut out, "rotSpawn");
+        out.push(b'J');
+        utf(&mut out, "createTime");
+        out.push(b'[');
+        utf(&mut out, "blocks");
+        out.push(0x74);
+        utf(&mut out, "[B");
+        out.push(b'L');
+        utf(&mut out, "creator");
+        out.push(0x74);
+        utf(&mut out, "Ljava/lang/String;");
+        out.extend_from_slice(&[0x78, 0x70]);
+
+        for value in [2i32, 3, 4, 3, 1, 2] {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        out.extend_from_slice(&90f32.to_be_bytes());
+        out.extend_from_slice(&1_262_304_000_000i64.to_be_bytes());
+        out.extend_from_slice(&[0x75, 0x72]);
+        utf(&mut out, "[B");
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&[0x02, 0, 0, 0x78, 0x70]);
+        out.extend_from_slice(&24i32.to_be_bytes());
+        out.extend_from_slice(&blocks());
+        out.push(0x74);
+        utf(&mut out, "notch");
+        out
+    }
+
+    #[test]
+    fn reads_serialized_levels() {
+        let data = gzip(&serialized_level());
+        let map = DmfMap::from_classic_dat(data.as_slice()).unwrap();
+        assert_eq!((map.x_size, map.y_size, map.z_size), (4, 2, 3));
+        assert_eq!((map.x_spawn, map.y_spawn, map.z_spawn), (3, 1, 2));
+        assert_eq!(map.metadata.spawn_yaw, 64);
+        assert_eq!(map.metadata.author.as_deref(), Some("notch"));
+        assert_eq!(map.metadata.created_at, Some(1_262_304_000));
+        assert_eq!(map.get_block(0, 0, 0), 1);
+        assert_eq!(map.get_block(2, 1, 1), 49);
+        assert_eq!(map.get_block(3, 1, 2), STONE);
+        assert!(map.is_dirty());
+        assert_eq!(read_dimensions(data.as_slice()).unwrap(), (4, 2, 3));
+    }
+
+    #[test]
+    fn reads_plain_levels() {
+        let mut out = MAGIC.to_be_bytes().to_vec();
+        out.push(VERSION_PLAIN);
+        utf(&mut out, "A Nice World");
+        utf(&mut out, "notch");
+        out.extend_from_slice(&0i64.to_be_bytes());
+        for value in [4i16, 3, 2] {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        out.extend_from_slice(&blocks());
+        let data = gzip(&out);
+
+        let map = DmfMap::from_classic_dat(data.as_slice()).unwrap();
+        assert_eq!((map.x_size, map.y_size, map.z_size), (4, 2, 3));
+        // the middle column is 2, 1 and has a block at y 1
+        assert_eq!((map.x_spawn, map.y_spawn, map.z_spawn), (2, 1, 1));
+        assert_eq!(map.metadata.created_at, None);
+        assert_eq!(read_dimensions(data.as_slice()).unwrap(), (4, 2, 3));
+    }
+
+    #[test]
+    fn reads_headerless_levels() {
+        let (x, y, z) = HEADERLESS_SIZE;
+        let mut blocks = vec![0u8; x as usize * y as usize * z as usize];
+        blocks[0] = 3;
+        let data = gzip(&blocks);
+        let map = DmfMap::from_classic_dat(data.as_slice()).unwrap();
+        assert_eq!(map.get_block(0, 0, 0), 3);
+        assert_eq!((map.x_spawn, map.y_spawn, map.z_spawn), (128, 0, 128));
+
+        let data = gzip(&blocks[..100]);
+        assert!(DmfMap::from_classic_dat(data.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,419 @@
+use crate::server::error::{Error, Result};
+use crate::server::formats::{read_array, read_blocks, LAST_CPE_BLOCK, STONE};
+use crate::server::game::dmf_map::DmfMap;
+use flate2::read::{DeflateDecoder, GzDecoder};
+use std::io::Read;
+
+// fCraft's .fcm, also written by 800Craft and LegendCraft. both versions
+// start with a little endian header, then metadata entries and the blocks.
+// v2 keeps the metadata uncompressed and gzips the blocks, v3 deflates both
+// and puts each entry in a group
+
+const IDENTIFIER_V2: u32 = 0xFC00_0002;
+const IDENTIFIER_V3: u32 = 0x0FC2_AF40;
+const REVISION_V3: u8 = 13;
+
+// fCraft names blocks after the classic ones, in id order
+const BLOCK_NAMES: [&str; 50] = [
+    "air",
+    "stone",
+    "grass",
+    "dirt",
+    "cobblestone",
+    "plank",
+    "sapling",
+    "admincrete",
+    "water",
+    "stillwater",
+    "lava",
+    "stilllava",
+    "sand",
+    "gravel",
+    "goldore",
+    "ironore",
+    "coal",
+    "log",
+    "leaves",
+    "sponge",
+    "glass",
+    "red",
+    "orange",
+    "yellow",
+    "lime",
+    "green",
+    "teal",
+    "aqua",
+    "cyan",
+    "blue",
+    "indigo",
+    "violet",
+    "magenta",
+    "pink",
+    "black",
+    "gray",
+    "white",
+    "yellowflower",
+    "redflower",
+    "brownmushroom",
+    "redmushroom",
+    "gold",
+    "iron",
+    "doublestair",
+    "stair",
+    "brick",
+    "tnt",
+    "books",
+    "mossyrocks",
+    "obsidian",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    V2,
+    V3,
+}
+
+struct Header {
+    version: Version,
+    width: u16,
+    height: u16,
+    length: u16,
+    // in 1/32 of a block, in fCraft's order of x, z, then y
+    spawn: (i32, i32, i32),
+    yaw: u8,
+    pitch: u8,
+    created_at: Option<u64>,
+    entries: u32,
+}
+
+struct Entry {
+    group: String,
+    key: String,
+    value: String,
+}
+
+impl DmfMap {
+    pub fn from_fcm(mut data: impl Read) -> Result<Self> {
+        let header = read_header(&mut data)?;
+        let (x_size, y_size, z_size) = dimensions(&header)?;
+        let volume = x_size as usize * y_size as usize * z_size as usize;
+        let (entries, raw) = match header.version {
+            Version::V2 => {
+                let entries = read_entries(&mut data, &header)?;
+                (entries, read_blocks(&mut GzDecoder::new(data), volume)?)
+            }
+            Version::V3 => {
+                let mut inflated = DeflateDecoder::new(data);
+                let entries = read_entries(&mut inflated, &header)?;
+                (entries, read_blocks(&mut inflated, volume)?)
+            }
+        };
+
+        // fCraft's spawn is at eye level, ours at the feet
+        let (x, z, y) = header.spawn;
+        let spawn = |position: i32, size: i16| (position / 32).clamp(0, size as i32 - 1) as i16;
+        let mut map = DmfMap::new(
+            spawn(x, x_size),
+            spawn(y.saturating_sub(51), y_size),
+            spawn(z, z_size),
+            x_size,
+            y_size,
+            z_size,
+        );
+        map.metadata.spawn_yaw = header.yaw;
+        map.metadata.spawn_pitch = header.pitch;
+        map.metadata.created_at = header.created_at;
+        map.metadata.generator = Some("fCraft".to_string());
+
+        // same order as ours, y then z then x
+        for (block, raw) in map.blocks.iter_mut().zip(&raw) {
+            *block = if *raw > LAST_CPE_BLOCK { STONE } else { *raw };
+        }
+        for entry in &entries {
+            apply_entry(&mut map, entry);
+        }
+
+        // there is no writing .fcm back, the next save stores it as our own format
+        map.mark_dirty();
+        Ok(map)
+    }
+}
+
+pub fn read_dimensions(mut data: impl Read) -> Result<(i16, i16, i16)> {
+    dimensions(&read_header(&mut data)?)
+}
+
+fn read_header(reader: &mut impl Read) -> Result<Header> {
+    let identifier = u32::from_le_bytes(read_array(reader)?);
+    match identifier {
+        IDENTIFIER_V2 => {
+            // width, length, height, spawn x, z, y as shorts, yaw, pitch
+            let fields: [u8; 14] = read_array(reader)?;
+            let u16_at = |at: usize| u16::from_le_bytes([fields[at], fields[at + 1]]);
+            let i16_at = |at: usize| u16_at(at) as i16 as i32;
+            Ok(Header {
+                version: Version::V2,
+                width: u16_at(0),
+                length: u16_at(2),
+                height: u16_at(4),
+                spawn: (i16_at(6), i16_at(8), i16_at(10)),
+                yaw: fields[12],
+                pitch: fields[13],
+                created_at: None,
+                entries: u16::from_le_bytes(read_array(reader)?).into(),
+            })
+        }
+        IDENTIFIER_V3 => {
+            let [revision] = read_array(reader)?;
+            if revision != REVISION_V3 {
+                return Err(Error::InvalidMap(format!(
+                    "unsupported fcm revision {}",
+                    revision
+                )));
+            }
+            // width, height, length, spawn x, z, y as ints, yaw, pitch,
+            // modified and created times, a guid and the layer index
+            let fields: [u8; 70] = read_array(reader)?;
+            let u16_at = |at: usize| u16::from_le_bytes([fields[at], fields[at + 1]]);
+            let u32_at = |at: usize| u32::from_le_bytes(fields[at..at + 4].try_into().unwrap());
+            Ok(Header {
+                version: Version::V3,
+                width: u16_at(0),
+                height: u16_at(2),
+                length: u16_at(4),
+                spawn: (u32_at(6) as i32, u32_at(10) as i32, u32_at(14) as i32),
+                yaw: fields[18],
+                pitch: fields[19],
+                created_at: Some(u32_at(24).into()).filter(|created| *created > 0),
+                entries: u32::from_le_bytes(read_array(reader)?),
+            })
+        }
+        other => Err(Error::InvalidMap(format!(
+            "unknown fcm identifier {:#010x}",
+            other
+        ))),
+    }
+}
+
+fn dimensions(header: &Header) -> Result<(i16, i16, i16)> {
+    let size = |size: u16| {
+        i16::try_from(size)
+            .ok()
+            .filter(|size| *size > 0)
+            .ok_or_else(|| {
+                Error::InvalidMap(format!(
+                    "bad dimensions {}x{}x{}",
+                    header.width, header.height, header.length
+                ))
+            })
+    };
+    Ok((
+        size(header.width)?,
+        size(header.height)?,
+        size(header.length)?,
+    ))
+}
+
+fn read_entries(reader: &mut impl Read, header: &Header) -> Result<Vec<Entry>> {
+    let version = header.version;
+    let mut entries = Vec::new();
+    for _ in 0..header.entries {
+        let group = match version {
+            Version::V2 => String::new(),
+            Version::V3 => read_string(reader, version)?,
+        };
+        entries.push(Entry {
+            group,
+            key: read_string(reader, version)?,
+            value: read_string(reader, version)?,
+        });
+    }
+    Ok(entries)
+}
+
+// the environment is the only metadata we have a place for. zones have no
+// equivalent here and are dropped, like the rest
+fn apply_entry(map: &mut DmfMap, entry: &Entry) {
+    if entry.group.eq_ignore_ascii_case("zones") || entry.key.starts_with('@') {
+        return;
+    }
+    let environment = &mut map.metadata.environment;
+    let value = entry.value.trim();
+    match entry.key.to_ascii_lowercase().as_str() {
+        "skycolor" => environment.sky_color = parse_color(value),
+        "cloudcolor" => environment.cloud_color = parse_color(value),
+        "fogcolor" => environment.fog_color = parse_color(value),
+        "edgeblock" | "borderblock" | "horizonblock" => {
+            environment.edge_block = parse_block(value).or(environment.edge_block)
+        }
+        "sideblock" | "bedrockblock" => {
+            environment.side_block = parse_block(value).or(environment.side_block)
+        }
+        "edgelevel" | "waterlevel" => {
+            // -1 asks for the default, which is what None means to us
+            environment.edge_height = value.parse().ok().filter(|height| *height >= 0)
+        }
+        "weather" => environment.weather = value.parse().ok().filter(|weather| *weather <= 2),
+        _ => {}
+    }
+}
+
+// hex with or without a #, or a decimal int where -1 means the default
+fn parse_color(value: &str) -> Option<[u8; 3]> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    let rgb = if hex.len() == 6 && hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        u32::from_str_radix(hex, 16).ok()?
+    } else {
+        u32::try_from(value.parse::<i64>().ok()?).ok()?
+    };
+    let [_, r, g, b] = rgb.to_be_bytes();
+    Some([r, g, b])
+}
+
+fn parse_block(value: &str) -> Option<u8> {
+    match value.parse::<u8>() {
+        Ok(block) => Some(block).filter(|block| *block <= LAST_CPE_BLOCK),
+        Err(_) => BLOCK_NAMES
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(value))
+            .map(|block| block as u8),
+    }
+}
+
+// v2 strings have an int length, v3 ones a short. read through take so a
+// bogus length can't make us allocate gigabytes up front
+fn read_string(reader: &mut impl Read, version: Version) -> Result<String> {
+    let length = match version {
+        Version::V2 => u32::from_le_bytes(read_array(reader)?),
+        Version::V3 => u16::from_le_bytes(read_array(reader)?).into(),
+    };
+    let mut buf = Vec::new();
+    reader.take(length.into()).read_to_end(&mut buf)?;
+    if buf.len() != length as usize {
+        return Err(Error::InvalidMap("file ends too early".to_string()));
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{
+        write::{DeflateEncoder, GzEncoder},
+        Compression,
+    };
+    use std::io::Write;
+
+    // 4 wide, 2 high and 3 long
+    fn blocks() -> Vec<u8> {
+        let mut blocks = vec![0u8; 4 * 2 * 3];
+        blocks[0] = 1;
+        blocks[4 * 3 + 4 + 2] = 49;
+        blocks[23] = 200;
+        blocks
+    }
+
+    fn fcm_v3(entries: &[(&str, &str, &str)]) -> Vec<u8> {
+        let mut data = IDENTIFIER_V3.to_le_bytes().to_vec();
+        data.push(REVISION_V3);
+        for size in [4u16, 2, 3] {
+            data.extend_from_slice(&size.to_le_bytes());
+        }
+        // spawn at 1, 1, 2 in blocks
+        for position in [32i32 + 16, 64 + 16, 32 + 51] {
+            data.extend_from_slice(&position.to_le_bytes());
+        }
+        data.extend_from_slice(&[64, 32]);
+        data.extend_from_slice(&1_700_000_100u32.to_le_bytes());
+        data.extend_from_slice(&1_700_000_000u32.to_le_bytes());
+        data.extend_from_slice(&[0; 16 + 26]);
+        data.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+
+        let mut encoder = DeflateEncoder::new(data, Compression::default());
+        for (group, key, value) in entries {
+            for text in [group, key, value] {
+                encoder
+                    .write_all(&(text.len() as u16).to_le_bytes())
+                    .unwrap();
+                encoder.write_all(text.as_bytes()).unwrap();
+            }
+        }
+        encoder.write_all(&blocks()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn reads_v3_maps() {
+        let data = fcm_v3(&[
+            ("zones", "spawn", "spawn 0 1 0 1 0 1 op"),
+            ("Environment", "SkyColor", "#ff0000"),
+            ("Environment", "FogColor", "-1"),
+            ("Environment", "EdgeBlock", "Lava"),
+            ("Environment", "SideBlock", "7"),
+            ("Environment", "EdgeLevel", "1"),
+        ]);
+        let map = DmfMap::from_fcm(data.as_slice()).unwrap();
+        assert_eq!((map.x_size, map.y_size, map.z_size), (4, 2, 3));
+        assert_eq!((map.x_spawn, map.y_spawn, map.z_spawn), (1, 1, 2));
+        assert_eq!((map.metadata.spawn_yaw, map.metadata.spawn_pitch), (64, 32));
+        assert_eq!(map.metadata.created_at, Some(1_700_000_000));
+        assert_eq!(map.get_block(0, 0, 0), 1);
+        assert_eq!(map.get_block(2, 1, 1), 49);
+        assert_eq!(map.get_block(3, 1, 2), STONE);
+
+        let environment = &map.metadata.environment;
+        assert_eq!(environment.sky_color, Some([255, 0, 0]));
+        assert_eq!(environment.fog_color, None);
+        assert_eq!(environment.edge_block, Some(10));
+        assert_eq!(environment.side_block, Some(7));
+        assert_eq!(environment.edge_height, Some(1));
+        assert!(map.is_dirty());
+        assert_eq!(read_dimensions(data.as_slice()).unwrap(), (4, 2, 3));
+    }
+
+    #[test]
+    fn reads_v2_maps() {
+        let mut data = IDENTIFIER_V2.to_le_bytes().to_vec();
+        for value in [4u16, 3, 2, 48, 80, 83] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&[128, 0]);
+        data.extend_from_slice(&1u16.to_le_bytes());
+        for text in ["CloudColor", "00ff00"] {
+            data.extend_from_slice(&(text.len() as u32).to_le_bytes());
+            data.extend_from_slice(text.as_bytes());
+        }
+        let mut encoder = GzEncoder::new(data, Compression::default());
+        encoder.write_all(&blocks()).unwrap();
+        let data = encoder.finish().unwrap();
+
+        let map = DmfMap::from_fcm(data.as_slice()).unwrap();
+        assert_eq!((map.x_size, map.y_size, map.z_size), (4, 2, 3));
+        assert_eq!((map.x_spawn, map.y_spawn, map.z_spawn), (1, 1, 2));
+        assert_eq!(map.metadata.spawn_yaw, 128);
+        assert_eq!(map.metadata.environment.cloud_color, Some([0, 255, 0]));
+        assert_eq!(map.get_block(2, 1, 1), 49);
+    }
+
+    #[test]
+    fn rejects_broken_files() {
+        let mut data = fcm_v3(&[]);
+        data.truncate(data.len() / 2);
+        assert!(DmfMap::from_fcm(data.as_slice()).is_err());
+
+        let mut data = fcm_v3(&[]);
+        data[4] = 12;
+        assert!(DmfMap::from_fcm(data.as_slice()).is_err());
+        assert!(DmfMap::from_fcm(&[0u8; 8][..]).is_err());
+
+        // a header claiming far more blocks than there are
+        let mut data = fcm_v3(&[]);
+        for at in [5, 7, 9] {
+            data[at..at + 2].copy_from_slice(&i16::MAX.to_le_bytes());
+        }
+        assert!(matches!(
+            DmfMap::from_fcm(data.as_slice()),
+            Err(Error::InvalidMap(_))
+        ));
+    }
+}
@@ -0,0 +1,566 @@
+use crate::server::error::{Error, Result};
+use std::io::{self, Read};
+use std::rc::Rc;
+
+// just enough of java's object serialization to pull the fields out of a
+// saved object graph. class annotations and writeObject data are skipped, so
+// only the default serialized fields come back
+
+const STREAM_MAGIC: u16 = 0xACED;
+const STREAM_VERSION: u16 = 5;
+
+const TC_NULL: u8 = 0x70;
+const TC_REFERENCE: u8 = 0x71;
+const TC_CLASSDESC: u8 = 0x72;
+const TC_OBJECT: u8 = 0x73;
+const TC_STRING: u8 = 0x74;
+const TC_ARRAY: u8 = 0x75;
+const TC_CLASS: u8 = 0x76;
+const TC_BLOCKDATA: u8 = 0x77;
+const TC_ENDBLOCKDATA: u8 = 0x78;
+const TC_RESET: u8 = 0x79;
+const TC_BLOCKDATALONG: u8 = 0x7A;
+const TC_LONGSTRING: u8 = 0x7C;
+const TC_PROXYCLASSDESC: u8 = 0x7D;
+const TC_ENUM: u8 = 0x7E;
+
+const SC_WRITE_METHOD: u8 = 0x01;
+const SC_SERIALIZABLE: u8 = 0x02;
+const SC_EXTERNALIZABLE: u8 = 0x04;
+const SC_BLOCK_DATA: u8 = 0x08;
+
+// handles count up from here in the order objects appear in the stream
+const BASE_HANDLE: u32 = 0x7E_0000;
+
+// deeper than any real file nests, keeps a crafted one from blowing the stack
+const MAX_DEPTH: usize = 512;
+
+// shared values are behind an rc so a reference doesn't copy them, a crafted
+// file could otherwise double its size with every level of references
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Boolean(bool),
+    Byte(i8),
+    Char(u16),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    String(Rc<str>),
+    Bytes(Rc<Vec<u8>>),
+    Array(Rc<Vec<Value>>),
+    Object(Rc<Object>),
+}
+
+// fields of every serializable class in the hierarchy, superclasses first
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Object {
+    pub class: String,
+    fields: Vec<(String, Value)>,
+}
+
+impl Value {
+    // any integer type, like the nbt one
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Value::Byte(value) => Some(value.into()),
+            Value::Short(value) => Some(value.into()),
+            Value::Int(value) => Some(value.into()),
+            Value::Long(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl Object {
+    // a subclass field hides a superclass one with the same name
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.fields
+            .iter()
+            .rev()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn int(&self, name: &str) -> Option<i64> {
+        self.get(name).and_then(Value::as_i64)
+    }
+
+    pub fn float(&self, name: &str) -> Option<f64> {
+        match self.get(name)? {
+            Value::Float(value) => Some((*value).into()),
+            Value::Double(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn string(&self, name: &str) -> Option<&str> {
+        match self.get(name)? {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn bytes(&self, name: &str) -> Option<&[u8]> {
+        match self.get(name)? {
+            Value::Bytes(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+// the first object written to the stream
+pub fn read(reader: &mut impl Read) -> Result<Value> {
+    JavaReader::new(reader, false).read_root()
+}
+
+// same as read but byte arrays come back empty, for when only the small
+// fields are wanted
+pub fn read_without_arrays(reader: &mut impl Read) -> Result<Value> {
+    JavaReader::new(reader, true).read_root()
+}
+
+#[derive(Debug)]
+struct ClassDesc {
+    name: String,
+    flags: u8,
+    // type code and name
+    fields: Vec<(u8, String)>,
+    parent: Option<Rc<ClassDesc>>,
+}
+
+#[derive(Debug, Clone)]
+enum Handle {
+    Class(Rc<ClassDesc>),
+    Value(Value),
+}
+
+struct JavaReader<'a, R> {
+    reader: &'a mut R,
+    skip_arrays: bool,
+    handles: Vec<Handle>,
+}
+
+impl<'a, R: Read> JavaReader<'a, R> {
+    fn new(reader: &'a mut R, skip_arrays: bool) -> Self {
+        Self {
+            reader,
+            skip_arrays,
+            handles: Vec::new(),
+        }
+    }
+
+    fn read_root(&mut self) -> Result<Value> {
+        let magic = u16::from_be_bytes(self.array()?);
+        let version = u16::from_be_bytes(self.array()?);
+        if magic != STREAM_MAGIC || version != STREAM_VERSION {
+            return Err(Error::InvalidMap(
+                "not a java serialization stream".to_string(),
+            ));
+        }
+        let code = self.u8()?;
+        self.content(code, 0)
+    }
+
+    fn content(&mut self, code: u8, depth: usize) -> Result<Value> {
+        if depth > MAX_DEPTH {
+            return Err(Error::InvalidMap("java object nests too deep".to_string()));
+        }
+        Ok(match code {
+            TC_NULL => Value::Null,
+            TC_REFERENCE => match self.reference()? {
+                Handle::Value(value) => value,
+                Handle::Class(_) => {
+                    return Err(Error::InvalidMap(
+                        "java reference to a class where a value belongs".to_string(),
+                    ))
+                }
+            },
+            TC_OBJECT => self.object(depth)?,
+            TC_STRING => {
+                let length = u16::from_be_bytes(self.array()?) as u64;
+                self.new_string(length)?
+            }
+            TC_LONGSTRING => {
+                let length = u64::from_be_bytes(self.array()?);
+                self.new_string(length)?
+            }
+            TC_ARRAY => self.array_value(depth)?,
+            TC_CLASS => {
+                self.class_desc(depth + 1)?;
+                self.handles.push(Handle::Value(Value::Null));
+                Value::Null
+            }
+            TC_ENUM => {
+                self.class_desc(depth + 1)?;
+                let handle = self.reserve();
+                let code = self.u8()?;
+                let name = self.content(code, depth + 1)?;
+                self.handles[handle] = Handle::Value(name.clone());
+                name
+            }
+            TC_RESET => {
+                self.handles.clear();
+                let code = self.u8()?;
+                self.content(code, depth + 1)?
+            }
+            other => {
+                return Err(Error::InvalidMap(format!(
+                    "unexpected java stream code {:#04x}",
+                    other
+                )))
+            }
+        })
+    }
+
+    fn class_desc(&mut self, depth: usize) -> Result<Option<Rc<ClassDesc>>> {
+        if depth > MAX_DEPTH {
+            return Err(Error::InvalidMap("java object nests too deep".to_string()));
+        }
+        match self.u8()? {
+            TC_NULL => Ok(None),
+            TC_REFERENCE => match self.reference()? {
+                Handle::Class(class) => Ok(Some(class)),
+                Handle::Value(_) => Err(Error::InvalidMap(
+                    "java reference to a value where a class belongs".to_string(),
+                )),
+            },
+            TC_CLASSDESC => {
+                let name = self.utf()?;
+                let _serial_version: [u8; 8] = self.array()?;
+                let handle = self.reserve();
+                let flags = self.u8()?;
+                let count = u16::from_be_bytes(self.array()?);
+                let mut fields = Vec::new();
+                for _ in 0..count {
+                    let code = self.u8()?;
+                    let name = self.utf()?;
+                    if code == b'L' || code == b'[' {
+                        // the field's class name, which we don't need
+                        let code = self.u8()?;
+                        self.content(code, depth + 1)?;
+                    }
+                    fields.push((code, name));
+                }
+                self.skip_annotation(depth + 1)?;
+                let parent = self.class_desc(depth + 1)?;
+                let class = Rc::new(ClassDesc {
+                    name,
+                    flags,
+                    fields,
+                    parent,
+                });
+                self.handles[handle] = Handle::Class(class.clone());
+                Ok(Some(class))
+            }
+            TC_PROXYCLASSDESC => {
+                let handle = self.reserve();
+                let count = i32::from_be_bytes(self.array()?);
+                for _ in 0..count {
+                    self.utf()?;
+                }
+                self.skip_annotation(depth + 1)?;
+                let parent = self.class_desc(depth + 1)?;
+                let class = Rc::new(ClassDesc {
+                    name: String::new(),
+                    flags: SC_SERIALIZABLE,
+                    fields: Vec::new(),
+                    parent,
+                });
+                self.handles[handle] = Handle::Class(class.clone());
+                Ok(Some(class))
+            }
+            other => Err(Error::InvalidMap(format!(
+                "expected a java class, got {:#04x}",
+                other
+            ))),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Value> {
+        let class = self.required_class(depth)?;
+        let handle = self.reserve();
+
+        let mut hierarchy = Vec::new();
+        let mut next = Some(class.clone());
+        while let Some(class) = next {
+            next = class.parent.clone();
+            hierarchy.push(class);
+        }
+
+        let mut fields = Vec::new();
+        for class in hierarchy.iter().rev() {
+            if class.flags & SC_EXTERNALIZABLE != 0 {
+                if class.flags & SC_BLOCK_DATA == 0 {
+                    return Err(Error::InvalidMap(format!(
+                        "can't read externalizable {} from an old stream",
+                        class.name
+                    )));
+                }
+                self.skip_annotation(depth + 1)?;
+                continue;
+            }
+            for (code, name) in &class.fields {
+                let value = self.field(*code, depth + 1)?;
+                fields.push((name.clone(), value));
+            }
+            if class.flags & SC_WRITE_METHOD != 0 {
+                self.skip_annotation(depth + 1)?;
+            }
+        }
+
+        let object = Value::Object(Rc::new(Object {
+            class: class.name.clone(),
+            fields,
+        }));
+        self.handles[handle] = Handle::Value(object.clone());
+        Ok(object)
+    }
+
+    fn array_value(&mut self, depth: usize) -> Result<Value> {
+        let class = self.required_class(depth)?;
+        let handle = self.reserve();
+        let length = i32::from_be_bytes(self.array()?);
+        let length = usize::try_from(length)
+            .map_err(|_| Error::InvalidMap(format!("negative java array length {}", length)))?;
+
+        // the class name is [ followed by the element's type code
+        let element = class.name.as_bytes().get(1).copied().unwrap_or(0);
+        let array = if element == b'B' {
+            Value::Bytes(Rc::new(self.bytes(length as u64)?))
+        } else {
+            let mut values = Vec::new();
+            for _ in 0..length {
+                values.push(self.field(element, depth + 1)?);
+            }
+            Value::Array(Rc::new(values))
+        };
+        self.handles[handle] = Handle::Value(array.clone());
+        Ok(array)
+    }
+
+    fn field(&mut self, code: u8, depth: usize) -> Result<Value> {
+        Ok(match code {
+            b'Z' => Value::Boolean(self.u8()? != 0),
+            b'B' => Value::Byte(self.u8()? as i8),
+            b'C' => Value::Char(u16::from_be_bytes(self.array()?)),
+            b'S' => Value::Short(i16::from_be_bytes(self.array()?)),
+            b'I' => Value::Int(i32::from_be_bytes(self.array()?)),
+            b'J' => Value::Long(i64::from_be_bytes(self.array()?)),
+            b'F' => Value::Float(f32::from_be_bytes(self.array()?)),
+            b'D' => Value::Double(f64::from_be_bytes(self.array()?)),
+            b'L' | b'[' => {
+                let code = self.u8()?;
+                self.content(code, depth)?
+            }
+            other => {
+                return Err(Error::InvalidMap(format!(
+                    "unknown java field type {:#04x}",
+                    other
+                )))
+            }
+        })
+    }
+
+    // whatever a class or writeObject added, up to the end marker. objects in
+    // there still take up handles, so they have to be read properly
+    fn skip_annotation(&mut self, depth: usize) -> Result<()> {
+        loop {
+            match self.u8()? {
+                TC_ENDBLOCKDATA => return Ok(()),
+                TC_BLOCKDATA => {
+                    let length = self.u8()? as u64;
+                    self.skip(length)?;
+                }
+                TC_BLOCKDATALONG => {
+                    let length = u32::from_be_bytes(self.array()?) as u64;
+                    self.skip(length)?;
+                }
+                code => {
+                    self.content(code, depth)?;
+                }
+            }
+        }
+    }
+
+    fn required_class(&mut self, depth: usize) -> Result<Rc<ClassDesc>> {
+        self.class_desc(depth + 1)?
+            .ok_or_else(|| Error::InvalidMap("java object without a class".to_string()))
+    }
+
+    fn reserve(&mut self) -> usize {
+        self.handles.push(Handle::Value(Value::Null));
+        self.handles.len() - 1
+    }
+
+    fn reference(&mut self) -> Result<Handle> {
+        let handle = u32::from_be_bytes(self.array()?);
+        handle
+            .checked_sub(BASE_HANDLE)
+            .and_then(|index| self.handles.get(index as usize))
+            .cloned()
+            .ok_or_else(|| Error::InvalidMap(format!("unknown java handle {:#x}", handle)))
+    }
+
+    fn new_string(&mut self, length: u64) -> Result<Value> {
+        let mut limited = (&mut *self.reader).take(length);
+        let mut buf = Vec::new();
+        if limited.read_to_end(&mut buf).map_err(cut_off)? as u64 != length {
+            return Err(Error::InvalidMap("java data ends too early".to_string()));
+        }
+        let value = Value::String(String::from_utf8_lossy(&buf).into());
+        self.handles.push(Handle::Value(value.clone()));
+        Ok(value)
+    }
+
+    fn utf(&mut self) -> Result<String> {
+        let length = u16::from_be_bytes(self.array()?) as usize;
+        let mut buf = vec![0u8; length];
+        self.reader.read_exact(&mut buf).map_err(cut_off)?;
+        // modified utf-8 only differs for characters class names don't use
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.reader.read_exact(&mut buf).map_err(cut_off)?;
+        Ok(buf)
+    }
+
+    // read through take so a bogus length can't make us allocate gigabytes up front
+    fn bytes(&mut self, length: u64) -> Result<Vec<u8>> {
+        if self.skip_arrays {
+            self.skip(length)?;
+            return Ok(Vec::new());
+        }
+        let mut limited = (&mut *self.reader).take(length);
+        let mut buf = Vec::new();
+        if limited.read_to_end(&mut buf).map_err(cut_off)? as u64 != length {
+            return Err(Error::InvalidMap("java data ends too early".to_string()));
+        }
+        Ok(buf)
+    }
+
+    fn skip(&mut self, length: u64) -> Result<()> {
+        let mut limited = (&mut *self.reader).take(length);
+        if io::copy(&mut limited, &mut io::sink()).map_err(cut_off)? != length {
+            return Err(Error::InvalidMap("java data ends too early".to_string()));
+        }
+        Ok(())
+    }
+}
+
+fn cut_off(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::InvalidMap("java data ends too early".to_string()),
+        _ => Error::Io(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf(out: &mut Vec<u8>, value: &str) {
+        out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+
+    // class Base { int size; }
+    // class Child extends Base { String name; String alias; byte[] data; }
+    // where Child has a writeObject that adds some block data and a string
+    fn sample() -> Vec<u8> {
+        let mut out = vec![0xAC, 0xED, 0x00, 0x05, TC_OBJECT];
+
+        // 0x7e0000 Child
+        out.push(TC_CLASSDESC);
+        utf(&mut out, "Child");
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&[SC_SERIALIZABLE | SC_WRITE_METHOD, 0, 3]);
+        out.push(b'L');
+        utf(&mut out, "name");
+        // 0x7e0001
+        out.push(TC_STRING);
+        utf(&mut out, "Ljava/lang/String;");
+        out.push(b'L');
+        utf(&mut out, "alias");
+        out.push(TC_REFERENCE);
+        out.extend_from_slice(&0x7E_0001u32.to_be_bytes());
+        out.push(b'[');
+        utf(&mut out, "data");
+        // 0x7e0002
+        out.push(TC_STRING);
+        utf(&mut out, "[B");
+        out.push(TC_ENDBLOCKDATA);
+
+        // 0x7e0003 Base
+        out.push(TC_CLASSDESC);
+        utf(&mut out, "Base");
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&[SC_SERIALIZABLE, 0, 1]);
+        out.push(b'I');
+        utf(&mut out, "size");
+        out.extend_from_slice(&[TC_ENDBLOCKDATA, TC_NULL]);
+
+        // 0x7e0004 the object, Base's fields come first
+        out.extend_from_slice(&42i32.to_be_bytes());
+        // 0x7e0005
+        out.push(TC_STRING);
+        utf(&mut out, "hub");
+        out.push(TC_REFERENCE);
+        out.extend_from_slice(&0x7E_0005u32.to_be_bytes());
+        // 0x7e0006 [B, 0x7e0007 the array
+        out.push(TC_ARRAY);
+        out.push(TC_CLASSDESC);
+        utf(&mut out, "[B");
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&[SC_SERIALIZABLE, 0, 0, TC_ENDBLOCKDATA, TC_NULL]);
+        out.extend_from_slice(&3i32.to_be_bytes());
+        out.extend_from_slice(&[1, 2, 3]);
+
+        // writeObject extras
+        out.extend_from_slice(&[TC_BLOCKDATA, 2, 0xFF, 0xFF, TC_STRING]);
+        utf(&mut out, "extra");
+        out.push(TC_ENDBLOCKDATA);
+        out
+    }
+
+    #[test]
+    fn reads_fields_across_the_hierarchy() {
+        let Value::Object(object) = read(&mut sample().as_slice()).unwrap() else {
+            panic!("not an object");
+        };
+        assert_eq!(object.class, "Child");
+        assert_eq!(object.int("size"), Some(42));
+        assert_eq!(object.string("name"), Some("hub"));
+        assert_eq!(object.string("alias"), Some("hub"));
+        assert_eq!(object.bytes("data"), Some(&[1, 2, 3][..]));
+
+        let Value::Object(object) = read_without_arrays(&mut sample().as_slice()).unwrap() else {
+            panic!("not an object");
+        };
+        assert_eq!(object.bytes("data"), Some(&[][..]));
+        assert_eq!(object.int("size"), Some(42));
+    }
+
+    #[test]
+    fn rejects_broken_data() {
+        let mut data = sample();
+        data.truncate(data.len() - 4);
+        assert!(read(&mut data.as_slice()).is_err());
+
+        let mut data = sample();
+        data[1] = 0;
+        assert!(read(&mut data.as_slice()).is_err());
+
+        // a reference to a handle that was never handed out
+        let data = [0xAC, 0xED, 0x00, 0x05, TC_REFERENCE, 0x00, 0x7E, 0x00, 0x09];
+        assert!(read(&mut data.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use crate::server::error::{Error, Result};
+use crate::server::formats::{read_blocks, read_exact, LAST_CPE_BLOCK, STONE};
+use crate::server::game::dmf_map::DmfMap;
+use flate2::read::GzDecoder;
+use std::io::Read;
+
+// MCGalaxy's .lvl: a gzipped header, the blocks, then optional sections. the
+// one we care about holds the extended ids of custom blocks in 16³ chunks
+
+const SIGNATURE: u16 = 1874;
+const CUSTOM_BLOCKS_SECTION: u8 = 0xBD;
+const CHUNK_SIZE: usize = 16 * 16 * 16;
+
+// placeholders in the block array that say the real id is in a custom chunk,
+// as 256, 512 or 768 plus the byte stored there
+const CUSTOM_BLOCK: u8 = 163;
+const CUSTOM_BLOCK_2: u8 = 198;
+const CUSTOM_BLOCK_3: u8 = 199;
+
+struct Header {
+    width: u16,
+    height: u16,
+    length: u16,
+    spawn: (u16, u16, u16),
+    yaw: u8,
+    pitch: u8,
+}
+
+impl DmfMap {
+    pub fn from_lvl(data: impl Read) -> Result<Self> {
+        let mut reader = GzDecoder::new(data);
+        let header = read_header(&mut reader)?;
+        let (x_size, y_size, z_size) = dimensions(&header)?;
+        let raw = read_blocks(
+            &mut reader,
+            x_size as usize * y_size as usize * z_size as usize,
+        )?;
+        let (x_spawn, y_spawn, z_spawn) = header.spawn;
+        let mut map = DmfMap::new(
+            x_spawn as i16,
+            y_spawn as i16,
+            z_spawn as i16,
+            x_size,
+            y_size,
+            z_size,
+        );
+        map.metadata.spawn_yaw = header.yaw;
+        map.metadata.spawn_pitch = header.pitch;
+        map.metadata.generator = Some("MCGalaxy".to_string());
+
+        let custom_chunks = read_custom_blocks(&mut reader, &header)?;
+
+        let chunks_x = (header.width as usize).div_ceil(16);
+        let chunks_z = (header.length as usize).div_ceil(16);
+        let (width, length) = (header.width as usize, header.length as usize);
+        for (index, (block, raw)) in map.blocks.iter_mut().zip(&raw).enumerate() {
+            *block = match *raw {
+                CUSTOM_BLOCK | CUSTOM_BLOCK_2 | CUSTOM_BLOCK_3 => {
+                    let (x, z, y) = (
+                        index % width,
+                        index / width % length,
+                        index / width / length,
+                    );
+                    let chunk = (y / 16 * chunks_z + z / 16) * chunks_x + x / 16;
+                    let extended = custom_chunks
+                        .get(chunk)
+                        .and_then(|chunk| chunk.as_ref())
+                        .map_or(0, |chunk| chunk[(y % 16) << 8 | (z % 16) << 4 | (x % 16)]);
+                    custom_block(*raw, extended)
+                }
+                raw => core_block(raw),
+            };
+        }
+        // there is no writing .lvl back, the next save stores it as our own format
+        map.mark_dirty();
+        Ok(map)
+    }
+}
+
+pub fn read_dimensions(data: impl Read) -> Result<(i16, i16, i16)> {
+    dimensions(&read_header(&mut GzDecoder::new(data))?)
+}
+
+// very old files have no signature and start right at the width
+fn read_header(reader: &mut impl Read) -> Result<Header> {
+    let mut first = [0u8; 2];
+    read_exact(reader, &mut first)?;
+    let signed = u16::from_le_bytes(first) == SIGNATURE;
+    if signed {
+        read_exact(reader, &mut first)?;
+    }
+
+    // length, height, spawn x, z, y, yaw, pitch
+    let mut rest = [0u8; 12];
+    read_exact(reader, &mut rest)?;
+    let u16_at = |at: usize| u16::from_le_bytes([rest[at], rest[at + 1]]);
+    let header = Header {
+        width: u16::from_le_bytes(first),
+        length: u16_at(0),
+        height: u16_at(2),
+        spawn: (u16_at(4), u16_at(8), u16_at(6)),
+        yaw: rest[10],
+        pitch: rest[11],
+    };
+    if signed {
+        // visit and build permissions, ranks don't carry over
+        let mut permissions = [0u8; 2];
+        read_exact(reader, &mut permissions)?;
+    }
+    Ok(header)
+}
+
+fn dimensions(header: &Header) -> Result<(i16, i16, i16)> {
+    let size = |size: u16| {
+        i16::try_from(size)
+            .ok()
+            .filter(|size| *size > 0)
+            .ok_or_else(|| {
+                Error::InvalidMap(format!(
+                    "bad dimensions {}x{}x{}",
+                    header.width, header.height, header.length
+                ))
+            })
+    };
+    Ok((
+        size(header.width)?,
+        size(header.height)?,
+        size(header.length)?,
+    ))
+}
+
+// one flag byte per chunk, y then z then x, followed by the chunk if it is set
+fn read_custom_blocks(reader: &mut impl Read, header: &Header) -> Result<Vec<Option<Vec<u8>>>> {
+    let mut section = [0u8; 1];
+    if reader.read(&mut section)? == 0 || section[0] != CUSTOM_BLOCKS_SECTION {
+        return Ok(Vec::new());
+    }
+    let chunks = (header.width as usize).div_ceil(16)
+        * (header.height as usize).div_ceil(16)
+        * (header.length as usize).div_ceil(16);
+    let mut custom_chunks = Vec::with_capacity(chunks);
+    for _ in 0..chunks {
+        let mut present = [0u8; 1];
+        read_exact(reader, &mut present)?;
+        if present[0] == 1 {
+            let mut chunk = vec![0u8; CHUNK_SIZE];
+            read_exact(reader, &mut chunk)?;
+            custom_chunks.push(Some(chunk));
+        } else {
+            custom_chunks.push(None);
+        }
+    }
+    Ok(custom_chunks)
+}
+
+// custom blocks past the cpe ones keep their id so block definitions still
+// apply, everything that doesn't fit in a byte becomes stone
+fn custom_block(placeholder: u8, extended: u8) -> u8 {
+    match placeholder {
+        CUSTOM_BLOCK if extended > LAST_CPE_BLOCK => extended,
+        _ => STONE,
+    }
+}
+
+// MCGalaxy's own blocks past the cpe ones mostly behave like a normal block,
+// clients are sent that block instead and so do we
+fn core_block(block: u8) -> u8 {
+    match block {
+        0..=LAST_CPE_BLOCK => block,
+        100 => 0x14, // op_glass
+        101 => 0x31, // opsidian
+        102 => 0x2d, // op_brick
+        103 => 0x01, // op_stone
+        104 => 0x04, // op_cobblestone
+        105 => 0x00, // op_air
+        106 => 0x08, // op_water
+        107 => 0x0a, // op_lava
+        108 => 0x01, // griefer_stone
+        109 => 0x13, // lava_sponge
+        110 => 0x05, // wood_float
+        111 => 0x11, // door_log
+        112 => 0x0a, // lava_fast
+        113 => 0x31, // door_obsidian
+        114 => 0x14, // door_glass
+        115 => 0x01, // door_stone
+        116 => 0x12, // door_leaves
+        117 => 0x0c, // door_sand
+        118 => 0x05, // door_wood
+        119 => 0x19, // door_green
+        120 => 0x2e, // door_tnt
+        121 => 0x2c, // door_slab
+        _ => STONE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    fn lvl_file(signed: bool, blocks: &[u8], custom: Option<&[u8]>) -> Vec<u8> {
+        let mut data = Vec::new();
+        if signed {
+            data.extend_from_slice(&SIGNATURE.to_le_bytes());
+        }
+        // 4 wide, 2 high, 3 long, spawn at 1, 1, 2
+        for value in [4u16, 3, 2, 1, 2, 1] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&[64, 32]);
+        if signed {
+            data.extend_from_slice(&[0, 0]);
+        }
+        data.extend_from_slice(blocks);
+        if let Some(chunk) = custom {
+            data.extend_from_slice(&[CUSTOM_BLOCKS_SECTION, 1]);
+            data.extend_from_slice(chunk);
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn reads_header_spawn_and_blocks() {
+        let mut blocks = vec![0u8; 4 * 2 * 3];
+        blocks[0] = 1;
+        blocks[23] = 102;
+        for signed in [true, false] {
+            let data = lvl_file(signed, &blocks, None);
+            let map = DmfMap::from_lvl(data.as_slice()).unwrap();
+            assert_eq!((map.x_size, map.y_size, map.z_size), (4, 2, 3));
+            assert_eq!((map.x_spawn, map.y_spawn, map.z_spawn), (1, 1, 2));
+            assert_eq!(map.metadata.spawn_yaw, 64);
+            assert_eq!(map.get_block(0, 0, 0), 1);
+            assert_eq!(map.get_block(3, 1, 2), 0x2d);
+            assert!(map.is_dirty());
+            assert_eq!(read_dimensions(data.as_slice()).unwrap(), (4, 2, 3));
+        }
+    }
+
+    #[test]
+    fn resolves_custom_blocks() {
+        let mut blocks = vec![0u8; 4 * 2 * 3];
+        // x 2, y 1, z 1 and x 3, y 0, z 0
+        blocks[4 * 3 + 4 + 2] = CUSTOM_BLOCK;
+        blocks[3] = CUSTOM_BLOCK_2;
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        chunk[1 << 8 | 1 << 4 | 2] = 80;
+        chunk[3] = 90;
+        let data = lvl_file(true, &blocks, Some(&chunk));
+
+        let map = DmfMap::from_lvl(data.as_slice()).unwrap();
+        assert_eq!(map.get_block(2, 1, 1), 80);
+        assert_eq!(map.get_block(3, 0, 0), STONE);
+    }
+
+    #[test]
+    fn rejects_short_files() {
+        let data = lvl_file(true, &[0u8; 10], None);
+        assert!(DmfMap::from_lvl(data.as_slice()).is_err());
+
+        // a header claiming far more blocks than there are
+        let mut data = Vec::new();
+        for value in [i16::MAX as u16, i16::MAX as u16, i16::MAX as u16, 0, 0, 0] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&[0u8; 64]);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let data = encoder.finish().unwrap();
+        assert!(matches!(
+            DmfMap::from_lvl(data.as_slice()),
+            Err(Error::InvalidMap(_))
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+pub mod anvil;
+pub mod classicworld;
+pub mod dat;
+pub mod fcm;
+pub mod java;
+pub mod lvl;
+pub mod nbt;
+pub mod palette;
+pub mod schematic;
+
+use crate::server::error::{Error, Result};
+use crate::server::files::write_atomically;
+use crate::server::game::dmf_map::DmfMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+// the last block clients know without block definitions
+pub const LAST_CPE_BLOCK: u8 = 65;
+// what imported blocks we have nothing for turn into
+pub(super) const STONE: u8 = 0x01;
+
+// the file formats maps can be kept in, picked by extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapFormat {
+    Dmf,
+    ClassicWorld,
+    // MCGalaxy
+    Lvl,
+    // the original Minecraft Classic, .dat or .mine
+    Dat,
+    // fCraft and its forks
+    Fcm,
+}
+
+impl MapFormat {
+    pub fn of(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "dmf" => Some(Self::Dmf),
+            "cw" => Some(Self::ClassicWorld),
+            "lvl" => Some(Self::Lvl),
+            "dat" | "mine" => Some(Self::Dat),
+            "fcm" => Some(Self::Fcm),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Dmf => "dmf",
+            Self::ClassicWorld => "cw",
+            Self::Lvl => "lvl",
+            Self::Dat => "dat",
+            Self::Fcm => "fcm",
+        }
+    }
+
+    // formats we only import, maps from them are saved as dmf instead
+    pub fn can_save(self) -> bool {
+        !matches!(self, Self::Lvl | Self::Dat | Self::Fcm)
+    }
+}
+
+pub fn load(path: &Path) -> Result<DmfMap> {
+    match format_of(path)? {
+        MapFormat::Dmf => DmfMap::load_file(&path.to_string_lossy()),
+        MapFormat::ClassicWorld => DmfMap::from_classicworld(BufReader::new(File::open(path)?)),
+        MapFormat::Lvl => DmfMap::from_lvl(BufReader::new(File::open(path)?)),
+        MapFormat::Dat => DmfMap::from_classic_dat(BufReader::new(File::open(path)?)),
+        MapFormat::Fcm => DmfMap::from_fcm(BufReader::new(File::open(path)?)),
+    }
+}
+
+pub fn save(map: &DmfMap, path: &Path) -> Result<()> {
+    match format_of(path)? {
+        MapFormat::Dmf => map.save_file(&path.to_string_lossy())?,
+        MapFormat::ClassicWorld => {
+            println!("saving file to {}", path.display());
+            let data = map.to_classicworld(&map_name(path))?;
+            write_atomically(path, |file| file.write_all(&data))?;
+        }
+        format => {
+            return Err(Error::InvalidMap(format!(
+                "can't write .{} files",
+                format.extension()
+            )))
+        }
+    }
+    Ok(())
+}
+
+// loads a map and saves it again, the extensions pick the formats. the map is
+// handed back for whoever wants to say what was converted
+pub fn convert(input: &Path, output: &Path) -> Result<DmfMap> {
+    let map = load(input)?;
+    save(&map, output)?;
+    Ok(map)
+}
+
+// size of the map without keeping its blocks around
+pub fn read_dimensions(path: &Path) -> Result<(i16, i16, i16)> {
+    match format_of(path)? {
+        MapFormat::Dmf => DmfMap::read_dimensions(&path.to_string_lossy()),
+        MapFormat::ClassicWorld => classicworld::read_dimensions(BufReader::new(File::open(path)?)),
+        MapFormat::Lvl => lvl::read_dimensions(BufReader::new(File::open(path)?)),
+        MapFormat::Dat => dat::read_dimensions(BufReader::new(File::open(path)?)),
+        MapFormat::Fcm => fcm::read_dimensions(BufReader::new(File::open(path)?)),
+    }
+}
+
+pub fn map_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+// the blocks a header says are coming. the buffer grows as they are read, so a
+// header claiming a huge map can't make us allocate more than the file holds
+pub(super) fn read_blocks(reader: &mut impl Read, volume: usize) -> Result<Vec<u8>> {
+    let mut blocks = Vec::new();
+    reader.take(volume as u64).read_to_end(&mut blocks)?;
+    if blocks.len() != volume {
+        return Err(Error::InvalidMap(format!(
+            "expected {} blocks, file is too short",
+            volume
+        )));
+    }
+    Ok(blocks)
+}
+
+// a file that ends early is a broken map rather than an io problem
+pub(super) fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::InvalidMap("file ends too early".to_string()),
+        _ => Error::Io(e),
+    })
+}
+
+pub(super) fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    read_exact(reader, &mut buf)?;
+    Ok(buf)
+}
+
+fn format_of(path: &Path) -> Result<MapFormat> {
+    MapFormat::of(path)
+        .ok_or_else(|| Error::InvalidMap(format!("{} is not a known map format", path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_between_formats() {
+        let dir = std::env::temp_dir().join(format!("dandelion-convert-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut map = DmfMap::new(1, 2, 3, 4, 5, 6);
+        map.set_block(3, 4, 5, 0x31);
+        save(&map, &dir.join("in.dmf")).unwrap();
+
+        let converted = convert(&dir.join("in.dmf"), &dir.join("out.cw")).unwrap();
+        assert_eq!(converted.blocks, map.blocks);
+        let loaded = load(&dir.join("out.cw")).unwrap();
+        assert_eq!(loaded.blocks, map.blocks);
+        assert_eq!((loaded.x_spawn, loaded.y_spawn, loaded.z_spawn), (1, 2, 3));
+
+        assert!(convert(&dir.join("in.dmf"), &dir.join("out.lvl")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
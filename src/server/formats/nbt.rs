@@ -0,0 +1,452 @@
+use crate::server::error::{Error, Result};
+use std::io::{self, Read, Write};
+
+// named binary tag, the big endian tree format ClassicWorld, schematics and
+// anvil regions are stored in
+
+const TAG_END: u8 = 0;
+const TAG_BYTE: u8 = 1;
+const TAG_SHORT: u8 = 2;
+const TAG_INT: u8 = 3;
+const TAG_LONG: u8 = 4;
+const TAG_FLOAT: u8 = 5;
+const TAG_DOUBLE: u8 = 6;
+const TAG_BYTE_ARRAY: u8 = 7;
+const TAG_STRING: u8 = 8;
+const TAG_LIST: u8 = 9;
+const TAG_COMPOUND: u8 = 10;
+const TAG_INT_ARRAY: u8 = 11;
+const TAG_LONG_ARRAY: u8 = 12;
+
+// deeper than any real file nests, keeps a crafted one from blowing the stack
+const MAX_DEPTH: usize = 512;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<u8>),
+    String(String),
+    List(Vec<Tag>),
+    Compound(Compound),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+// entries keep the order they were read or added in
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Compound(Vec<(String, Tag)>);
+
+impl Tag {
+    fn id(&self) -> u8 {
+        match self {
+            Tag::Byte(_) => TAG_BYTE,
+            Tag::Short(_) => TAG_SHORT,
+            Tag::Int(_) => TAG_INT,
+            Tag::Long(_) => TAG_LONG,
+            Tag::Float(_) => TAG_FLOAT,
+            Tag::Double(_) => TAG_DOUBLE,
+            Tag::ByteArray(_) => TAG_BYTE_ARRAY,
+            Tag::String(_) => TAG_STRING,
+            Tag::List(_) => TAG_LIST,
+            Tag::Compound(_) => TAG_COMPOUND,
+            Tag::IntArray(_) => TAG_INT_ARRAY,
+            Tag::LongArray(_) => TAG_LONG_ARRAY,
+        }
+    }
+
+    // any integer type, files disagree on how wide some fields are
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Tag::Byte(value) => Some(value.into()),
+            Tag::Short(value) => Some(value.into()),
+            Tag::Int(value) => Some(value.into()),
+            Tag::Long(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl Compound {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tag> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, tag)| tag)
+    }
+
+    // replaces an existing entry with the same name
+    pub fn insert(&mut self, name: &str, tag: Tag) {
+        match self.0.iter_mut().find(|(key, _)| key == name) {
+            Some((_, existing)) => *existing = tag,
+            None => self.0.push((name.to_string(), tag)),
+        }
+    }
+
+    pub fn with(mut self, name: &str, tag: Tag) -> Self {
+        self.insert(name, tag);
+        self
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Tag)> {
+        self.0.iter().map(|(key, tag)| (key.as_str(), tag))
+    }
+
+    pub fn int(&self, name: &str) -> Option<i64> {
+        self.get(name).and_then(Tag::as_i64)
+    }
+
+    pub fn float(&self, name: &str) -> Option<f64> {
+        match self.get(name)? {
+            Tag::Float(value) => Some((*value).into()),
+            Tag::Double(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn string(&self, name: &str) -> Option<&str> {
+        match self.get(name)? {
+            Tag::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn bytes(&self, name: &str) -> Option<&[u8]> {
+        match self.get(name)? {
+            Tag::ByteArray(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn compound(&self, name: &str) -> Option<&Compound> {
+        match self.get(name)? {
+            Tag::Compound(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn list(&self, name: &str) -> Option<&[Tag]> {
+        match self.get(name)? {
+            Tag::List(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn int_array(&self, name: &str) -> Option<&[i32]> {
+        match self.get(name)? {
+            Tag::IntArray(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn long_array(&self, name: &str) -> Option<&[i64]> {
+        match self.get(name)? {
+            Tag::LongArray(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+// the root is always a named compound
+pub fn read(reader: &mut impl Read) -> Result<(String, Compound)> {
+    NbtReader {
+        reader,
+        skip_arrays: false,
+    }
+    .read_root()
+}
+
+// same as read but every array comes back empty, for when only the small
+// fields are wanted and the block data would be a waste of memory
+pub fn read_without_arrays(reader: &mut impl Read) -> Result<(String, Compound)> {
+    NbtReader {
+        reader,
+        skip_arrays: true,
+    }
+    .read_root()
+}
+
+pub fn write(writer: &mut impl Write, name: &str, root: &Compound) -> io::Result<()> {
+    writer.write_all(&[TAG_COMPOUND])?;
+    write_string(writer, name)?;
+    write_compound(writer, root)
+}
+
+struct NbtReader<'a, R> {
+    reader: &'a mut R,
+    skip_arrays: bool,
+}
+
+impl<R: Read> NbtReader<'_, R> {
+    fn read_root(&mut self) -> Result<(String, Compound)> {
+        let id = self.u8()?;
+        if id != TAG_COMPOUND {
+            return Err(Error::InvalidMap(format!(
+                "nbt root should be a compound, not tag {}",
+                id
+            )));
+        }
+        let name = self.string()?;
+        let root = self.compound(0)?;
+        Ok((name, root))
+    }
+
+    fn tag(&mut self, id: u8, depth: usize) -> Result<Tag> {
+        if depth > MAX_DEPTH {
+            return Err(Error::InvalidMap("nbt nests too deep".to_string()));
+        }
+        Ok(match id {
+            TAG_BYTE => Tag::Byte(self.u8()? as i8),
+            TAG_SHORT => Tag::Short(i16::from_be_bytes(self.array()?)),
+            TAG_INT => Tag::Int(i32::from_be_bytes(self.array()?)),
+            TAG_LONG => Tag::Long(i64::from_be_bytes(self.array()?)),
+            TAG_FLOAT => Tag::Float(f32::from_be_bytes(self.array()?)),
+            TAG_DOUBLE => Tag::Double(f64::from_be_bytes(self.array()?)),
+            TAG_BYTE_ARRAY => {
+                let length = self.length()?;
+                Tag::ByteArray(self.bytes(length)?)
+            }
+            TAG_STRING => Tag::String(self.string()?),
+            TAG_LIST => {
+                let element_id = self.u8()?;
+                let length = self.length()?;
+                if element_id == TAG_END && length > 0 {
+                    return Err(Error::InvalidMap("nbt list of end tags".to_string()));
+                }
+                let mut list = Vec::new();
+                for _ in 0..length {
+                    list.push(self.tag(element_id, depth + 1)?);
+                }
+                Tag::List(list)
+            }
+            TAG_COMPOUND => Tag::Compound(self.compound(depth + 1)?),
+            TAG_INT_ARRAY => {
+                let length = self.length()?;
+                Tag::IntArray(
+                    self.bytes(length * 4)?
+                        .chunks_exact(4)
+                        .map(|int| i32::from_be_bytes(int.try_into().unwrap()))
+                        .collect(),
+                )
+            }
+            TAG_LONG_ARRAY => {
+                let length = self.length()?;
+                Tag::LongArray(
+                    self.bytes(length * 8)?
+                        .chunks_exact(8)
+                        .map(|long| i64::from_be_bytes(long.try_into().unwrap()))
+                        .collect(),
+                )
+            }
+            other => return Err(Error::InvalidMap(format!("unknown nbt tag {}", other))),
+        })
+    }
+
+    fn compound(&mut self, depth: usize) -> Result<Compound> {
+        let mut compound = Compound::new();
+        loop {
+            let id = self.u8()?;
+            if id == TAG_END {
+                return Ok(compound);
+            }
+            let name = self.string()?;
+            let tag = self.tag(id, depth)?;
+            compound.0.push((name, tag));
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.reader.read_exact(&mut buf).map_err(cut_off)?;
+        Ok(buf)
+    }
+
+    fn length(&mut self) -> Result<usize> {
+        let length = i32::from_be_bytes(self.array()?);
+        usize::try_from(length)
+            .map_err(|_| Error::InvalidMap(format!("negative nbt length {}", length)))
+    }
+
+    // read through take so a bogus length can't make us allocate gigabytes up front
+    fn bytes(&mut self, length: usize) -> Result<Vec<u8>> {
+        let mut limited = (&mut *self.reader).take(length as u64);
+        let mut buf = Vec::new();
+        let read = if self.skip_arrays {
+            io::copy(&mut limited, &mut io::sink()).map_err(cut_off)? as usize
+        } else {
+            limited.read_to_end(&mut buf).map_err(cut_off)?
+        };
+        if read != length {
+            return Err(Error::InvalidMap("nbt data ends too early".to_string()));
+        }
+        Ok(buf)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let length = u16::from_be_bytes(self.array()?) as usize;
+        let mut buf = vec![0u8; length];
+        self.reader.read_exact(&mut buf).map_err(cut_off)?;
+        // java writes modified utf-8, which only differs for characters no
+        // map name or block name uses
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+}
+
+fn cut_off(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::InvalidMap("nbt data ends too early".to_string()),
+        _ => Error::Io(e),
+    }
+}
+
+fn write_tag(writer: &mut impl Write, tag: &Tag) -> io::Result<()> {
+    match tag {
+        Tag::Byte(value) => writer.write_all(&value.to_be_bytes()),
+        Tag::Short(value) => writer.write_all(&value.to_be_bytes()),
+        Tag::Int(value) => writer.write_all(&value.to_be_bytes()),
+        Tag::Long(value) => writer.write_all(&value.to_be_bytes()),
+        Tag::Float(value) => writer.write_all(&value.to_be_bytes()),
+        Tag::Double(value) => writer.write_all(&value.to_be_bytes()),
+        Tag::ByteArray(value) => {
+            write_length(writer, value.len())?;
+            writer.write_all(value)
+        }
+        Tag::String(value) => write_string(writer, value),
+        Tag::List(list) => {
+            let element_id = list.first().map_or(TAG_END, Tag::id);
+            if list.iter().any(|tag| tag.id() != element_id) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "nbt list mixes tag types",
+                ));
+            }
+            writer.write_all(&[element_id])?;
+            write_length(writer, list.len())?;
+            for tag in list {
+                write_tag(writer, tag)?;
+            }
+            Ok(())
+        }
+        Tag::Compound(compound) => write_compound(writer, compound),
+        Tag::IntArray(value) => {
+            write_length(writer, value.len())?;
+            for int in value {
+                writer.write_all(&int.to_be_bytes())?;
+            }
+            Ok(())
+        }
+        Tag::LongArray(value) => {
+            write_length(writer, value.len())?;
+            for long in value {
+                writer.write_all(&long.to_be_bytes())?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn write_compound(writer: &mut impl Write, compound: &Compound) -> io::Result<()> {
+    for (name, tag) in compound.iter() {
+        writer.write_all(&[tag.id()])?;
+        write_string(writer, name)?;
+        write_tag(writer, tag)?;
+    }
+    writer.write_all(&[TAG_END])
+}
+
+fn write_length(writer: &mut impl Write, length: usize) -> io::Result<()> {
+    let length = i32::try_from(length)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "nbt array too long"))?;
+    writer.write_all(&length.to_be_bytes())
+}
+
+fn write_string(writer: &mut impl Write, value: &str) -> io::Result<()> {
+    let length = u16::try_from(value.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "nbt string too long"))?;
+    writer.write_all(&length.to_be_bytes())?;
+    writer.write_all(value.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Compound {
+        Compound::new()
+            .with("Byte", Tag::Byte(-1))
+            .with("Short", Tag::Short(300))
+            .with("Long", Tag::Long(-5_000_000_000))
+            .with("Float", Tag::Float(1.5))
+            .with("Blocks", Tag::ByteArray(vec![1, 2, 3]))
+            .with(
+                "List",
+                Tag::List(vec![Tag::String("a".into()), Tag::String("b".into())]),
+            )
+            .with("Empty", Tag::List(Vec::new()))
+            .with(
+                "Nested",
+                Tag::Compound(Compound::new().with("Ints", Tag::IntArray(vec![-1, 7]))),
+            )
+            .with("Longs", Tag::LongArray(vec![i64::MIN]))
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut data = Vec::new();
+        write(&mut data, "Root", &sample()).unwrap();
+        let (name, root) = read(&mut data.as_slice()).unwrap();
+        assert_eq!(name, "Root");
+        assert_eq!(root, sample());
+        assert_eq!(root.int("Short"), Some(300));
+        assert_eq!(root.float("Float"), Some(1.5));
+        assert_eq!(
+            root.compound("Nested").unwrap().int_array("Ints"),
+            Some(&[-1, 7][..])
+        );
+    }
+
+    #[test]
+    fn can_skip_arrays() {
+        let mut data = Vec::new();
+        write(&mut data, "", &sample()).unwrap();
+        let (_, root) = read_without_arrays(&mut data.as_slice()).unwrap();
+        assert_eq!(root.bytes("Blocks"), Some(&[][..]));
+        assert_eq!(root.int("Long"), Some(-5_000_000_000));
+    }
+
+    #[test]
+    fn rejects_broken_data() {
+        let mut data = Vec::new();
+        write(&mut data, "", &sample()).unwrap();
+        data.truncate(data.len() - 3);
+        assert!(read(&mut data.as_slice()).is_err());
+
+        // a byte array claiming to be 2gb long
+        let data = [
+            TAG_COMPOUND,
+            0,
+            0,
+            TAG_BYTE_ARRAY,
+            0,
+            0,
+            0x7f,
+            0xff,
+            0xff,
+            0xff,
+        ];
+        assert!(read(&mut data.as_slice()).is_err());
+        assert!(read(&mut [TAG_BYTE, 0, 0].as_slice()).is_err());
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+
+// classic block ids and the modern block states they stand for, used when
+// blocks go to or come from java edition files. the cpe blocks past 49 and
+// some of the wool colours have no exact match and get the closest one
+const MODERN_BLOCKS: [&str; 66] = [
+    "minecraft:air",
+    "minecraft:stone",
+    "minecraft:grass_block",
+    "minecraft:dirt",
+    "minecraft:cobblestone",
+    "minecraft:oak_planks",
+    "minecraft:oak_sapling",
+    "minecraft:bedrock",
+    "minecraft:water",
+    "minecraft:water",
+    "minecraft:lava",
+    "minecraft:lava",
+    "minecraft:sand",
+    "minecraft:gravel",
+    "minecraft:gold_ore",
+    "minecraft:iron_ore",
+    "minecraft:coal_ore",
+    "minecraft:oak_log",
+    "minecraft:oak_leaves",
+    "minecraft:sponge",
+    "minecraft:glass",
+    "minecraft:red_wool",
+    "minecraft:orange_wool",
+    "minecraft:yellow_wool",
+    "minecraft:lime_wool",
+    "minecraft:green_wool",
+    "minecraft:cyan_wool",
+    "minecraft:light_blue_wool",
+    "minecraft:blue_wool",
+    "minecraft:purple_wool",
+    "minecraft:purple_wool",
+    "minecraft:magenta_wool",
+    "minecraft:magenta_wool",
+    "minecraft:pink_wool",
+    "minecraft:black_wool",
+    "minecraft:gray_wool",
+    "minecraft:white_wool",
+    "minecraft:dandelion",
+    "minecraft:poppy",
+    "minecraft:brown_mushroom",
+    "minecraft:red_mushroom",
+    "minecraft:gold_block",
+    "minecraft:iron_block",
+    "minecraft:smooth_stone_slab[type=double]",
+    "minecraft:smooth_stone_slab[type=bottom]",
+    "minecraft:bricks",
+    "minecraft:tnt",
+    "minecraft:bookshelf",
+    "minecraft:mossy_cobblestone",
+    "minecraft:obsidian",
+    "minecraft:cobblestone_slab[type=bottom]",
+    "minecraft:chain",
+    "minecraft:sandstone",
+    "minecraft:snow",
+    "minecraft:fire",
+    "minecraft:pink_concrete",
+    "minecraft:green_concrete",
+    "minecraft:brown_wool",
+    "minecraft:blue_concrete",
+    "minecraft:cyan_concrete",
+    "minecraft:ice",
+    "minecraft:chiseled_quartz_block",
+    "minecraft:magma_block",
+    "minecraft:quartz_pillar",
+    "minecraft:barrel",
+    "minecraft:stone_bricks",
+];
+
+// modern blocks that are just another name for one of ours
+const ALIASES: [(&str, u8); 2] = [("minecraft:cave_air", 0x00), ("minecraft:void_air", 0x00)];
+
+// the wool colours java edition has, most coloured blocks start with one
+const COLOURS: [&str; 16] = [
+    "white",
+    "orange",
+    "magenta",
+    "light_blue",
+    "yellow",
+    "lime",
+    "pink",
+    "gray",
+    "light_gray",
+    "cyan",
+    "purple",
+    "blue",
+    "brown",
+    "green",
+    "red",
+    "black",
+];
+
+// shapes made of a material, a stair is closest to a full block of it
+const SHAPES: [&str; 12] = [
+    "_stairs",
+    "_slab",
+    "_wall",
+    "_fence_gate",
+    "_fence",
+    "_pressure_plate",
+    "_button",
+    "_trapdoor",
+    "_door",
+    "_wall_sign",
+    "_sign",
+    "_pane",
+];
+
+// for everything else, the first of these the name contains wins
+const KEYWORDS: [(&str, u8); 33] = [
+    ("glass", 20),
+    ("leaves", 18),
+    ("log", 17),
+    ("wood", 17),
+    ("stem", 17),
+    ("hyphae", 17),
+    ("planks", 5),
+    ("bricks", 45),
+    ("cobble", 4),
+    ("sandstone", 52),
+    ("sand", 12),
+    ("gravel", 13),
+    ("dirt", 3),
+    ("mud", 3),
+    ("podzol", 3),
+    ("mycelium", 2),
+    ("gold_ore", 14),
+    ("iron_ore", 15),
+    ("coal_ore", 16),
+    ("gold", 41),
+    ("iron", 42),
+    ("ore", 1),
+    ("water", 8),
+    ("lava", 10),
+    ("ice", 60),
+    ("snow", 36),
+    ("obsidian", 49),
+    ("tulip", 38),
+    ("flower", 37),
+    ("mushroom", 39),
+    ("sapling", 6),
+    ("grass", 0),
+    ("air", 0),
+];
+
+const STONE: &str = "minecraft:stone";
+const STONE_BLOCK: u8 = 0x01;
+
+// the translation both ways, with the table above and any overrides from
+// the config on top
+#[derive(Debug, Clone)]
+pub struct Palette {
+    to_modern: Vec<String>,
+    to_classic: HashMap<String, u8>,
+}
+
+impl Palette {
+    pub fn new(overrides: &HashMap<u8, String>) -> Self {
+        let mut overrides: Vec<(u8, &String)> =
+            overrides.iter().map(|(&id, state)| (id, state)).collect();
+        overrides.sort_unstable();
+
+        let mut to_modern: Vec<String> = MODERN_BLOCKS
+            .iter()
+            .map(|state| state.to_string())
+            .collect();
+        for &(id, state) in &overrides {
+            if to_modern.len() <= id as usize {
+                to_modern.resize(id as usize + 1, STONE.to_string());
+            }
+            to_modern[id as usize] = state.clone();
+        }
+
+        // overrides win, then the lowest id wins where two share a state
+        let mut to_classic = HashMap::new();
+        for &(id, state) in &overrides {
+            to_classic.entry(state.clone()).or_insert(id);
+        }
+        for (id, state) in to_modern.iter().enumerate() {
+            to_classic.entry(state.clone()).or_insert(id as u8);
+        }
+        for (state, id) in ALIASES {
+            to_classic.entry(state.to_string()).or_insert(id);
+        }
+        Self {
+            to_modern,
+            to_classic,
+        }
+    }
+
+    // modern block -> classic id entries that win over the table, a name
+    // without properties stands for all of its states
+    pub fn with_mapping(mut self, mapping: &HashMap<String, u8>) -> Self {
+        for (state, &block) in mapping {
+            self.to_classic.insert(namespaced(state), block);
+        }
+        self
+    }
+
+    // blocks we don't know a modern name for are written as stone
+    pub fn modern(&self, block: u8) -> &str {
+        self.to_modern
+            .get(block as usize)
+            .map_or(STONE, String::as_str)
+    }
+
+    // the exact state if it is known, otherwise just the block without its
+    // properties, so a lit furnace and an unlit one are the same to us
+    pub fn classic(&self, state: &str) -> Option<u8> {
+        if let Some(&block) = self.to_classic.get(state) {
+            return Some(block);
+        }
+        let name = namespaced(state.split('[').next().unwrap_or(state));
+        self.to_classic.get(&name).copied().or_else(|| {
+            self.to_classic
+                .iter()
+                .filter(|(known, _)| known.split('[').next() == Some(name.as_str()))
+                .map(|(_, &block)| block)
+                .min()
+        })
+    }
+
+    // like classic, but blocks it doesn't know are guessed from their name:
+    // shapes become their material, coloured blocks the wool of that colour
+    // and the rest goes by keyword, with stone when nothing fits
+    pub fn nearest(&self, state: &str) -> u8 {
+        if let Some(block) = self.classic(state) {
+            return block;
+        }
+        let name = state.split('[').next().unwrap_or(state);
+        let name = name.rsplit(':').next().unwrap_or(name);
+
+        if let Some(material) = SHAPES.iter().find_map(|shape| name.strip_suffix(shape)) {
+            let material = material.strip_suffix("_stained_glass").unwrap_or(material);
+            for candidate in [
+                material.to_string(),
+                format!("{}s", material),
+                format!("{}_planks", material),
+                format!("{}_block", material),
+            ] {
+                if let Some(block) = self.classic(&candidate) {
+                    return block;
+                }
+            }
+        }
+        if !name.contains("glass") {
+            let colour = COLOURS
+                .iter()
+                .filter(|colour| name.starts_with(&format!("{}_", colour)))
+                .max_by_key(|colour| colour.len());
+            if let Some(block) = colour.and_then(|colour| self.classic(&format!("{}_wool", colour)))
+            {
+                return block;
+            }
+        }
+        KEYWORDS
+            .iter()
+            .find(|(keyword, _)| name.contains(keyword))
+            .map_or(STONE_BLOCK, |&(_, block)| block)
+    }
+}
+
+fn namespaced(state: &str) -> String {
+    if state.contains(':') {
+        state.to_string()
+    } else {
+        format!("minecraft:{}", state)
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::new(&HashMap::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_both_ways() {
+        let palette = Palette::default();
+        assert_eq!(palette.modern(2), "minecraft:grass_block");
+        assert_eq!(palette.modern(200), STONE);
+        assert_eq!(palette.classic("minecraft:water"), Some(8));
+        assert_eq!(palette.classic("minecraft:water[level=3]"), Some(8));
+        assert_eq!(palette.classic("oak_log[axis=x]"), Some(17));
+        assert_eq!(
+            palette.classic("minecraft:smooth_stone_slab[type=top]"),
+            Some(43)
+        );
+        assert_eq!(palette.classic("minecraft:cave_air"), Some(0));
+        assert_eq!(palette.classic("minecraft:beacon"), None);
+    }
+
+    #[test]
+    fn overrides_take_precedence() {
+        let overrides = HashMap::from([
+            (9, "minecraft:blue_ice".to_string()),
+            (100, "minecraft:beacon".to_string()),
+        ]);
+        let palette = Palette::new(&overrides);
+        assert_eq!(palette.modern(9), "minecraft:blue_ice");
+        assert_eq!(palette.modern(100), "minecraft:beacon");
+        assert_eq!(palette.modern(99), STONE);
+        assert_eq!(palette.classic("minecraft:blue_ice"), Some(9));
+        assert_eq!(palette.classic("minecraft:beacon"), Some(100));
+        assert_eq!(palette.classic("minecraft:water"), Some(8));
+
+        let mapping = HashMap::from([
+            ("oak_stairs".to_string(), 44),
+            ("minecraft:water[level=0]".to_string(), 9),
+        ]);
+        let palette = Palette::default().with_mapping(&mapping);
+        assert_eq!(
+            palette.classic("minecraft:oak_stairs[facing=east]"),
+            Some(44)
+        );
+        assert_eq!(palette.classic("minecraft:water[level=0]"), Some(9));
+        assert_eq!(palette.classic("minecraft:water[level=1]"), Some(8));
+    }
+
+    #[test]
+    fn guesses_the_nearest_block() {
+        let palette = Palette::default();
+        let nearest = |state| palette.nearest(state);
+        assert_eq!(nearest("minecraft:grass_block[snowy=false]"), 2);
+        assert_eq!(nearest("minecraft:oak_stairs[facing=north]"), 5);
+        assert_eq!(nearest("minecraft:stone_brick_wall"), 65);
+        assert_eq!(nearest("minecraft:cobblestone_slab[type=top]"), 50);
+        assert_eq!(nearest("minecraft:light_blue_concrete"), 27);
+        assert_eq!(nearest("minecraft:red_terracotta"), 21);
+        assert_eq!(nearest("minecraft:red_stained_glass_pane"), 20);
+        assert_eq!(nearest("minecraft:spruce_log[axis=y]"), 17);
+        assert_eq!(nearest("minecraft:deepslate_iron_ore"), 15);
+        assert_eq!(nearest("minecraft:raw_iron_block"), 42);
+        assert_eq!(nearest("minecraft:short_grass"), 0);
+        assert_eq!(nearest("minecraft:end_rod"), 1);
+    }
+}
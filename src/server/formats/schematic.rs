@@ -0,0 +1,338 @@
+use crate::server::error::{Error, Result};
+use crate::server::formats::nbt::{self, Compound, Tag};
+use crate::server::formats::palette::Palette;
+use crate::server::game::dmf_map::DmfMap;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::collections::HashMap;
+use std::io::{self, Read};
+
+// sponge schematics, the .schem files worldedit and most java edition tools
+// share builds in. versions 1 to 3 are read, version 2 is written since every
+// tool that knows 3 also knows 2
+
+const WRITTEN_VERSION: i32 = 2;
+// 1.20.1, any release that has every block of the palette works
+const DATA_VERSION: i32 = 3465;
+
+// a box of blocks cut out of a map
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schematic {
+    pub width: i16,
+    pub height: i16,
+    pub length: i16,
+    // y, then z, then x, like maps
+    pub blocks: Vec<u8>,
+    // from the position it was copied at to its lowest corner, worldedit
+    // pastes it the same distance away from the player
+    pub offset: (i32, i32, i32),
+}
+
+impl Schematic {
+    // the corners can be given in any order, the box is cut to the map.
+    // origin is where the copy was made from, usually the player's feet
+    pub fn copy(
+        map: &DmfMap,
+        from: (i16, i16, i16),
+        to: (i16, i16, i16),
+        origin: (i16, i16, i16),
+    ) -> Result<Self> {
+        let clamp = |a: i16, b: i16, size: i16| (a.min(b).max(0), a.max(b).min(size - 1));
+        let (x_min, x_max) = clamp(from.0, to.0, map.x_size);
+        let (y_min, y_max) = clamp(from.1, to.1, map.y_size);
+        let (z_min, z_max) = clamp(from.2, to.2, map.z_size);
+        if x_min > x_max || y_min > y_max || z_min > z_max {
+            return Err(Error::InvalidMap(
+                "the selection is outside the map".to_string(),
+            ));
+        }
+
+        let mut blocks = Vec::new();
+        for y in y_min..=y_max {
+            for z in z_min..=z_max {
+                for x in x_min..=x_max {
+                    blocks.push(map.get_block(x, y, z));
+                }
+            }
+        }
+        Ok(Self {
+            width: x_max - x_min + 1,
+            height: y_max - y_min + 1,
+            length: z_max - z_min + 1,
+            blocks,
+            offset: (
+                i32::from(x_min) - i32::from(origin.0),
+                i32::from(y_min) - i32::from(origin.1),
+                i32::from(z_min) - i32::from(origin.2),
+            ),
+        })
+    }
+
+    // puts the lowest corner at `at`, whatever sticks out of the map is left
+    // out. returns the blocks that changed
+    pub fn paste(&self, map: &mut DmfMap, at: (i32, i32, i32)) -> Vec<(i16, i16, i16, u8)> {
+        let mut changed = Vec::new();
+        let mut blocks = self.blocks.iter();
+        for y in 0..self.height as i32 {
+            for z in 0..self.length as i32 {
+                for x in 0..self.width as i32 {
+                    let Some(&block) = blocks.next() else {
+                        return changed;
+                    };
+                    let position = (
+                        i16::try_from(at.0 + x),
+                        i16::try_from(at.1 + y),
+                        i16::try_from(at.2 + z),
+                    );
+                    let (Ok(x), Ok(y), Ok(z)) = position else {
+                        continue;
+                    };
+                    if map.contains(x, y, z) && map.get_block(x, y, z) != block {
+                        map.set_block(x, y, z, block);
+                        changed.push((x, y, z, block));
+                    }
+                }
+            }
+        }
+        changed
+    }
+
+    pub fn read(data: impl Read, palette: &Palette) -> Result<Self> {
+        let (_, root) = nbt::read(&mut GzDecoder::new(data))?;
+        // version 3 wraps everything in a compound of its own
+        let schematic = root.compound("Schematic").unwrap_or(&root);
+        let version = schematic.int("Version").unwrap_or(1);
+        let (states, data) = match version {
+            1 | 2 => (schematic.compound("Palette"), schematic.bytes("BlockData")),
+            3 => {
+                let blocks = schematic.compound("Blocks");
+                (
+                    blocks.and_then(|blocks| blocks.compound("Palette")),
+                    blocks.and_then(|blocks| blocks.bytes("Data")),
+                )
+            }
+            other => {
+                return Err(Error::InvalidMap(format!(
+                    "unsupported schematic version {}",
+                    other
+                )))
+            }
+        };
+        let (Some(states), Some(data)) = (states, data) else {
+            return Err(Error::InvalidMap(
+                "schematic has no palette or blocks".to_string(),
+            ));
+        };
+
+        // sizes are unsigned shorts, but a schematic bigger than a map is no use
+        let size = |name: &str| {
+            schematic
+                .int(name)
+                .and_then(|size| i16::try_from(size as u16).ok())
+                .filter(|size| *size > 0)
+                .ok_or_else(|| Error::InvalidMap(format!("bad schematic {}", name)))
+        };
+        let (width, height, length) = (size("Width")?, size("Height")?, size("Length")?);
+
+        let mut lookup = HashMap::new();
+        for (state, index) in states.iter() {
+            if let Some(index) = index.as_i64() {
+                lookup.insert(index, palette.nearest(state));
+            }
+        }
+        let volume = width as usize * height as usize * length as usize;
+        // every block takes at least a byte, so a made up size can't make us
+        // allocate more than the file had
+        let mut blocks = Vec::with_capacity(volume.min(data.len()));
+        let mut data = data;
+        while blocks.len() < volume {
+            let index = read_varint(&mut data)?;
+            let block = lookup.get(&index.into()).ok_or_else(|| {
+                Error::InvalidMap(format!(
+                    "schematic uses palette entry {} it doesn't have",
+                    index
+                ))
+            })?;
+            blocks.push(*block);
+        }
+
+        let offset = schematic
+            .compound("Metadata")
+            .map_or((0, 0, 0), |metadata| {
+                let offset = |name: &str| metadata.int(name).unwrap_or(0) as i32;
+                (
+                    offset("WEOffsetX"),
+                    offset("WEOffsetY"),
+                    offset("WEOffsetZ"),
+                )
+            });
+        Ok(Self {
+            width,
+            height,
+            length,
+            blocks,
+            offset,
+        })
+    }
+
+    pub fn write(&self, palette: &Palette) -> io::Result<Vec<u8>> {
+        let mut states = Compound::new();
+        let mut indices: HashMap<&str, i32> = HashMap::new();
+        let mut data = Vec::with_capacity(self.blocks.len());
+        for &block in &self.blocks {
+            let state = palette.modern(block);
+            let next = indices.len() as i32;
+            let index = *indices.entry(state).or_insert_with(|| {
+                states.insert(state, Tag::Int(next));
+                next
+            });
+            write_varint(&mut data, index);
+        }
+
+        let (x, y, z) = self.offset;
+        let metadata = Compound::new()
+            .with("WEOffsetX", Tag::Int(x))
+            .with("WEOffsetY", Tag::Int(y))
+            .with("WEOffsetZ", Tag::Int(z));
+        let root = Compound::new()
+            .with("Version", Tag::Int(WRITTEN_VERSION))
+            .with("DataVersion", Tag::Int(DATA_VERSION))
+            .with("Width", Tag::Short(self.width))
+            .with("Height", Tag::Short(self.height))
+            .with("Length", Tag::Short(self.length))
+            .with("Offset", Tag::IntArray(vec![0, 0, 0]))
+            .with("Metadata", Tag::Compound(metadata))
+            .with("PaletteMax", Tag::Int(indices.len() as i32))
+            .with("Palette", Tag::Compound(states))
+            .with("BlockData", Tag::ByteArray(data));
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        nbt::write(&mut encoder, "Schematic", &root)?;
+        encoder.finish()
+    }
+}
+
+// 7 bits at a time, lowest first, the top bit says another byte follows
+fn read_varint(data: &mut &[u8]) -> Result<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let Some((&byte, rest)) = data.split_first() else {
+            return Err(Error::InvalidMap(
+                "schematic has fewer blocks than its size".to_string(),
+            ));
+        };
+        *data = rest;
+        value |= u32::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::InvalidMap(
+        "schematic varint is too long".to_string(),
+    ))
+}
+
+fn write_varint(out: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    while value >= 0x80 {
+        out.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_map() -> DmfMap {
+        let mut map = DmfMap::new(0, 0, 0, 8, 8, 8);
+        map.set_block(1, 1, 1, 2);
+        map.set_block(2, 1, 1, 8);
+        map.set_block(2, 2, 3, 49);
+        map
+    }
+
+    #[test]
+    fn copies_and_pastes() {
+        let map = sample_map();
+        let schematic = Schematic::copy(&map, (2, 2, 3), (1, 1, 1), (0, 1, 4)).unwrap();
+        assert_eq!(
+            (schematic.width, schematic.height, schematic.length),
+            (2, 2, 3)
+        );
+        assert_eq!(schematic.offset, (1, 0, -3));
+
+        let mut other = DmfMap::new(0, 0, 0, 8, 8, 8);
+        // the last column sticks out of the map
+        assert_eq!(
+            schematic.paste(&mut other, (6, 0, 6)),
+            vec![(6, 0, 6, 2), (7, 0, 6, 8)]
+        );
+        assert_eq!(other.get_block(6, 0, 6), 2);
+        assert_eq!(other.get_block(7, 0, 6), 8);
+
+        // cut down to the map
+        let all = Schematic::copy(&map, (-5, 0, 0), (100, 0, 0), (0, 0, 0)).unwrap();
+        assert_eq!(all.width, 8);
+        assert!(Schematic::copy(&map, (9, 0, 0), (12, 0, 0), (0, 0, 0)).is_err());
+    }
+
+    #[test]
+    fn round_trips() {
+        let palette = Palette::default();
+        let schematic = Schematic::copy(&sample_map(), (0, 0, 0), (7, 7, 7), (1, 0, 0)).unwrap();
+        let data = schematic.write(&palette).unwrap();
+        assert_eq!(
+            Schematic::read(data.as_slice(), &palette).unwrap(),
+            schematic
+        );
+    }
+
+    #[test]
+    fn reads_version_3() {
+        let blocks = Compound::new()
+            .with(
+                "Palette",
+                Tag::Compound(
+                    Compound::new()
+                        .with("minecraft:air", Tag::Int(0))
+                        .with("minecraft:oak_log[axis=z]", Tag::Int(200))
+                        .with("minecraft:beacon", Tag::Int(2)),
+                ),
+            )
+            // 200 takes two bytes
+            .with("Data", Tag::ByteArray(vec![0, 0xC8, 0x01, 2]));
+        let schematic = Compound::new()
+            .with("Version", Tag::Int(3))
+            .with("Width", Tag::Short(3))
+            .with("Height", Tag::Short(1))
+            .with("Length", Tag::Short(1))
+            .with("Blocks", Tag::Compound(blocks));
+        let root = Compound::new().with("Schematic", Tag::Compound(schematic));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        nbt::write(&mut encoder, "", &root).unwrap();
+        let data = encoder.finish().unwrap();
+
+        let schematic = Schematic::read(data.as_slice(), &Palette::default()).unwrap();
+        // nothing about a beacon is close to a classic block
+        assert_eq!(schematic.blocks, vec![0, 17, 1]);
+    }
+
+    #[test]
+    fn rejects_missing_blocks() {
+        let root = Compound::new()
+            .with("Version", Tag::Int(2))
+            .with("Width", Tag::Short(2))
+            .with("Height", Tag::Short(1))
+            .with("Length", Tag::Short(1))
+            .with(
+                "Palette",
+                Tag::Compound(Compound::new().with("minecraft:air", Tag::Int(0))),
+            )
+            .with("BlockData", Tag::ByteArray(vec![0]));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        nbt::write(&mut encoder, "Schematic", &root).unwrap();
+        let data = encoder.finish().unwrap();
+        assert!(Schematic::read(data.as_slice(), &Palette::default()).is_err());
+    }
+}
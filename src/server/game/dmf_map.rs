@@ -1,10 +1,26 @@
 use std::{
-    fs::File,
-    io::{self, ErrorKind, Read, Write},
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::Path,
 };
 
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression, Crc};
+
+use crate::server::error::{Error, Result};
+use crate::server::files::write_atomically;
+use crate::server::formats::{read_exact, LAST_CPE_BLOCK};
+use crate::server::game::map_metadata::MapMetadata;
+use crate::server::game::movement::EntityPosition;
+
 const HEADER_INDENTIFIER: &str = "DANDELION MAP FORMAT";
-const HEADER_VERSION: u8 = 0x00;
+// identifier, version, spawn and size
+const HEADER_SIZE: usize = 20 + 1 + 6 + 6;
+// version 0 is the header followed by the raw blocks. version 1 adds the
+// metadata, compresses the blocks and ends in a crc32 of everything before it
+const HEADER_VERSION: u8 = 0x01;
+const VERSION_RAW: u8 = 0x00;
+// deflate can't shrink data to less than about a thousandth of its size
+const MAX_DEFLATE_RATIO: u64 = 1032;
 
 #[derive(Debug, Clone)]
 pub struct DmfMap {
@@ -17,6 +33,11 @@ pub struct DmfMap {
     pub z_size: i16,
 
     pub blocks: Vec<u8>,
+
+    pub metadata: MapMetadata,
+
+    // changed since it was last loaded or saved
+    dirty: bool,
 }
 
 impl DmfMap {
@@ -28,8 +49,8 @@ impl DmfMap {
         y_size: i16,
         z_size: i16,
     ) -> Self {
-        let total_blocks = (x_size as usize * y_size as usize * z_size as usize);
-        return Self {
+        let total_blocks = x_size as usize * y_size as usize * z_size as usize;
+        Self {
             x_spawn,
             y_spawn,
             z_spawn,
@@ -39,10 +60,15 @@ impl DmfMap {
             z_size,
 
             blocks: vec![0x00; total_blocks],
-        };
+
+            metadata: MapMetadata::default(),
+
+            // a new map isn't on disk yet
+            dirty: true,
+        }
     }
     pub fn get_block(&self, x: i16, y: i16, z: i16) -> u8 {
-        if x < self.x_size && y < self.y_size && z < self.z_size {
+        if self.contains(x, y, z) {
             let index = (y as usize * self.z_size as usize * self.x_size as usize)
                 + (z as usize * self.x_size as usize)
                 + x as usize;
@@ -56,11 +82,14 @@ impl DmfMap {
         }
     }
     pub fn set_block(&mut self, x: i16, y: i16, z: i16, block: u8) {
-        if x < self.x_size && y < self.y_size && z < self.z_size {
+        if self.contains(x, y, z) {
             let index = (y as usize * self.z_size as usize * self.x_size as usize)
                 + (z as usize * self.x_size as usize)
                 + x as usize;
-            self.blocks[index] = block;
+            if self.blocks[index] != block {
+                self.blocks[index] = block;
+                self.dirty = true;
+            }
         } else {
             println!(
                 "Error: attempted to place block outside world ({}, {}, {})",
@@ -68,91 +97,480 @@ impl DmfMap {
             );
         }
     }
+    pub fn contains(&self, x: i16, y: i16, z: i16) -> bool {
+        (0..self.x_size).contains(&x)
+            && (0..self.y_size).contains(&y)
+            && (0..self.z_size).contains(&z)
+    }
+    // spawn is stored in blocks, players are placed in the middle of the
+    // block with their eyes (51/32 above their feet) at the right height
+    pub fn spawn_position(&self) -> EntityPosition {
+        EntityPosition::new(
+            self.x_spawn.saturating_mul(32).saturating_add(16),
+            self.y_spawn.saturating_mul(32).saturating_add(51),
+            self.z_spawn.saturating_mul(32).saturating_add(16),
+            self.metadata.spawn_yaw,
+            self.metadata.spawn_pitch,
+        )
+    }
+    // on top of the middle column, for maps that don't say where to spawn
+    pub fn ground_spawn(&self) -> (i16, i16, i16) {
+        let (x, z) = (self.x_size / 2, self.z_size / 2);
+        let ground = (0..self.y_size)
+            .rev()
+            .find(|&y| self.get_block(x, y, z) != 0x00)
+            .map_or(0, |y| y + 1);
+        (x, ground.min(self.y_size - 1), z)
+    }
     pub fn set_spawn_point(&mut self, x: i16, y: i16, z: i16) {
         self.x_spawn = x;
         self.y_spawn = y;
         self.z_spawn = z;
+        self.dirty = true;
+    }
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+    // a box of `size` blocks starting at `from`, which can reach past the map
+    // where it is filled with air. the spawn moves with the blocks and is
+    // kept inside the new map
+    pub fn cropped(&self, from: (i16, i16, i16), size: (i16, i16, i16)) -> Self {
+        let (x_size, y_size, z_size) = size;
+        let mut map = Self::new(0, 0, 0, x_size, y_size, z_size);
+        for y in 0..y_size {
+            for z in 0..z_size {
+                for x in 0..x_size {
+                    let (Some(old_x), Some(old_y), Some(old_z)) = (
+                        x.checked_add(from.0),
+                        y.checked_add(from.1),
+                        z.checked_add(from.2),
+                    ) else {
+                        continue;
+                    };
+                    if self.contains(old_x, old_y, old_z) {
+                        map.set_block(x, y, z, self.get_block(old_x, old_y, old_z));
+                    }
+                }
+            }
+        }
+        let spawn =
+            |spawn: i16, from: i16, size: i16| spawn.saturating_sub(from).clamp(0, size - 1);
+        map.x_spawn = spawn(self.x_spawn, from.0, x_size);
+        map.y_spawn = spawn(self.y_spawn, from.1, y_size);
+        map.z_spawn = spawn(self.z_spawn, from.2, z_size);
+        map.metadata = self.metadata.clone();
+        map
+    }
+    // how many of each block the map has, by id
+    pub fn block_counts(&self) -> [usize; 256] {
+        let mut counts = [0; 256];
+        for &block in &self.blocks {
+            counts[block as usize] += 1;
+        }
+        counts
+    }
+    // things that load fine but would confuse clients
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !self.contains(self.x_spawn, self.y_spawn, self.z_spawn) {
+            problems.push(format!(
+                "spawn {}, {}, {} is outside the map",
+                self.x_spawn, self.y_spawn, self.z_spawn
+            ));
+        }
+        for (block, count) in self.block_counts().iter().enumerate() {
+            let block = block as u8;
+            let defined = self
+                .metadata
+                .block_definitions
+                .iter()
+                .any(|definition| definition.id == block);
+            if *count > 0 && block > LAST_CPE_BLOCK && !defined {
+                problems.push(format!(
+                    "{} blocks of {}, which has no block definition",
+                    count, block
+                ));
+            }
+        }
+        problems
     }
     pub fn save_file(&self, path: &str) -> io::Result<()> {
-        let mut file = File::create(path)?;
-        print!("saving file to {}", path);
+        println!("saving file to {}", path);
+        let data = self.to_bytes()?;
+        write_atomically(Path::new(path), |file| file.write_all(&data))
+    }
+
+    // always the current version, older maps get upgraded the first time
+    // they are saved
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(HEADER_SIZE + self.blocks.len() / 8);
+        data.extend_from_slice(HEADER_INDENTIFIER.as_bytes());
+        data.push(HEADER_VERSION);
+
+        data.extend_from_slice(&self.x_spawn.to_le_bytes());
+        data.extend_from_slice(&self.y_spawn.to_le_bytes());
+        data.extend_from_slice(&self.z_spawn.to_le_bytes());
 
-        file.write_all(HEADER_INDENTIFIER.as_bytes())?;
-        file.write_all(&[HEADER_VERSION])?;
+        data.extend_from_slice(&self.x_size.to_le_bytes());
+        data.extend_from_slice(&self.y_size.to_le_bytes());
+        data.extend_from_slice(&self.z_size.to_le_bytes());
 
-        file.write_all(&self.x_spawn.to_le_bytes())?;
-        file.write_all(&self.y_spawn.to_le_bytes())?;
-        file.write_all(&self.z_spawn.to_le_bytes())?;
+        let metadata = self.metadata.encode();
+        data.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+        data.extend_from_slice(&metadata);
 
-        file.write_all(&self.x_size.to_le_bytes())?;
-        file.write_all(&self.y_size.to_le_bytes())?;
-        file.write_all(&self.z_size.to_le_bytes())?;
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.blocks)?;
+        let payload = encoder.finish()?;
+        data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        data.extend_from_slice(&payload);
 
-        file.write_all(&self.blocks)?;
-        file.flush();
+        let mut crc = Crc::new();
+        crc.update(&data);
+        data.extend_from_slice(&crc.sum().to_le_bytes());
+        Ok(data)
+    }
 
-        return Ok(());
+    pub fn load_file(path: &str) -> Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
     }
 
-    pub fn load_file(path: &str) -> io::Result<Self> {
-        let mut file = File::open(&path)?;
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let mut reader = data;
+        let header = read_header(&mut reader)?;
+        header.check_fits(data.len() as u64)?;
+        let total_blocks = header.volume();
 
-        let mut indentifier = [0u8; 20];
-        file.read_exact(&mut indentifier)?;
-        if &indentifier != HEADER_INDENTIFIER.as_bytes() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid header identifier",
-            ));
+        if header.version == VERSION_RAW {
+            // check_fits made sure the blocks are all there
+            let mut map = header.into_map(reader[..total_blocks].to_vec());
+            // so the next save writes it in the current version
+            map.dirty = true;
+            return Ok(map);
         }
 
-        let mut version = [0u8; 1];
-        file.read_exact(&mut version)?;
-        if version[0] != HEADER_VERSION {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Unsupported version",
-            ));
+        // the header and the crc at least, whatever the crc says
+        if data.len() < HEADER_SIZE + 4 {
+            return Err(Error::InvalidMap("file ends too early".to_string()));
+        }
+        let (body, checksum) = data.split_at(data.len() - 4);
+        let mut crc = Crc::new();
+        crc.update(body);
+        if crc.sum().to_le_bytes() != checksum {
+            return Err(Error::InvalidMap("checksum does not match".to_string()));
         }
 
-        let mut x_spawn = [0u8; 2];
-        file.read_exact(&mut x_spawn)?;
-        let x_spawn = i16::from_le_bytes(x_spawn);
+        let mut reader = &body[HEADER_SIZE..];
+        let metadata = MapMetadata::decode(read_section(&mut reader)?)?;
 
-        let mut y_spawn = [0u8; 2];
-        file.read_exact(&mut y_spawn)?;
-        let y_spawn = i16::from_le_bytes(y_spawn);
+        let payload = read_section(&mut reader)?;
+        // never inflate more than the map can hold, whatever the payload says
+        let mut blocks = Vec::new();
+        ZlibDecoder::new(payload)
+            .take(total_blocks as u64 + 1)
+            .read_to_end(&mut blocks)
+            .map_err(|e| Error::InvalidMap(format!("block data is corrupt: {}", e)))?;
+        if blocks.len() != total_blocks {
+            return Err(Error::InvalidMap(format!(
+                "expected {} blocks, found {}",
+                total_blocks,
+                blocks.len()
+            )));
+        }
+        let mut map = header.into_map(blocks);
+        map.metadata = metadata;
+        map.dirty = false;
 
-        let mut z_spawn = [0u8; 2];
-        file.read_exact(&mut z_spawn)?;
-        let z_spawn = i16::from_le_bytes(z_spawn);
+        Ok(map)
+    }
 
-        let mut x_size = [0u8; 2];
-        file.read_exact(&mut x_size)?;
-        let x_size = i16::from_le_bytes(x_size);
+    // size of the map without reading its blocks
+    pub fn read_dimensions(path: &str) -> Result<(i16, i16, i16)> {
+        let mut file = File::open(path)?;
+        let header = read_header(&mut file)?;
+        header.check_fits(file.metadata()?.len())?;
+        Ok(header.size)
+    }
+}
 
-        let mut y_size = [0u8; 2];
-        file.read_exact(&mut y_size)?;
-        let y_size = i16::from_le_bytes(y_size);
+// the part every version shares
+struct Header {
+    version: u8,
+    spawn: (i16, i16, i16),
+    size: (i16, i16, i16),
+}
 
-        let mut z_size = [0u8; 2];
-        file.read_exact(&mut z_size)?;
-        let z_size = i16::from_le_bytes(z_size);
+impl Header {
+    fn volume(&self) -> usize {
+        let (x, y, z) = self.size;
+        x as usize * y as usize * z as usize
+    }
 
-        let total_blocks = (x_size as usize * y_size as usize * z_size as usize);
-        let mut blocks = vec![0u8; total_blocks];
-        file.read_exact(&mut blocks)?;
+    // a header can claim anything, the file has to be big enough to hold
+    // that many blocks before any room is made for them
+    fn check_fits(&self, file_length: u64) -> Result<()> {
+        let volume = self.volume() as u64;
+        let data = file_length.saturating_sub(HEADER_SIZE as u64);
+        let fits = if self.version == VERSION_RAW {
+            volume <= data
+        } else {
+            volume <= data.saturating_mul(MAX_DEFLATE_RATIO)
+        };
+        if !fits {
+            let (x, y, z) = self.size;
+            return Err(Error::InvalidMap(format!(
+                "{}x{}x{} blocks don't fit in a file of {} bytes",
+                x, y, z, file_length
+            )));
+        }
+        Ok(())
+    }
 
-        return Ok(Self {
+    fn into_map(self, blocks: Vec<u8>) -> DmfMap {
+        let (x_spawn, y_spawn, z_spawn) = self.spawn;
+        let (x_size, y_size, z_size) = self.size;
+        DmfMap {
             x_spawn,
             y_spawn,
             z_spawn,
-
             x_size,
             y_size,
             z_size,
-
             blocks,
+            metadata: MapMetadata::default(),
+            dirty: true,
+        }
+    }
+}
+
+fn read_header(file: &mut impl Read) -> Result<Header> {
+    let mut indentifier = [0u8; 20];
+    read_exact(file, &mut indentifier)?;
+    if indentifier != HEADER_INDENTIFIER.as_bytes() {
+        return Err(Error::InvalidMap("invalid header identifier".to_string()));
+    }
+
+    let mut version = [0u8; 1];
+    read_exact(file, &mut version)?;
+    if version[0] > HEADER_VERSION {
+        return Err(Error::InvalidMap(format!(
+            "unsupported version {}",
+            version[0]
+        )));
+    }
+
+    // spawn then size, x, y and z
+    let mut fields = [0u8; 12];
+    read_exact(file, &mut fields)?;
+    let i16_at = |at: usize| i16::from_le_bytes([fields[at], fields[at + 1]]);
+    let header = Header {
+        version: version[0],
+        spawn: (i16_at(0), i16_at(2), i16_at(4)),
+        size: (i16_at(6), i16_at(8), i16_at(10)),
+    };
+
+    let (x_size, y_size, z_size) = header.size;
+    if x_size <= 0 || y_size <= 0 || z_size <= 0 {
+        return Err(Error::InvalidMap(format!(
+            "bad dimensions {}x{}x{}",
+            x_size, y_size, z_size
+        )));
+    }
+    Ok(header)
+}
+
+// a u32 length followed by that many bytes
+fn read_section<'a>(reader: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let mut length = [0u8; 4];
+    read_exact(reader, &mut length)?;
+    let length = u32::from_le_bytes(length) as usize;
+    if reader.len() < length {
+        return Err(Error::InvalidMap("file ends too early".to_string()));
+    }
+    let (section, rest) = reader.split_at(length);
+    *reader = rest;
+    Ok(section)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::game::map_metadata::BlockDefinition;
+
+    fn sample_map() -> DmfMap {
+        let mut map = DmfMap::new(2, 3, 4, 16, 8, 12);
+        map.set_block(1, 2, 3, 0x01);
+        map.set_block(15, 7, 11, 0x31);
+        map.metadata.spawn_yaw = 128;
+        map.metadata.author = Some("flafmg".to_string());
+        map
+    }
+
+    #[test]
+    fn round_trips_blocks_and_metadata() {
+        let map = sample_map();
+        let loaded = DmfMap::from_bytes(&map.to_bytes().unwrap()).unwrap();
+        assert_eq!(loaded.blocks, map.blocks);
+        assert_eq!(loaded.metadata, map.metadata);
+        assert_eq!((loaded.x_spawn, loaded.y_spawn, loaded.z_spawn), (2, 3, 4));
+        assert_eq!(loaded.spawn_position().yaw, 128);
+        assert!(!loaded.is_dirty());
+    }
+
+    #[test]
+    fn loads_version_0_and_upgrades_it() {
+        let map = sample_map();
+        let mut data = HEADER_INDENTIFIER.as_bytes().to_vec();
+        data.push(VERSION_RAW);
+        for value in [2i16, 3, 4, 16, 8, 12] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&map.blocks);
+
+        let loaded = DmfMap::from_bytes(&data).unwrap();
+        assert_eq!(loaded.blocks, map.blocks);
+        assert_eq!(loaded.metadata, MapMetadata::default());
+        assert!(loaded.is_dirty());
+        assert_eq!(loaded.to_bytes().unwrap()[20], HEADER_VERSION);
+
+        data.pop();
+        assert!(DmfMap::from_bytes(&data).is_err());
+    }
+
+    #[test]
+    fn rejects_corrupted_files() {
+        let mut data = sample_map().to_bytes().unwrap();
+        let middle = data.len() / 2;
+        data[middle] ^= 0xff;
+        let Err(Error::InvalidMap(message)) = DmfMap::from_bytes(&data) else {
+            panic!("corrupted map loaded");
+        };
+        assert_eq!(message, "checksum does not match");
+
+        let mut data = sample_map().to_bytes().unwrap();
+        data[20] = HEADER_VERSION + 1;
+        assert!(DmfMap::from_bytes(&data).is_err());
+    }
+
+    #[test]
+    fn rejects_files_cut_off_inside_the_checksum() {
+        // a 1x1xz header whose last byte is also the first byte of a crc that
+        // matches, which keeps z small enough to pass the size check
+        let cut = (0..i16::MAX)
+            .map(|x_spawn| {
+                let mut data = HEADER_INDENTIFIER.as_bytes().to_vec();
+                data.push(HEADER_VERSION);
+                for value in [x_spawn, 0, 0, 1, 1] {
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+                data.push(1);
+                let mut crc = Crc::new();
+                crc.update(&data);
+                data.extend_from_slice(&crc.sum().to_le_bytes());
+                data
+            })
+            .find(|data| data[HEADER_SIZE - 1] < 0x0c)
+            .unwrap();
+        assert_eq!(cut.len(), HEADER_SIZE + 3);
+        assert!(matches!(
+            DmfMap::from_bytes(&cut),
+            Err(Error::InvalidMap(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_headers_bigger_than_the_file() {
+        let path = std::env::temp_dir().join(format!("dandelion-huge-{}.dmf", std::process::id()));
+        for version in [VERSION_RAW, HEADER_VERSION] {
+            let mut data = HEADER_INDENTIFIER.as_bytes().to_vec();
+            data.push(version);
+            for value in [0i16, 0, 0, i16::MAX, i16::MAX, i16::MAX] {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+            data.extend_from_slice(&[0u8; 64]);
+
+            assert!(matches!(
+                DmfMap::from_bytes(&data),
+                Err(Error::InvalidMap(_))
+            ));
+            fs::write(&path, &data).unwrap();
+            assert!(matches!(
+                DmfMap::read_dimensions(&path.to_string_lossy()),
+                Err(Error::InvalidMap(_))
+            ));
+        }
+        fs::remove_file(&path).unwrap();
+
+        let data = sample_map().to_bytes().unwrap();
+        fs::write(&path, &data).unwrap();
+        assert_eq!(
+            DmfMap::read_dimensions(&path.to_string_lossy()).unwrap(),
+            (16, 8, 12)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn counts_blocks() {
+        let counts = sample_map().block_counts();
+        assert_eq!(counts[0x00], 16 * 8 * 12 - 2);
+        assert_eq!(counts[0x01], 1);
+        assert_eq!(counts[0x31], 1);
+        assert_eq!(counts.iter().sum::<usize>(), 16 * 8 * 12);
+    }
+
+    #[test]
+    fn finds_problems_clients_would_trip_over() {
+        let mut map = sample_map();
+        assert!(map.problems().is_empty());
+
+        map.set_spawn_point(16, 0, 0);
+        map.set_block(0, 0, 0, 100);
+        map.set_block(1, 0, 0, 100);
+        map.set_block(2, 0, 0, 101);
+        map.metadata.block_definitions.push(BlockDefinition {
+            id: 101,
+            ..BlockDefinition::default()
         });
+        assert_eq!(
+            map.problems(),
+            vec![
+                "spawn 16, 0, 0 is outside the map".to_string(),
+                "2 blocks of 100, which has no block definition".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn crops_and_grows() {
+        let map = sample_map();
+        let cropped = map.cropped((1, 2, 3), (15, 6, 9));
+        assert_eq!(cropped.get_block(0, 0, 0), 0x01);
+        assert_eq!(cropped.get_block(14, 5, 8), 0x31);
+        assert_eq!(
+            (cropped.x_spawn, cropped.y_spawn, cropped.z_spawn),
+            (1, 1, 1)
+        );
+        assert_eq!(cropped.metadata, map.metadata);
+
+        // past the far corner is air, the spawn stays inside
+        let grown = map.cropped((-2, 0, 0), (40, 8, 12));
+        assert_eq!(grown.get_block(3, 2, 3), 0x01);
+        assert_eq!(grown.get_block(17, 7, 11), 0x31);
+        assert_eq!(grown.block_counts()[0x01], 1);
+        assert_eq!(grown.block_counts()[0x00], 40 * 8 * 12 - 2);
+        let small = map.cropped((0, 0, 0), (1, 1, 1));
+        assert_eq!((small.x_spawn, small.y_spawn, small.z_spawn), (0, 0, 0));
+
+        // nothing of the old map in reach is all air, even at the edge of i16
+        let outside = map.cropped((i16::MAX - 1, 0, 0), (4, 4, 4));
+        assert_eq!(outside.block_counts()[0x00], 4 * 4 * 4);
+        assert_eq!(outside.x_spawn, 0);
     }
 }
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+// server wide id of anything that can be spawned on a client, never sent on
+// the wire and never reused while the server runs
+pub type EntityId = u32;
+
+// clients only know 128 entity ids and treat -1 as themselves, so every
+// viewer hands out 0..=126 to whatever it currently has spawned
+pub const MAX_WIRE_ID: i8 = 126;
+
+#[derive(Clone, Debug)]
+pub struct WireIds {
+    by_entity: HashMap<EntityId, i8>,
+    // kept in reverse so the lowest free id is popped first
+    free: Vec<i8>,
+}
+
+impl WireIds {
+    pub fn new() -> Self {
+        Self {
+            by_entity: HashMap::new(),
+            free: (0..=MAX_WIRE_ID).rev().collect(),
+        }
+    }
+
+    pub fn get(&self, entity_id: EntityId) -> Option<i8> {
+        self.by_entity.get(&entity_id).copied()
+    }
+
+    pub fn contains(&self, entity_id: EntityId) -> bool {
+        self.by_entity.contains_key(&entity_id)
+    }
+
+    // wire id for the entity, handing out a new one if needed. none once every
+    // wire id is taken
+    pub fn assign(&mut self, entity_id: EntityId) -> Option<i8> {
+        if let Some(wire_id) = self.get(entity_id) {
+            return Some(wire_id);
+        }
+        let wire_id = self.free.pop()?;
+        self.by_entity.insert(entity_id, wire_id);
+        Some(wire_id)
+    }
+
+    // frees the entity's wire id and returns it so it can be despawned
+    pub fn release(&mut self, entity_id: EntityId) -> Option<i8> {
+        let wire_id = self.by_entity.remove(&entity_id)?;
+        // keep the lowest ids at the end so they get reused first
+        let index = self.free.partition_point(|free| *free > wire_id);
+        self.free.insert(index, wire_id);
+        Some(wire_id)
+    }
+
+    pub fn entities(&self) -> impl Iterator<Item = EntityId> + '_ {
+        self.by_entity.keys().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_entity.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_entity.is_empty()
+    }
+}
+
+impl Default for WireIds {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assigns_lowest_free_id() {
+        let mut ids = WireIds::new();
+        assert_eq!(ids.assign(1000), Some(0));
+        assert_eq!(ids.assign(7), Some(1));
+        assert_eq!(ids.assign(1000), Some(0));
+        assert_eq!(ids.release(1000), Some(0));
+        assert_eq!(ids.assign(42), Some(0));
+        assert_eq!(ids.get(7), Some(1));
+    }
+
+    #[test]
+    fn runs_out_after_the_last_wire_id() {
+        let mut ids = WireIds::new();
+        for entity_id in 0..=MAX_WIRE_ID as EntityId {
+            assert_eq!(ids.assign(entity_id + 500), Some(entity_id as i8));
+        }
+        assert_eq!(ids.assign(9999), None);
+        assert_eq!(ids.release(600), Some(100));
+        assert_eq!(ids.assign(9999), Some(100));
+        assert_eq!(ids.len(), MAX_WIRE_ID as usize + 1);
+    }
+
+    #[test]
+    fn releasing_unknown_entity_does_nothing() {
+        let mut ids = WireIds::new();
+        assert_eq!(ids.release(3), None);
+        assert!(ids.is_empty());
+    }
+}
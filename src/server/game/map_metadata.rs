@@ -0,0 +1,331 @@
+use crate::server::error::{Error, Result};
+use crate::server::game::player::Rank;
+
+// every entry is a tag, a u16 length and that many bytes, so entries from a
+// newer server can be skipped by an older one
+const TAG_SPAWN_ORIENTATION: u8 = 0x01;
+const TAG_CREATED_AT: u8 = 0x02;
+const TAG_AUTHOR: u8 = 0x03;
+const TAG_GENERATOR: u8 = 0x04;
+const TAG_SEED: u8 = 0x05;
+const TAG_SKY_COLOR: u8 = 0x10;
+const TAG_CLOUD_COLOR: u8 = 0x11;
+const TAG_FOG_COLOR: u8 = 0x12;
+const TAG_EDGE_BLOCK: u8 = 0x13;
+const TAG_SIDE_BLOCK: u8 = 0x14;
+const TAG_EDGE_HEIGHT: u8 = 0x15;
+const TAG_WEATHER: u8 = 0x16;
+const TAG_BUILD_RANK: u8 = 0x20;
+const TAG_VISIT_RANK: u8 = 0x21;
+const TAG_BLOCK_DEFINITION: u8 = 0x30;
+
+// fixed part of a block definition, the name follows it
+const BLOCK_DEFINITION_SIZE: usize = 15;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MapMetadata {
+    pub spawn_yaw: u8,
+    pub spawn_pitch: u8,
+    // unix seconds
+    pub created_at: Option<u64>,
+    pub author: Option<String>,
+    pub generator: Option<String>,
+    pub seed: Option<i64>,
+    pub environment: Environment,
+    pub permissions: Permissions,
+    pub block_definitions: Vec<BlockDefinition>,
+}
+
+// what EnvMapAppearance and EnvSetColor tell clients, unset means the client default
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Environment {
+    pub sky_color: Option<[u8; 3]>,
+    pub cloud_color: Option<[u8; 3]>,
+    pub fog_color: Option<[u8; 3]>,
+    pub edge_block: Option<u8>,
+    pub side_block: Option<u8>,
+    pub edge_height: Option<i16>,
+    pub weather: Option<u8>,
+}
+
+// lowest rank allowed to do something on the map, unset means everyone
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Permissions {
+    pub build: Option<Rank>,
+    pub visit: Option<Rank>,
+}
+
+// laid out like the BlockDefinitions cpe packet
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockDefinition {
+    pub id: u8,
+    pub name: String,
+    pub solidity: u8,
+    pub speed: u8,
+    pub top_texture: u8,
+    pub side_texture: u8,
+    pub bottom_texture: u8,
+    pub transmits_light: bool,
+    pub walk_sound: u8,
+    pub full_bright: bool,
+    pub shape: u8,
+    pub draw: u8,
+    pub fog_density: u8,
+    pub fog_color: [u8; 3],
+}
+
+impl MapMetadata {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        put(
+            &mut out,
+            TAG_SPAWN_ORIENTATION,
+            &[self.spawn_yaw, self.spawn_pitch],
+        );
+        if let Some(created_at) = self.created_at {
+            put(&mut out, TAG_CREATED_AT, &created_at.to_le_bytes());
+        }
+        if let Some(author) = &self.author {
+            put(&mut out, TAG_AUTHOR, author.as_bytes());
+        }
+        if let Some(generator) = &self.generator {
+            put(&mut out, TAG_GENERATOR, generator.as_bytes());
+        }
+        if let Some(seed) = self.seed {
+            put(&mut out, TAG_SEED, &seed.to_le_bytes());
+        }
+
+        let environment = &self.environment;
+        if let Some(color) = environment.sky_color {
+            put(&mut out, TAG_SKY_COLOR, &color);
+        }
+        if let Some(color) = environment.cloud_color {
+            put(&mut out, TAG_CLOUD_COLOR, &color);
+        }
+        if let Some(color) = environment.fog_color {
+            put(&mut out, TAG_FOG_COLOR, &color);
+        }
+        if let Some(block) = environment.edge_block {
+            put(&mut out, TAG_EDGE_BLOCK, &[block]);
+        }
+        if let Some(block) = environment.side_block {
+            put(&mut out, TAG_SIDE_BLOCK, &[block]);
+        }
+        if let Some(height) = environment.edge_height {
+            put(&mut out, TAG_EDGE_HEIGHT, &height.to_le_bytes());
+        }
+        if let Some(weather) = environment.weather {
+            put(&mut out, TAG_WEATHER, &[weather]);
+        }
+
+        if let Some(rank) = self.permissions.build {
+            put(&mut out, TAG_BUILD_RANK, &[rank_to_byte(rank)]);
+        }
+        if let Some(rank) = self.permissions.visit {
+            put(&mut out, TAG_VISIT_RANK, &[rank_to_byte(rank)]);
+        }
+
+        for block in &self.block_definitions {
+            let mut value = vec![
+                block.id,
+                block.solidity,
+                block.speed,
+                block.top_texture,
+                block.side_texture,
+                block.bottom_texture,
+                block.transmits_light as u8,
+                block.walk_sound,
+                block.full_bright as u8,
+                block.shape,
+                block.draw,
+                block.fog_density,
+            ];
+            value.extend_from_slice(&block.fog_color);
+            value.extend_from_slice(block.name.as_bytes());
+            put(&mut out, TAG_BLOCK_DEFINITION, &value);
+        }
+        out
+    }
+
+    pub fn decode(mut data: &[u8]) -> Result<Self> {
+        let mut metadata = Self::default();
+        while !data.is_empty() {
+            if data.len() < 3 {
+                return Err(Error::InvalidMap("metadata entry is cut off".to_string()));
+            }
+            let tag = data[0];
+            let length = u16::from_le_bytes([data[1], data[2]]) as usize;
+            let Some(value) = data.get(3..3 + length) else {
+                return Err(Error::InvalidMap(format!(
+                    "metadata entry {:#04x} is cut off",
+                    tag
+                )));
+            };
+            data = &data[3 + length..];
+
+            match tag {
+                TAG_SPAWN_ORIENTATION => {
+                    let [yaw, pitch] = fixed(tag, value)?;
+                    metadata.spawn_yaw = yaw;
+                    metadata.spawn_pitch = pitch;
+                }
+                TAG_CREATED_AT => {
+                    metadata.created_at = Some(u64::from_le_bytes(fixed(tag, value)?))
+                }
+                TAG_AUTHOR => metadata.author = Some(text(value)),
+                TAG_GENERATOR => metadata.generator = Some(text(value)),
+                TAG_SEED => metadata.seed = Some(i64::from_le_bytes(fixed(tag, value)?)),
+                TAG_SKY_COLOR => metadata.environment.sky_color = Some(fixed(tag, value)?),
+                TAG_CLOUD_COLOR => metadata.environment.cloud_color = Some(fixed(tag, value)?),
+                TAG_FOG_COLOR => metadata.environment.fog_color = Some(fixed(tag, value)?),
+                TAG_EDGE_BLOCK => metadata.environment.edge_block = Some(byte(tag, value)?),
+                TAG_SIDE_BLOCK => metadata.environment.side_block = Some(byte(tag, value)?),
+                TAG_EDGE_HEIGHT => {
+                    metadata.environment.edge_height = Some(i16::from_le_bytes(fixed(tag, value)?))
+                }
+                TAG_WEATHER => metadata.environment.weather = Some(byte(tag, value)?),
+                TAG_BUILD_RANK => metadata.permissions.build = Some(rank(tag, value)?),
+                TAG_VISIT_RANK => metadata.permissions.visit = Some(rank(tag, value)?),
+                TAG_BLOCK_DEFINITION => metadata.block_definitions.push(block_definition(value)?),
+                // written by a newer version, nothing we can do with it
+                _ => {}
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+fn put(out: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    // strings are the only thing that can get long, cut them rather than
+    // writing a length that doesn't fit
+    let value = &value[..value.len().min(u16::MAX as usize)];
+    out.push(tag);
+    out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+fn fixed<const N: usize>(tag: u8, value: &[u8]) -> Result<[u8; N]> {
+    value.try_into().map_err(|_| {
+        Error::InvalidMap(format!(
+            "metadata entry {:#04x} should be {} bytes, not {}",
+            tag,
+            N,
+            value.len()
+        ))
+    })
+}
+
+fn byte(tag: u8, value: &[u8]) -> Result<u8> {
+    fixed::<1>(tag, value).map(|[byte]| byte)
+}
+
+// lossy since a long string may have been cut in the middle of a character
+fn text(value: &[u8]) -> String {
+    String::from_utf8_lossy(value).into_owned()
+}
+
+fn rank(tag: u8, value: &[u8]) -> Result<Rank> {
+    match byte(tag, value)? {
+        0 => Ok(Rank::Guest),
+        1 => Ok(Rank::Member),
+        2 => Ok(Rank::Moderator),
+        3 => Ok(Rank::Admin),
+        other => Err(Error::InvalidMap(format!("unknown rank {}", other))),
+    }
+}
+
+fn rank_to_byte(rank: Rank) -> u8 {
+    match rank {
+        Rank::Guest => 0,
+        Rank::Member => 1,
+        Rank::Moderator => 2,
+        Rank::Admin => 3,
+    }
+}
+
+fn block_definition(value: &[u8]) -> Result<BlockDefinition> {
+    if value.len() < BLOCK_DEFINITION_SIZE {
+        return Err(Error::InvalidMap(format!(
+            "block definition should be at least {} bytes, not {}",
+            BLOCK_DEFINITION_SIZE,
+            value.len()
+        )));
+    }
+    Ok(BlockDefinition {
+        id: value[0],
+        solidity: value[1],
+        speed: value[2],
+        top_texture: value[3],
+        side_texture: value[4],
+        bottom_texture: value[5],
+        transmits_light: value[6] != 0,
+        walk_sound: value[7],
+        full_bright: value[8] != 0,
+        shape: value[9],
+        draw: value[10],
+        fog_density: value[11],
+        fog_color: [value[12], value[13], value[14]],
+        name: text(&value[BLOCK_DEFINITION_SIZE..]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_every_field() {
+        let metadata = MapMetadata {
+            spawn_yaw: 64,
+            spawn_pitch: 8,
+            created_at: Some(1_700_000_000),
+            author: Some("flafmg".to_string()),
+            generator: Some("classic".to_string()),
+            seed: Some(-42),
+            environment: Environment {
+                sky_color: Some([1, 2, 3]),
+                cloud_color: Some([4, 5, 6]),
+                fog_color: None,
+                edge_block: Some(8),
+                side_block: Some(7),
+                edge_height: Some(-1),
+                weather: Some(1),
+            },
+            permissions: Permissions {
+                build: Some(Rank::Member),
+                visit: Some(Rank::Admin),
+            },
+            block_definitions: vec![BlockDefinition {
+                id: 70,
+                name: "Glowing stone".to_string(),
+                solidity: 2,
+                speed: 128,
+                top_texture: 1,
+                side_texture: 2,
+                bottom_texture: 3,
+                transmits_light: true,
+                walk_sound: 4,
+                full_bright: true,
+                shape: 16,
+                draw: 0,
+                fog_density: 0,
+                fog_color: [0, 0, 0],
+            }],
+        };
+        assert_eq!(MapMetadata::decode(&metadata.encode()).unwrap(), metadata);
+    }
+
+    #[test]
+    fn skips_unknown_tags() {
+        let mut data = vec![0x7f, 2, 0, 0xaa, 0xbb];
+        put(&mut data, TAG_AUTHOR, b"someone");
+        let metadata = MapMetadata::decode(&data).unwrap();
+        assert_eq!(metadata.author.as_deref(), Some("someone"));
+    }
+
+    #[test]
+    fn rejects_cut_off_entries() {
+        assert!(MapMetadata::decode(&[TAG_AUTHOR, 10, 0, b'a']).is_err());
+        assert!(MapMetadata::decode(&[TAG_SEED, 1, 0, 0]).is_err());
+    }
+}
@@ -1,2 +1,7 @@
 pub mod dmf_map;
+pub mod entity_ids;
+pub mod map_metadata;
+pub mod movement;
 pub mod player;
+pub mod player_registry;
+pub mod visibility;
@@ -0,0 +1,147 @@
+use bytes::Bytes;
+
+use crate::server::network::packet::Packet;
+use crate::server::network::packets::clientbound::{
+    SetPositionAndOrientationPacket, UpdateOrientationPacket, UpdatePositionAndOrientationPacket,
+    UpdatePositionPacket,
+};
+
+// position in fixed point (1/32 of a block) plus rotation, as sent on the wire
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EntityPosition {
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+    pub yaw: u8,
+    pub pitch: u8,
+}
+
+impl EntityPosition {
+    pub fn new(x: i16, y: i16, z: i16, yaw: u8, pitch: u8) -> Self {
+        Self {
+            x,
+            y,
+            z,
+            yaw,
+            pitch,
+        }
+    }
+}
+
+// picks the smallest packet that moves `entity_id` from `last` to `current`,
+// relative packets only carry an i8 per axis so anything further than that
+// falls back to an absolute teleport
+pub fn movement_packet(
+    entity_id: i8,
+    last: &EntityPosition,
+    current: &EntityPosition,
+) -> Option<Bytes> {
+    let moved = (last.x, last.y, last.z) != (current.x, current.y, current.z);
+    let rotated = (last.yaw, last.pitch) != (current.yaw, current.pitch);
+    if !moved && !rotated {
+        return None;
+    }
+
+    let delta = |from: i16, to: i16| i8::try_from(to as i32 - from as i32).ok();
+    let relative = match (
+        delta(last.x, current.x),
+        delta(last.y, current.y),
+        delta(last.z, current.z),
+    ) {
+        (Some(dx), Some(dy), Some(dz)) => Some((dx, dy, dz)),
+        _ => None,
+    };
+
+    let packet = match relative {
+        Some((dx, dy, dz)) if moved && rotated => UpdatePositionAndOrientationPacket::new(
+            entity_id,
+            dx,
+            dy,
+            dz,
+            current.yaw,
+            current.pitch,
+        )
+        .encode(),
+        Some((dx, dy, dz)) if moved => UpdatePositionPacket::new(entity_id, dx, dy, dz).encode(),
+        Some(_) => UpdateOrientationPacket::new(entity_id, current.yaw, current.pitch).encode(),
+        None => SetPositionAndOrientationPacket::new(
+            entity_id,
+            current.x,
+            current.y,
+            current.z,
+            current.yaw,
+            current.pitch,
+        )
+        .encode(),
+    };
+    Some(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(x: i16, y: i16, z: i16, yaw: u8, pitch: u8) -> EntityPosition {
+        EntityPosition::new(x, y, z, yaw, pitch)
+    }
+
+    #[test]
+    fn standing_still_sends_nothing() {
+        let here = position(100, 200, 300, 10, 20);
+        assert_eq!(movement_packet(3, &here, &here), None);
+    }
+
+    #[test]
+    fn picks_the_smallest_packet() {
+        let last = position(100, 200, 300, 10, 20);
+
+        let moved = movement_packet(3, &last, &position(110, 195, 300, 10, 20)).unwrap();
+        assert_eq!(moved[0], 0x0a);
+        assert_eq!(
+            UpdatePositionPacket::decode(&moved).unwrap(),
+            UpdatePositionPacket::new(3, 10, -5, 0)
+        );
+
+        let turned = movement_packet(3, &last, &position(100, 200, 300, 64, 0)).unwrap();
+        assert_eq!(turned[0], 0x0b);
+        assert_eq!(
+            UpdateOrientationPacket::decode(&turned).unwrap(),
+            UpdateOrientationPacket::new(3, 64, 0)
+        );
+
+        let both = movement_packet(3, &last, &position(99, 200, 301, 11, 20)).unwrap();
+        assert_eq!(both[0], 0x09);
+        assert_eq!(
+            UpdatePositionAndOrientationPacket::decode(&both).unwrap(),
+            UpdatePositionAndOrientationPacket::new(3, -1, 0, 1, 11, 20)
+        );
+    }
+
+    #[test]
+    fn teleports_past_what_an_i8_holds() {
+        let last = position(1000, 1000, 1000, 0, 0);
+
+        // 127 and -128 still fit in a relative move
+        let edge = movement_packet(5, &last, &position(1127, 872, 1000, 0, 0)).unwrap();
+        assert_eq!(
+            UpdatePositionPacket::decode(&edge).unwrap(),
+            UpdatePositionPacket::new(5, 127, -128, 0)
+        );
+
+        for far in [
+            position(1128, 1000, 1000, 0, 0),
+            position(1000, 871, 1000, 0, 0),
+        ] {
+            let teleport = movement_packet(5, &last, &far).unwrap();
+            assert_eq!(teleport[0], 0x08);
+            assert_eq!(
+                SetPositionAndOrientationPacket::decode(&teleport).unwrap(),
+                SetPositionAndOrientationPacket::new(5, far.x, far.y, far.z, 0, 0)
+            );
+        }
+
+        // a far move with a turn is still a single teleport
+        let turned = movement_packet(5, &last, &position(-1000, 1000, 1000, 90, 4)).unwrap();
+        assert_eq!(turned[0], 0x08);
+    }
+}
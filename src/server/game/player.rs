@@ -1,52 +1,90 @@
 use crate::server::game::dmf_map::DmfMap;
+use crate::server::game::entity_ids::{EntityId, WireIds};
+use crate::server::game::movement::EntityPosition;
+use crate::server::game::player_registry::ConnectionId;
+use crate::server::network::packet::Packet;
 use crate::server::network::packets::clientbound::{
-    SendMessagePacket, SetPositionAndOrientationPacket,
-};
-use crate::server::network::{
-    packet::PacketTrait,
-    packet_stream::packet_writer::PacketWriter,
-    packets::clientbound::{LevelDataChunkPacket, LevelFinalizePacket, LevelInitializePacket},
+    LevelDataChunkPacket, LevelFinalizePacket, LevelInitializePacket, SendMessagePacket,
+    SetPositionAndOrientationPacket,
 };
+use crate::server::network::session::ClientSocket;
 use flate2::write::GzEncoder;
 use flate2::Compression;
+use serde::{Deserialize, Serialize};
 use std::io::prelude::*;
-use std::sync::Arc;
-use tokio::{io::WriteHalf, net::TcpStream, sync::RwLock};
+use tokio::io::{self, AsyncWriteExt};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rank {
+    #[default]
+    Guest,
+    Member,
+    Moderator,
+    Admin,
+}
+
+impl Rank {
+    pub fn is_staff(&self) -> bool {
+        *self >= Rank::Moderator
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Player {
-    id: i8,
+    connection_id: ConnectionId,
+    id: EntityId,
     name: String,
+    pub rank: Rank,
     pub current_map: String,
     pub x: i16,
     pub y: i16,
     pub z: i16,
     pub yaw: u8,
     pub pitch: u8,
-    pub socket: Arc<RwLock<WriteHalf<TcpStream>>>,
+    // what other players were last told about this player
+    pub last_broadcast: EntityPosition,
+    // entities currently spawned on this player's client and their wire ids
+    pub entity_ids: WireIds,
+    // set once the level was sent and the player was placed in it
+    pub spawned: bool,
+    pub hidden: bool,
+    pub socket: ClientSocket,
 }
 
 impl Player {
     pub fn new(
-        id: i8,
+        connection_id: ConnectionId,
+        id: EntityId,
         name: String,
+        rank: Rank,
         current_map: String,
-        socket: Arc<RwLock<WriteHalf<TcpStream>>>,
+        socket: ClientSocket,
     ) -> Self {
         Self {
+            connection_id,
             id,
             name,
+            rank,
             current_map,
             x: 0,
             y: 0,
             z: 0,
             yaw: 0,
             pitch: 0,
+            last_broadcast: EntityPosition::default(),
+            entity_ids: WireIds::new(),
+            spawned: false,
+            hidden: false,
             socket,
         }
     }
 
-    pub fn get_id(&self) -> i8 {
+    pub fn get_connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+
+    pub fn get_id(&self) -> EntityId {
         self.id
     }
 
@@ -65,40 +103,49 @@ impl Player {
         self.pitch = pitch;
         self.yaw = yaw;
     }
+
+    pub fn position(&self) -> EntityPosition {
+        EntityPosition::new(self.x, self.y, self.z, self.yaw, self.pitch)
+    }
+
+    pub async fn send(&self, data: &[u8]) -> io::Result<()> {
+        let mut socket = self.socket.write().await;
+        socket.write_all(data).await
+    }
+
+    pub async fn send_packet(&self, packet: &impl Packet) -> io::Result<()> {
+        self.send(&packet.encode()).await
+    }
+
     pub async fn teleport(&mut self, x: i16, y: i16, z: i16, pitch: u8, yaw: u8) {
         self.set_pos(x, y, z, pitch, yaw);
-        let mut set_position = SetPositionAndOrientationPacket::new(-1, x, y, z, pitch, yaw);
-        set_position.write(&mut PacketWriter::new());
-        let mut socket = self.socket.write().await;
-        set_position.resolve(&mut socket).await;
+        let set_position = SetPositionAndOrientationPacket::new(-1, x, y, z, yaw, pitch);
+        if let Err(e) = self.send_packet(&set_position).await {
+            eprintln!("Error teleporting {}: {}", self.name, e);
+        }
     }
+
     pub async fn send_message(&self, msg: &str) {
-        let mut message_packet = SendMessagePacket::new(-1, msg.to_string());
-        message_packet.write(&mut PacketWriter::new());
-        let mut socket = self.socket.write().await;
-        message_packet.resolve(&mut socket).await;
+        let message_packet = SendMessagePacket::new(-1, msg.to_string());
+        if let Err(e) = self.send_packet(&message_packet).await {
+            eprintln!("Error sending message to {}: {}", self.name, e);
+        }
     }
-    pub async fn send_to_level(&self, map: &DmfMap) {
-        let block_data = &map.blocks;
 
-        let mut socket_guard = self.socket.write().await;
+    pub async fn send_to_level(&self, map: &DmfMap) -> io::Result<()> {
+        let block_data = &map.blocks;
 
-        let mut resolve_packet_response = LevelInitializePacket::new();
-        let mut packet_writer = PacketWriter::new();
-        resolve_packet_response.write(&mut packet_writer);
-        resolve_packet_response
-            .resolve(&mut *socket_guard)
-            .await
-            .unwrap();
+        let mut socket = self.socket.write().await;
+        socket.write_all(&LevelInitializePacket.encode()).await?;
 
         let total_length = block_data.len();
         let mut prefixed_data = Vec::with_capacity(4 + total_length);
         prefixed_data.extend_from_slice(&(total_length as u32).to_be_bytes());
-        prefixed_data.extend_from_slice(&block_data);
+        prefixed_data.extend_from_slice(block_data);
 
         let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-        encoder.write_all(&prefixed_data).unwrap();
-        let compressed_data = encoder.finish().unwrap();
+        encoder.write_all(&prefixed_data)?;
+        let compressed_data = encoder.finish()?;
 
         let chunk_size = 1024;
         for (i, chunk) in compressed_data.chunks(chunk_size).enumerate() {
@@ -107,24 +154,11 @@ impl Player {
                 / compressed_data.len() as f32
                 * 100.0) as u8;
 
-            let mut chunk_data = chunk.to_vec();
-            if chunk_data.len() < chunk_size {
-                chunk_data.resize(chunk_size, 0x00);
-            }
-
-            let mut packet = LevelDataChunkPacket::new(chunk_length, chunk_data, percent_complete);
-            let mut packet_writer = PacketWriter::new();
-            packet.write(&mut packet_writer);
-
-            if let Err(e) = packet.resolve(&mut *socket_guard).await {
-                eprintln!("Error sending chunk {}: {}", i, e);
-                break;
-            }
+            let packet = LevelDataChunkPacket::new(chunk_length, chunk.to_vec(), percent_complete);
+            socket.write_all(&packet.encode()).await?;
         }
 
-        let mut level_finalize = LevelFinalizePacket::new(map.x_size, map.y_size, map.z_size);
-        let mut packet_writer = PacketWriter::new();
-        level_finalize.write(&mut packet_writer);
-        level_finalize.resolve(&mut *socket_guard).await.unwrap();
+        let level_finalize = LevelFinalizePacket::new(map.x_size, map.y_size, map.z_size);
+        socket.write_all(&level_finalize.encode()).await
     }
 }
@@ -0,0 +1,159 @@
+use dashmap::mapref::multiple::{RefMulti, RefMutMulti};
+use dashmap::mapref::one::{Ref, RefMut};
+use dashmap::DashMap;
+
+use super::player::Player;
+
+// handed out by the listener, one per tcp connection, never reused
+pub type ConnectionId = u64;
+
+// the single source of truth for everyone that is logged in, keyed by the
+// connection they came from. don't hold a Ref across an await, take what
+// you need out of it (or clone the player) first
+pub struct PlayerRegistry {
+    players: DashMap<ConnectionId, Player>,
+}
+
+impl PlayerRegistry {
+    pub fn new() -> Self {
+        Self {
+            players: DashMap::new(),
+        }
+    }
+
+    pub fn insert(&self, player: Player) {
+        self.players.insert(player.get_connection_id(), player);
+    }
+
+    pub fn remove(&self, connection_id: ConnectionId) -> Option<Player> {
+        self.players
+            .remove(&connection_id)
+            .map(|(_, player)| player)
+    }
+
+    pub fn get(&self, connection_id: ConnectionId) -> Option<Ref<'_, ConnectionId, Player>> {
+        self.players.get(&connection_id)
+    }
+
+    pub fn get_mut(&self, connection_id: ConnectionId) -> Option<RefMut<'_, ConnectionId, Player>> {
+        self.players.get_mut(&connection_id)
+    }
+
+    // runs `f` on the stored player, returns none if they are gone
+    pub fn update<R>(
+        &self,
+        connection_id: ConnectionId,
+        f: impl FnOnce(&mut Player) -> R,
+    ) -> Option<R> {
+        self.players
+            .get_mut(&connection_id)
+            .map(|mut player| f(&mut player))
+    }
+
+    pub fn contains(&self, connection_id: ConnectionId) -> bool {
+        self.players.contains_key(&connection_id)
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<ConnectionId> {
+        self.players
+            .iter()
+            .find(|player| player.get_name().eq_ignore_ascii_case(name))
+            .map(|player| player.get_connection_id())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = RefMulti<'_, ConnectionId, Player>> {
+        self.players.iter()
+    }
+
+    pub fn iter_mut(&self) -> impl Iterator<Item = RefMutMulti<'_, ConnectionId, Player>> {
+        self.players.iter_mut()
+    }
+
+    // owned copies, safe to keep around while sending
+    pub fn snapshot(&self) -> Vec<Player> {
+        self.players.iter().map(|player| player.clone()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.players.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.players.is_empty()
+    }
+}
+
+impl Default for PlayerRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::game::player::Rank;
+    use crate::server::network::session::discard_socket;
+
+    fn player(connection_id: ConnectionId, name: &str) -> Player {
+        Player::new(
+            connection_id,
+            connection_id as u32 + 100,
+            name.to_string(),
+            Rank::Guest,
+            "main".to_string(),
+            discard_socket(),
+        )
+    }
+
+    #[test]
+    fn inserts_finds_and_removes() {
+        let registry = PlayerRegistry::new();
+        registry.insert(player(1, "Alice"));
+        registry.insert(player(2, "bob"));
+        assert_eq!(registry.len(), 2);
+        assert!(registry.contains(1));
+        assert_eq!(registry.get(2).unwrap().get_name(), "bob");
+
+        assert_eq!(registry.find_by_name("alice"), Some(1));
+        assert_eq!(registry.find_by_name("BOB"), Some(2));
+        assert_eq!(registry.find_by_name("carol"), None);
+
+        let removed = registry.remove(1).unwrap();
+        assert_eq!(removed.get_name(), "Alice");
+        assert!(registry.remove(1).is_none());
+        assert_eq!(registry.find_by_name("alice"), None);
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[test]
+    fn updates_in_place() {
+        let registry = PlayerRegistry::new();
+        registry.insert(player(1, "alice"));
+        let snapshot = registry.snapshot();
+
+        assert_eq!(
+            registry.update(1, |player| {
+                player.set_pos(64, 96, 128, 0, 90);
+                player.current_map = "other".to_string();
+                player.get_id()
+            }),
+            Some(101)
+        );
+        let stored = registry.get(1).unwrap();
+        assert_eq!(
+            (stored.x, stored.y, stored.z, stored.yaw),
+            (64, 96, 128, 90)
+        );
+        assert_eq!(stored.current_map, "other");
+        // holding on to it would lock its shard for the update below
+        drop(stored);
+        // snapshots are copies and don't follow along
+        assert_eq!(
+            (snapshot[0].x, snapshot[0].current_map.as_str()),
+            (0, "main")
+        );
+
+        assert_eq!(registry.update(9, |_| ()), None);
+    }
+}
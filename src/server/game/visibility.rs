@@ -0,0 +1,80 @@
+use super::player::Player;
+
+// whether `viewer` should have `target` spawned on their client: same map,
+// within `view_distance` blocks and not hidden
+pub fn can_see(viewer: &Player, target: &Player, view_distance: u32) -> bool {
+    if viewer.get_id() == target.get_id() || !viewer.spawned || !target.spawned {
+        return false;
+    }
+    if target.hidden || viewer.current_map != target.current_map {
+        return false;
+    }
+
+    let range = view_distance as i64;
+    distance_squared(viewer, target) <= range * range
+}
+
+// squared distance in whole blocks, positions are in 1/32 of a block
+pub fn distance_squared(viewer: &Player, target: &Player) -> i64 {
+    let dx = (viewer.x as i64 - target.x as i64) / 32;
+    let dy = (viewer.y as i64 - target.y as i64) / 32;
+    let dz = (viewer.z as i64 - target.z as i64) / 32;
+    dx * dx + dy * dy + dz * dz
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::game::player::Rank;
+    use crate::server::network::session::discard_socket;
+
+    // standing on `map` at the given block, spawned in
+    fn player(id: u64, map: &str, x: i16, y: i16, z: i16) -> Player {
+        let mut player = Player::new(
+            id,
+            id as _,
+            format!("player{}", id),
+            Rank::Guest,
+            map.to_string(),
+            discard_socket(),
+        );
+        player.set_pos(x * 32, y * 32, z * 32, 0, 0);
+        player.spawned = true;
+        player
+    }
+
+    #[test]
+    fn sees_players_in_range_on_the_same_map() {
+        let viewer = player(1, "main", 0, 0, 0);
+        assert!(can_see(&viewer, &player(2, "main", 10, 5, -10), 16));
+        assert!(can_see(&viewer, &player(2, "main", 16, 0, 0), 16));
+        assert!(!can_see(&viewer, &player(2, "main", 17, 0, 0), 16));
+        assert!(!can_see(&viewer, &player(2, "main", 10, 10, 10), 16));
+        assert!(!can_see(&viewer, &player(2, "other", 0, 0, 0), 16));
+    }
+
+    #[test]
+    fn skips_self_hidden_and_unspawned_players() {
+        let viewer = player(1, "main", 0, 0, 0);
+        assert!(!can_see(&viewer, &viewer, 16));
+
+        let mut hidden = player(2, "main", 1, 0, 0);
+        hidden.hidden = true;
+        assert!(!can_see(&viewer, &hidden, 16));
+
+        let mut loading = player(3, "main", 1, 0, 0);
+        loading.spawned = false;
+        assert!(!can_see(&viewer, &loading, 16));
+        assert!(!can_see(&loading, &viewer, 16));
+    }
+
+    #[test]
+    fn measures_distance_in_whole_blocks() {
+        let viewer = player(1, "main", 0, 0, 0);
+        let mut target = player(2, "main", 3, 4, 0);
+        assert_eq!(distance_squared(&viewer, &target), 25);
+        // less than a block off doesn't count
+        target.x += 31;
+        assert_eq!(distance_squared(&viewer, &target), 25);
+    }
+}
@@ -41,12 +41,12 @@ impl MapBuilder {
         for x in 0..dimensions.x {
             for z in 0..dimensions.z {
                 for y in 0..ground_level {
-                    map.set_block(x as i16, y as i16, z as i16, 0x01); // stone
+                    map.set_block(x, y as i16, z, 0x01); // stone
                 }
                 for y in ground_level..(ground_level + 3) {
-                    map.set_block(x as i16, y as i16, z as i16, 0x03); // dirt
+                    map.set_block(x, y as i16, z, 0x03); // dirt
                 }
-                map.set_block(x as i16, ground_level as i16 + 3, z as i16, 0x02);
+                map.set_block(x, ground_level as i16 + 3, z, 0x02);
                 // grass
             }
         }
@@ -68,12 +68,12 @@ impl MapBuilder {
                 let height = (height + dimensions.y as f64 / 2.0).min(dimensions.y as f64) as u32;
 
                 for y in 0..height {
-                    map.set_block(x as i16, y as i16, z as i16, 0x01); // stone
+                    map.set_block(x, y as i16, z, 0x01); // stone
                 }
                 for y in height..(height + 3) {
-                    map.set_block(x as i16, y as i16, z as i16, 0x03); // dirt
+                    map.set_block(x, y as i16, z, 0x03); // dirt
                 }
-                map.set_block(x as i16, height as i16 + 3, z as i16, 0x02); // grass
+                map.set_block(x, height as i16 + 3, z, 0x02); // grass
             }
         }
 
@@ -116,7 +116,7 @@ impl MapBuilder {
                     .min(dimensions.y as f64) as u32;
 
                 for y in 0..height {
-                    map.set_block(x as i16, y as i16, z as i16, 0x01);
+                    map.set_block(x, y as i16, z, 0x01);
                 }
             }
         }
@@ -124,8 +124,8 @@ impl MapBuilder {
         for x in 0..dimensions.x {
             for z in 0..dimensions.z {
                 for y in 0..=water_level {
-                    if map.get_block(x as i16, y as i16, z as i16) == 0x00 {
-                        map.set_block(x as i16, y as i16, z as i16, 0x08);
+                    if map.get_block(x, y, z) == 0x00 {
+                        map.set_block(x, y, z, 0x08);
                     }
                 }
             }
@@ -134,19 +134,18 @@ impl MapBuilder {
         for x in 0..dimensions.x {
             for z in 0..dimensions.z {
                 for y in (0..dimensions.y).rev() {
-                    let block = map.get_block(x as i16, y as i16, z as i16);
-
-                    if block == 0x01 {
-                        if map.get_block(x as i16, y as i16 + 1, z as i16) == 0x00
-                            || map.get_block(x as i16, y as i16 + 1, z as i16) == 0x08
-                        {
-                            if y as u32 <= water_level as u32 + 2 {
-                                map.set_block(x as i16, y as i16, z as i16, 0x0c);
-                            } else {
-                                map.set_block(x as i16, y as i16, z as i16, 0x02);
-                                for i in 1..=3 {
-                                    map.set_block(x as i16, y as i16 - i, z as i16, 0x03);
-                                }
+                    let block = map.get_block(x, y, z);
+
+                    if block == 0x01
+                        && (map.get_block(x, y + 1, z) == 0x00
+                            || map.get_block(x, y + 1, z) == 0x08)
+                    {
+                        if y as u32 <= water_level as u32 + 2 {
+                            map.set_block(x, y, z, 0x0c);
+                        } else {
+                            map.set_block(x, y, z, 0x02);
+                            for i in 1..=3 {
+                                map.set_block(x, y - i, z, 0x03);
                             }
                         }
                     }
@@ -1,54 +1,566 @@
+use crate::server::backups::Backups;
+use crate::server::config::Config;
+use crate::server::error::{Error, Result};
+use crate::server::formats::{self, MapFormat};
 use crate::server::game::dmf_map::DmfMap;
+use crate::server::game::player_registry::PlayerRegistry;
+use dashmap::mapref::one::{Ref, RefMut};
 use dashmap::DashMap;
+use std::collections::HashSet;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::io;
-use tokio::time::{sleep, Duration};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration, Instant};
 
-pub async fn load_all_maps_in(path: &str, maps: Arc<DashMap<String, DmfMap>>) -> io::Result<()> {
-    let entries = fs::read_dir(path)?;
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const RESCAN_INTERVAL: Duration = Duration::from_secs(5);
 
-    for entry in entries {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_file() {
-            let file_name = path.file_stem().unwrap().to_str().unwrap().to_string();
-            load_map(&path, maps.clone()).await?;
-            println!("map: {} loaded!", file_name);
+// what is known about a map on disk without loading its blocks
+#[derive(Debug, Clone)]
+pub struct MapInfo {
+    pub path: PathBuf,
+    pub x_size: i16,
+    pub y_size: i16,
+    pub z_size: i16,
+}
+
+// keeps track of every map in the maps folder, loads them when someone needs
+// them and unloads them again once nobody has been on them for a while
+pub struct MapManager {
+    dir: PathBuf,
+    index: DashMap<String, MapInfo>,
+    loaded: DashMap<String, DmfMap>,
+    // loaded maps nobody is on, and since when
+    empty_since: DashMap<String, Instant>,
+    pinned: HashSet<String>,
+    idle_unload: Duration,
+    save_interval: Duration,
+    backups: Backups,
+    backup_interval: Option<Duration>,
+    last_backup: DashMap<String, Instant>,
+    // when a missed lookup last rescanned the folder
+    last_rescan: std::sync::Mutex<Option<Instant>>,
+    // one save at a time so an older copy never lands on top of a newer one
+    save_lock: Mutex<()>,
+    // one load at a time so two players joining don't read the same file twice
+    load_lock: Mutex<()>,
+}
+
+impl MapManager {
+    pub fn new(dir: impl Into<PathBuf>, config: &Config) -> Self {
+        let dir = dir.into();
+        let mut pinned: HashSet<String> = config.pinned_maps.iter().cloned().collect();
+        pinned.insert(config.default_map.clone());
+        Self {
+            backups: Backups::new(dir.join("backups"), config.map_backups_kept),
+            dir,
+            index: DashMap::new(),
+            loaded: DashMap::new(),
+            empty_since: DashMap::new(),
+            pinned,
+            idle_unload: Duration::from_secs(config.map_idle_unload_secs),
+            save_interval: Duration::from_secs(config.map_save_interval_secs.max(1)),
+            backup_interval: (config.map_backup_interval_secs > 0)
+                .then(|| Duration::from_secs(config.map_backup_interval_secs)),
+            last_backup: DashMap::new(),
+            last_rescan: std::sync::Mutex::new(None),
+            save_lock: Mutex::new(()),
+            load_lock: Mutex::new(()),
         }
     }
 
-    Ok(())
-}
+    // rescans the maps folder
+    pub fn refresh_index(&self) -> Result<()> {
+        self.update_index(scan(&self.dir)?);
+        Ok(())
+    }
 
-pub async fn load_map(path: &Path, maps: Arc<DashMap<String, DmfMap>>) -> io::Result<()> {
-    let file_name = path.file_stem().unwrap().to_str().unwrap().to_string();
-    let map = DmfMap::load_file(path.to_str().unwrap()).unwrap();
-    maps.insert(file_name, map);
+    // the same from the runtime, the folder is read on a blocking thread
+    async fn refresh_index_async(&self) -> Result<()> {
+        let dir = self.dir.clone();
+        let scanned = tokio::task::spawn_blocking(move || scan(&dir))
+            .await
+            .map_err(|e| Error::InvalidMap(e.to_string()))
+            .and_then(|result| result)?;
+        self.update_index(scanned);
+        Ok(())
+    }
 
-    Ok(())
-}
+    fn update_index(&self, scanned: Vec<(String, MapInfo)>) {
+        let mut found = HashSet::new();
+        for (name, info) in scanned {
+            match self.index_entry(&found, &name, info) {
+                Ok(info) => {
+                    found.insert(name.clone());
+                    self.index.insert(name, info);
+                }
+                Err(skipped) => eprintln!(
+                    "Skipping map {}: another file is already called {}",
+                    skipped.display(),
+                    name
+                ),
+            }
+        }
+        self.index
+            .retain(|name, _| found.contains(name) || self.loaded.contains_key(name));
+    }
+
+    // picks between two files with the same name. an imported map has a dmf
+    // copy next to it after its first save, which is the one to keep using
+    fn index_entry(
+        &self,
+        found: &HashSet<String>,
+        name: &str,
+        info: MapInfo,
+    ) -> std::result::Result<MapInfo, PathBuf> {
+        let Some(existing) = self.index.get(name).filter(|_| found.contains(name)) else {
+            return Ok(info);
+        };
+        let saves = |path: &Path| MapFormat::of(path).is_some_and(MapFormat::can_save);
+        match (saves(&existing.path), saves(&info.path)) {
+            (true, false) => Err(info.path),
+            (false, true) => Ok(info),
+            // the same map in two formats, there is no telling which is newer
+            _ => Err(info.path),
+        }
+    }
+
+    // loads the pinned maps, everything else waits until someone joins it
+    pub async fn load_pinned(&self) {
+        let pinned: Vec<String> = self.pinned.iter().cloned().collect();
+        for name in pinned {
+            if let Err(e) = self.load(&name).await {
+                eprintln!("Failed to load pinned map {}: {}", name, e);
+            }
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.index.iter().map(|entry| entry.key().clone()).collect();
+        names.sort();
+        names
+    }
+
+    pub fn info(&self, name: &str) -> Option<MapInfo> {
+        self.index.get(name).map(|info| info.clone())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.index.contains_key(name)
+    }
+
+    pub fn is_loaded(&self, name: &str) -> bool {
+        self.loaded.contains_key(name)
+    }
+
+    // map names are matched without caring about case, a miss rescans the
+    // folder in case the map was added while running. players can miss on
+    // purpose, so that happens at most once every RESCAN_INTERVAL
+    pub async fn find(&self, name: &str) -> Option<String> {
+        let lookup = || {
+            self.index
+                .iter()
+                .map(|entry| entry.key().clone())
+                .find(|known| known.eq_ignore_ascii_case(name))
+        };
+        if let Some(found) = lookup() {
+            return Some(found);
+        }
+        if !self.rescan_due() {
+            return None;
+        }
+        if let Err(e) = self.refresh_index_async().await {
+            eprintln!("Failed to rescan maps: {}", e);
+        }
+        lookup()
+    }
+
+    fn rescan_due(&self) -> bool {
+        let mut last_rescan = self.last_rescan.lock().unwrap();
+        let now = Instant::now();
+        if last_rescan.is_some_and(|last| now.duration_since(last) < RESCAN_INTERVAL) {
+            return false;
+        }
+        *last_rescan = Some(now);
+        true
+    }
+
+    // a copy of the map, loading it first if needed
+    pub async fn get(&self, name: &str) -> Result<DmfMap> {
+        self.load(name).await?;
+        self.loaded
+            .get(name)
+            .map(|map| map.clone())
+            .ok_or_else(|| Error::InvalidMap(format!("{} was unloaded while loading", name)))
+    }
+
+    // only maps that are already loaded, for reading them in place
+    pub fn get_loaded(&self, name: &str) -> Option<Ref<'_, String, DmfMap>> {
+        self.loaded.get(name)
+    }
+
+    // only maps that are already loaded, for edits made by players on them
+    pub fn get_mut(&self, name: &str) -> Option<RefMut<'_, String, DmfMap>> {
+        self.loaded.get_mut(name)
+    }
+
+    pub async fn load(&self, name: &str) -> Result<()> {
+        self.empty_since.remove(name);
+        if self.loaded.contains_key(name) {
+            return Ok(());
+        }
+
+        let _guard = self.load_lock.lock().await;
+        if self.loaded.contains_key(name) {
+            return Ok(());
+        }
+        let info = self
+            .index
+            .get(name)
+            .map(|info| info.clone())
+            .ok_or_else(|| Error::InvalidMap(format!("no map called {}", name)))?;
+
+        let map = tokio::task::spawn_blocking(move || formats::load(&info.path))
+            .await
+            .map_err(|e| Error::InvalidMap(e.to_string()))??;
+        self.loaded.insert(name.to_string(), map);
+        println!("map: {} loaded!", name);
+        Ok(())
+    }
+
+    // writes the map if it changed since the last save, the file is written
+    // on a blocking thread from a copy so players can keep building
+    pub async fn save(&self, name: &str) -> Result<bool> {
+        let _guard = self.save_lock.lock().await;
+        let Some(map) = self.take_dirty(name) else {
+            return Ok(false);
+        };
+
+        let path = self.path_of(name);
+        let backups = self.backup_due(name).then(|| self.backups.clone());
+        let backup_name = name.to_string();
+        let saved_to = path.clone();
+        let saved = tokio::task::spawn_blocking(move || {
+            formats::save(&map, &path)?;
+            Ok(backups.map(|backups| backups.create(&backup_name, &path)))
+        })
+        .await
+        .map_err(|e| Error::InvalidMap(e.to_string()))
+        .and_then(|result| result);
+        let backup = match saved {
+            Ok(backup) => backup,
+            Err(e) => {
+                // try again next time
+                if let Some(mut map) = self.loaded.get_mut(name) {
+                    map.mark_dirty();
+                }
+                return Err(e);
+            }
+        };
+        if let Some(mut info) = self.index.get_mut(name) {
+            info.path = saved_to;
+        }
+        println!("Saved map: {}", name);
+
+        // a failed backup doesn't make the save any less done
+        match backup {
+            Some(Ok(id)) => {
+                self.last_backup.insert(name.to_string(), Instant::now());
+                println!("Backed up map {} as {}", name, id);
+            }
+            Some(Err(e)) => eprintln!("Failed to back up map {}: {}", name, e),
+            None => {}
+        }
+        Ok(true)
+    }
+
+    fn backup_due(&self, name: &str) -> bool {
+        self.backup_interval.is_some_and(|interval| {
+            self.last_backup
+                .get(name)
+                .is_none_or(|at| at.elapsed() >= interval)
+        })
+    }
+
+    // backup ids of a map, newest first
+    pub fn backups_of(&self, name: &str) -> Result<Vec<String>> {
+        Ok(self.backups.list(name)?)
+    }
+
+    // puts a backup in place of the map, what was there is backed up first so
+    // a restore can be undone
+    pub async fn restore(&self, name: &str, id: &str) -> Result<()> {
+        self.save(name).await?;
+        let _guard = self.save_lock.lock().await;
+        let backup_path = self
+            .backups
+            .path_of(name, id)?
+            .ok_or_else(|| Error::InvalidMap(format!("{} has no backup {}", name, id)))?;
+
+        let path = self.path_of(name);
+        let backups = self.backups.clone();
+        let backup_name = name.to_string();
+        let map_path = path.clone();
+        let map = tokio::task::spawn_blocking(move || -> Result<DmfMap> {
+            let mut map = formats::load(&backup_path)?;
+            if map_path.exists() {
+                backups.create(&backup_name, &map_path)?;
+            }
+            formats::save(&map, &map_path)?;
+            map.mark_clean();
+            Ok(map)
+        })
+        .await
+        .map_err(|e| Error::InvalidMap(e.to_string()))??;
+
+        self.last_backup.insert(name.to_string(), Instant::now());
+        self.index.insert(
+            name.to_string(),
+            MapInfo {
+                path,
+                x_size: map.x_size,
+                y_size: map.y_size,
+                z_size: map.z_size,
+            },
+        );
+        if let Some(mut loaded) = self.loaded.get_mut(name) {
+            *loaded = map;
+        }
+        println!("Restored map {} from backup {}", name, id);
+        Ok(())
+    }
+
+    fn take_dirty(&self, name: &str) -> Option<DmfMap> {
+        let mut map = self.loaded.get_mut(name)?;
+        if !map.is_dirty() {
+            return None;
+        }
+        map.mark_clean();
+        Some(map.clone())
+    }
+
+    pub async fn save_all(&self) {
+        let names: Vec<String> = self
+            .loaded
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        for name in names {
+            if let Err(e) = self.save(&name).await {
+                eprintln!("Failed to save map {}: {}", name, e);
+            }
+        }
+    }
+
+    pub async fn unload(&self, name: &str) -> Result<()> {
+        self.empty_since
+            .entry(name.to_string())
+            .or_insert_with(Instant::now);
+        self.save(name).await?;
+        // a block placed while saving keeps the map around until the next try,
+        // and so does a player joining it, which takes it out of empty_since
+        if self
+            .loaded
+            .remove_if(name, |_, map| {
+                !map.is_dirty() && self.empty_since.contains_key(name)
+            })
+            .is_some()
+        {
+            self.empty_since.remove(name);
+            println!("Unloaded map: {}", name);
+        }
+        Ok(())
+    }
+
+    // where the map is saved, imported maps move to a dmf next to the original
+    fn path_of(&self, name: &str) -> PathBuf {
+        self.index
+            .get(name)
+            .map(|info| info.path.clone())
+            .filter(|path| MapFormat::of(path).is_some_and(MapFormat::can_save))
+            .unwrap_or_else(|| self.dir.join(format!("{}.dmf", name)))
+    }
+
+    pub async fn save_all_loop(&self) {
+        loop {
+            sleep(self.save_interval).await;
+            self.save_all().await;
+        }
+    }
+
+    // unloads maps that have had nobody on them for idle_unload, pinned maps stay
+    pub async fn unload_idle_loop(&self, players: Arc<PlayerRegistry>) {
+        loop {
+            sleep(IDLE_CHECK_INTERVAL).await;
 
-pub async fn unload_map(name: &str, maps: Arc<DashMap<String, DmfMap>>) {
-    maps.remove(name);
-    println!("Unloaded map: {}", name);
+            let occupied: HashSet<String> = players
+                .iter()
+                .map(|player| player.current_map.clone())
+                .collect();
+            let now = Instant::now();
+            let loaded: Vec<String> = self
+                .loaded
+                .iter()
+                .map(|entry| entry.key().clone())
+                .collect();
+
+            for name in loaded {
+                if self.pinned.contains(&name) || occupied.contains(&name) {
+                    self.empty_since.remove(&name);
+                    continue;
+                }
+                let since = *self.empty_since.entry(name.clone()).or_insert(now);
+                if now.duration_since(since) < self.idle_unload {
+                    continue;
+                }
+                if let Err(e) = self.unload(&name).await {
+                    eprintln!("Failed to unload map {}: {}", name, e);
+                }
+            }
+        }
+    }
 }
 
-pub async fn save_all(maps: Arc<DashMap<String, DmfMap>>) {
-    for r in maps.iter() {
-        let (name, map) = r.pair();
-        if let Err(e) = map.save_file(&format!("maps/{}.dmf", name)) {
-            eprintln!("Failed to save map {}: {}", name, e);
-        } else {
-            println!("Saved map: {}", name);
+// every map file in the folder, one that can't be read is skipped so one bad
+// file doesn't stop the server
+fn scan(dir: &Path) -> Result<Vec<(String, MapInfo)>> {
+    let mut scanned = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() || MapFormat::of(&path).is_none() {
+            continue;
+        }
+        match read_info(&path) {
+            Ok(found) => scanned.push(found),
+            Err(e) => eprintln!("Skipping map {}: {}", path.display(), e),
         }
     }
+    Ok(scanned)
+}
+
+fn read_info(path: &Path) -> Result<(String, MapInfo)> {
+    let name = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| Error::InvalidMap("file name is not valid utf-8".to_string()))?
+        .to_string();
+    let (x_size, y_size, z_size) = formats::read_dimensions(path)?;
+    Ok((
+        name,
+        MapInfo {
+            path: path.to_path_buf(),
+            x_size,
+            y_size,
+            z_size,
+        },
+    ))
 }
 
-pub async fn save_all_loop(maps: Arc<DashMap<String, DmfMap>>) {
-    loop {
-        save_all(Arc::clone(&maps)).await;
-        sleep(Duration::from_secs(90)).await;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map_dir(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("dandelion-maps-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn indexes_without_loading_and_loads_on_demand() {
+        let dir = map_dir("lazy");
+        DmfMap::new(1, 2, 3, 8, 4, 16)
+            .save_file(&dir.join("Hub.dmf").to_string_lossy())
+            .unwrap();
+        fs::write(dir.join("broken.dmf"), b"not a map").unwrap();
+
+        let maps = MapManager::new(&dir, &Config::default());
+        maps.refresh_index().unwrap();
+        assert_eq!(maps.names(), vec!["Hub".to_string()]);
+        let info = maps.info("Hub").unwrap();
+        assert_eq!((info.x_size, info.y_size, info.z_size), (8, 4, 16));
+        assert!(!maps.is_loaded("Hub"));
+        assert!(maps.get_loaded("Hub").is_none());
+
+        assert_eq!(maps.find("hub").await.as_deref(), Some("Hub"));
+        let map = maps.get("Hub").await.unwrap();
+        assert_eq!(map.blocks.len(), 8 * 4 * 16);
+        assert!(maps.is_loaded("Hub"));
+        assert_eq!(maps.get_loaded("Hub").unwrap().x_spawn, 1);
+
+        maps.unload("Hub").await.unwrap();
+        assert!(!maps.is_loaded("Hub"));
+        assert!(maps.contains("Hub"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_saves_maps_that_changed() {
+        let dir = map_dir("dirty");
+        DmfMap::new(0, 0, 0, 4, 4, 4)
+            .save_file(&dir.join("build.dmf").to_string_lossy())
+            .unwrap();
+        let maps = MapManager::new(&dir, &Config::default());
+        maps.refresh_index().unwrap();
+        maps.load("build").await.unwrap();
+        assert!(!maps.save("build").await.unwrap());
+
+        // placing the block that is already there changes nothing
+        maps.get_mut("build").unwrap().set_block(1, 1, 1, 0x00);
+        assert!(!maps.save("build").await.unwrap());
+
+        maps.get_mut("build").unwrap().set_block(1, 1, 1, 0x01);
+        assert!(maps.save("build").await.unwrap());
+        assert!(!maps.save("build").await.unwrap());
+
+        let saved = DmfMap::load_file(&dir.join("build.dmf").to_string_lossy()).unwrap();
+        assert_eq!(saved.get_block(1, 1, 1), 0x01);
+        assert!(!saved.is_dirty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_maps_someone_joins_while_they_unload() {
+        let dir = map_dir("unload-join");
+        DmfMap::new(0, 0, 0, 4, 4, 4)
+            .save_file(&dir.join("busy.dmf").to_string_lossy())
+            .unwrap();
+        let maps = MapManager::new(&dir, &Config::default());
+        maps.refresh_index().unwrap();
+        maps.load("busy").await.unwrap();
+        maps.get_mut("busy").unwrap().set_block(1, 1, 1, 0x01);
+
+        // the unload waits on the save lock while the player joins
+        let guard = maps.save_lock.lock().await;
+        let (unloaded, _) = tokio::join!(maps.unload("busy"), async {
+            maps.load("busy").await.unwrap();
+            drop(guard);
+        });
+        unloaded.unwrap();
+        assert!(maps.is_loaded("busy"));
+
+        maps.unload("busy").await.unwrap();
+        assert!(!maps.is_loaded("busy"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn finds_maps_added_after_startup() {
+        let dir = map_dir("rescan");
+        let maps = MapManager::new(&dir, &Config::default());
+        maps.refresh_index().unwrap();
+        assert_eq!(maps.find("late").await, None);
+
+        DmfMap::new(0, 0, 0, 4, 4, 4)
+            .save_file(&dir.join("late.dmf").to_string_lossy())
+            .unwrap();
+        // the miss above just rescanned, the next one has to wait its turn
+        assert_eq!(maps.find("LATE").await, None);
+        *maps.last_rescan.lock().unwrap() = None;
+        assert_eq!(maps.find("LATE").await.as_deref(), Some("late"));
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,6 +1,12 @@
+pub mod backups;
 pub mod config;
+pub mod error;
+pub mod files;
+pub mod formats;
 pub mod game;
 pub mod map_builder;
 pub mod maps;
 pub mod network;
+pub mod schematics;
+#[allow(clippy::module_inception)]
 pub mod server;
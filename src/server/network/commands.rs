@@ -0,0 +1,354 @@
+use super::packet_resolver::PacketResolver;
+use super::packets::clientbound::{SendMessagePacket, UpdateSetBlockPacket};
+use crate::server::error::Error;
+use crate::server::formats::schematic::Schematic;
+use crate::server::game::player::Player;
+use crate::server::game::player_registry::ConnectionId;
+use crate::server::schematics;
+
+// more than this doesn't fit on the screen
+const LISTED_BACKUPS: usize = 8;
+// past this many changed blocks it's cheaper to send the whole map again
+const PASTE_BLOCK_UPDATES: usize = 4096;
+
+// chat messages starting with a slash end up here instead of in chat
+impl PacketResolver {
+    pub(super) async fn handle_command(&self, connection_id: ConnectionId, line: &str) {
+        let Some(player) = self
+            .server
+            .connected_players
+            .get(connection_id)
+            .map(|player| player.clone())
+        else {
+            return;
+        };
+
+        let mut args = line.trim_start_matches('/').split_whitespace();
+        let Some(command) = args.next() else {
+            return;
+        };
+        let args: Vec<&str> = args.collect();
+
+        match command.to_ascii_lowercase().as_str() {
+            "goto" | "g" => self.goto_command(&player, &args).await,
+            "backup" | "backups" => self.backup_command(&player, &args).await,
+            "schematic" | "schem" => self.schematic_command(&player, &args).await,
+            _ => {
+                player
+                    .send_message(&format!("&cUnknown command: {}", command))
+                    .await
+            }
+        }
+    }
+
+    async fn goto_command(&self, player: &Player, args: &[&str]) {
+        let [map_name] = args else {
+            player.send_message("&cUsage: /goto <map>").await;
+            return;
+        };
+        let Some(map_name) = self.find_map(map_name).await else {
+            player
+                .send_message(&format!("&cThere is no map called {}", map_name))
+                .await;
+            return;
+        };
+        if player.current_map == map_name {
+            player
+                .send_message(&format!("&eYou are already in {}", map_name))
+                .await;
+            return;
+        }
+
+        if self.change_map(player.get_connection_id(), &map_name).await {
+            println!("{} went to {}", player.get_name(), map_name);
+            player
+                .send_message(&format!("&eWelcome to {}", map_name))
+                .await;
+        } else {
+            player
+                .send_message(&format!("&cCould not load {}", map_name))
+                .await;
+        }
+    }
+
+    async fn backup_command(&self, player: &Player, args: &[&str]) {
+        match args {
+            ["list"] => self.list_backups(player, &player.current_map).await,
+            ["list", map_name] => match self.find_map(map_name).await {
+                Some(map_name) => self.list_backups(player, &map_name).await,
+                None => {
+                    player
+                        .send_message(&format!("&cThere is no map called {}", map_name))
+                        .await
+                }
+            },
+            ["restore", map_name, id] => self.restore_backup(player, map_name, id).await,
+            _ => {
+                player.send_message("&cUsage: /backup list [map]").await;
+                player
+                    .send_message("&cUsage: /backup restore <map> <backup>")
+                    .await;
+            }
+        }
+    }
+
+    async fn list_backups(&self, player: &Player, map_name: &str) {
+        let ids = match self.server.maps.backups_of(map_name) {
+            Ok(ids) => ids,
+            Err(e) => {
+                eprintln!("Error listing backups of {}: {}", map_name, e);
+                player.send_message("&cCould not list backups").await;
+                return;
+            }
+        };
+        if ids.is_empty() {
+            player
+                .send_message(&format!("&e{} has no backups", map_name))
+                .await;
+            return;
+        }
+
+        player
+            .send_message(&format!("&eBackups of {} (utc, newest first):", map_name))
+            .await;
+        for id in ids.iter().take(LISTED_BACKUPS) {
+            player.send_message(&format!("&7  {}", id)).await;
+        }
+        if ids.len() > LISTED_BACKUPS {
+            player
+                .send_message(&format!("&7  ...and {} older", ids.len() - LISTED_BACKUPS))
+                .await;
+        }
+    }
+
+    // everyone on the map gets the restored level sent again
+    async fn restore_backup(&self, player: &Player, map_name: &str, id: &str) {
+        if !player.rank.is_staff() {
+            player
+                .send_message("&cOnly staff can restore backups")
+                .await;
+            return;
+        }
+        let Some(map_name) = self.find_map(map_name).await else {
+            player
+                .send_message(&format!("&cThere is no map called {}", map_name))
+                .await;
+            return;
+        };
+
+        if let Err(e) = self.server.maps.restore(&map_name, id).await {
+            eprintln!("Error restoring {} from {}: {}", map_name, id, e);
+            player
+                .send_message(&format!("&cCould not restore {}: {}", map_name, e))
+                .await;
+            return;
+        }
+        println!("{} restored {} from {}", player.get_name(), map_name, id);
+
+        let on_map: Vec<ConnectionId> = self
+            .server
+            .connected_players
+            .iter()
+            .filter(|other| other.current_map == map_name)
+            .map(|other| other.get_connection_id())
+            .collect();
+        for connection_id in on_map {
+            self.change_map(connection_id, &map_name).await;
+        }
+        let message = format!("&e{} restored {} from {}", player.get_name(), map_name, id);
+        self.send_packet_to_all(None, &SendMessagePacket::new(-1, message))
+            .await;
+    }
+
+    async fn schematic_command(&self, player: &Player, args: &[&str]) {
+        match args {
+            ["list"] => self.list_schematics(player).await,
+            ["save", name, corners @ ..] if corners.len() == 6 => {
+                let corners: Option<Vec<i16>> =
+                    corners.iter().map(|value| value.parse().ok()).collect();
+                match corners.as_deref() {
+                    Some(&[x1, y1, z1, x2, y2, z2]) => {
+                        self.save_schematic(player, name, (x1, y1, z1), (x2, y2, z2))
+                            .await
+                    }
+                    _ => player.send_message("&cCorners have to be numbers").await,
+                }
+            }
+            ["paste", name] => self.paste_schematic(player, name).await,
+            _ => {
+                player.send_message("&cUsage: /schem list").await;
+                player
+                    .send_message("&cUsage: /schem save <name> <x1> <y1> <z1> <x2> <y2> <z2>")
+                    .await;
+                player.send_message("&cUsage: /schem paste <name>").await;
+            }
+        }
+    }
+
+    async fn list_schematics(&self, player: &Player) {
+        match self.server.schematics.list() {
+            Ok(names) if names.is_empty() => {
+                player.send_message("&eThere are no schematics yet").await
+            }
+            Ok(names) => {
+                player
+                    .send_message(&format!("&eSchematics: &7{}", names.join(", ")))
+                    .await
+            }
+            Err(e) => {
+                eprintln!("Error listing schematics: {}", e);
+                player.send_message("&cCould not list schematics").await;
+            }
+        }
+    }
+
+    // the player's position is kept as the origin, pasting puts the
+    // schematic the same way around whoever pastes it
+    async fn save_schematic(
+        &self,
+        player: &Player,
+        name: &str,
+        from: (i16, i16, i16),
+        to: (i16, i16, i16),
+    ) {
+        if !player.rank.is_staff() {
+            player
+                .send_message("&cOnly staff can save schematics")
+                .await;
+            return;
+        }
+        if !schematics::is_valid_name(name) {
+            player
+                .send_message("&cSchematic names can only have letters, numbers, _ and -")
+                .await;
+            return;
+        }
+        // copying only reads, other readers aren't held up meanwhile
+        let copied = match self.server.maps.get_loaded(&player.current_map) {
+            Some(map) => Schematic::copy(&map, from, to, feet_position(player)),
+            None => return,
+        };
+        let Ok(schematic) = copied else {
+            player
+                .send_message("&cThat selection is outside the map")
+                .await;
+            return;
+        };
+
+        let size = format!(
+            "{}x{}x{}",
+            schematic.width, schematic.height, schematic.length
+        );
+        let schematics = self.server.schematics.clone();
+        let file_name = name.to_string();
+        let saved = tokio::task::spawn_blocking(move || schematics.save(&file_name, &schematic))
+            .await
+            .map_err(|e| Error::InvalidMap(e.to_string()))
+            .and_then(|result| result);
+        match saved {
+            Ok(()) => {
+                println!("{} saved schematic {} ({})", player.get_name(), name, size);
+                player
+                    .send_message(&format!("&eSaved {} ({})", name, size))
+                    .await;
+            }
+            Err(e) => {
+                eprintln!("Error saving schematic {}: {}", name, e);
+                player
+                    .send_message(&format!("&cCould not save {}: {}", name, e))
+                    .await;
+            }
+        }
+    }
+
+    async fn paste_schematic(&self, player: &Player, name: &str) {
+        if !player.rank.is_staff() {
+            player
+                .send_message("&cOnly staff can paste schematics")
+                .await;
+            return;
+        }
+        let name = match self.server.schematics.find(name) {
+            Ok(Some(name)) => name,
+            Ok(None) => {
+                player
+                    .send_message(&format!("&cThere is no schematic called {}", name))
+                    .await;
+                return;
+            }
+            Err(e) => {
+                eprintln!("Error listing schematics: {}", e);
+                player.send_message("&cCould not list schematics").await;
+                return;
+            }
+        };
+        let schematics = self.server.schematics.clone();
+        let file_name = name.clone();
+        let loaded = tokio::task::spawn_blocking(move || schematics.load(&file_name))
+            .await
+            .map_err(|e| Error::InvalidMap(e.to_string()))
+            .and_then(|result| result);
+        let schematic = match loaded {
+            Ok(schematic) => schematic,
+            Err(e) => {
+                eprintln!("Error loading schematic {}: {}", name, e);
+                player
+                    .send_message(&format!("&cCould not load {}: {}", name, e))
+                    .await;
+                return;
+            }
+        };
+
+        let map_name = player.current_map.clone();
+        let (x, y, z) = feet_position(player);
+        let (dx, dy, dz) = schematic.offset;
+        let at = (i32::from(x) + dx, i32::from(y) + dy, i32::from(z) + dz);
+        let changed = match self.server.maps.get_mut(&map_name) {
+            Some(mut map) => schematic.paste(&mut map, at),
+            None => return,
+        };
+        println!(
+            "{} pasted {} in {} ({} blocks changed)",
+            player.get_name(),
+            name,
+            map_name,
+            changed.len()
+        );
+
+        if changed.len() <= PASTE_BLOCK_UPDATES {
+            for (x, y, z, block) in changed.iter().copied() {
+                self.packet_queue
+                    .enqueue_to_map(None, &map_name, &UpdateSetBlockPacket::new(x, y, z, block))
+                    .await;
+            }
+        } else {
+            let on_map: Vec<ConnectionId> = self
+                .server
+                .connected_players
+                .iter()
+                .filter(|other| other.current_map == map_name)
+                .map(|other| other.get_connection_id())
+                .collect();
+            for connection_id in on_map {
+                self.change_map(connection_id, &map_name).await;
+            }
+        }
+        player
+            .send_message(&format!(
+                "&ePasted {} ({} blocks changed)",
+                name,
+                changed.len()
+            ))
+            .await;
+    }
+}
+
+// the block the player stands in, positions are in 1/32 of a block with y
+// at eye height
+fn feet_position(player: &Player) -> (i16, i16, i16) {
+    (
+        player.x.div_euclid(32),
+        player.y.saturating_sub(51).div_euclid(32),
+        player.z.div_euclid(32),
+    )
+}
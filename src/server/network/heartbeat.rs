@@ -1,47 +1,261 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
 use reqwest::Client;
+use tokio::sync::Mutex;
 use tokio::time::sleep;
 
-use crate::server::{config::Config, server::Server};
+use crate::server::error::{Error, Result};
+use crate::server::server::Server;
 
-const HEARTBEAT_INTERVAL: u64 = 45;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(45);
+// first retry after a failed heartbeat, doubled on every further failure
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
 const SERVER_SOFTWARE: &str = "&eDANDELION &70.0.1";
 
+// everything a server list wants to know, read fresh for every heartbeat
+#[derive(Debug, Clone)]
+pub struct HeartbeatParams {
+    pub port: u16,
+    pub max_players: u32,
+    pub name: String,
+    pub public: bool,
+    pub salt: String,
+    pub users: usize,
+    pub web: bool,
+}
+
+impl HeartbeatParams {
+    pub fn from_server(server: &Server, port: u16) -> Self {
+        let config = &server.config;
+        Self {
+            port,
+            max_players: config.max_players,
+            name: config.name.clone(),
+            public: config.public,
+            salt: server.salt.clone(),
+            users: server.connected_players.len(),
+            web: server.accepts_websockets(),
+        }
+    }
+
+    fn query(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("port", self.port.to_string()),
+            ("max", self.max_players.to_string()),
+            ("name", self.name.clone()),
+            ("public", self.public.to_string()),
+            ("version", "7".to_string()),
+            ("salt", self.salt.clone()),
+            ("users", self.users.to_string()),
+            ("software", SERVER_SOFTWARE.to_string()),
+            ("web", self.web.to_string()),
+        ]
+    }
+}
+
+// play urls handed back by each list, written to play_url_file whenever one changes
+type PlayUrls = Mutex<BTreeMap<String, String>>;
+
 pub async fn start_heartbeat_loop(server: Arc<Server>) {
-    loop {
-        let server_clone = server.clone();
-        if let Err(er) = send_heartbeats(server_clone).await {
-            println!("ERROR: error sending heartbeats: {}", er);
+    let client = match Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("ERROR: could not create heartbeat client: {}", e);
+            return;
         }
-        sleep(Duration::from_secs(HEARTBEAT_INTERVAL)).await;
+    };
+    let Some(port) = server
+        .config
+        .public_listener()
+        .map(|listener| listener.addr.port())
+    else {
+        println!("Not sending heartbeats, every listener is lan only");
+        return;
+    };
+    let play_urls: Arc<PlayUrls> = Arc::new(Mutex::new(BTreeMap::new()));
+
+    // every list gets its own loop so one being down doesn't delay the others
+    let loops: Vec<_> = server
+        .config
+        .heartbeat_urls
+        .iter()
+        .map(|url| {
+            tokio::spawn(heartbeat_loop(
+                Arc::clone(&server),
+                port,
+                client.clone(),
+                url.clone(),
+                Arc::clone(&play_urls),
+            ))
+        })
+        .collect();
+    for heartbeat in loops {
+        let _ = heartbeat.await;
+    }
+}
+
+async fn heartbeat_loop(
+    server: Arc<Server>,
+    port: u16,
+    client: Client,
+    url: String,
+    play_urls: Arc<PlayUrls>,
+) {
+    let mut failures = 0;
+    loop {
+        let params = HeartbeatParams::from_server(&server, port);
+        let delay = match send_heartbeat(&client, &url, &params).await {
+            Ok(play_url) => {
+                failures = 0;
+                record_play_url(&server, &play_urls, &url, play_url).await;
+                HEARTBEAT_INTERVAL
+            }
+            Err(e) => {
+                let delay = retry_delay(failures);
+                failures += 1;
+                println!(
+                    "ERROR: heartbeat to {} failed: {}, retrying in {}s",
+                    url,
+                    e,
+                    delay.as_secs()
+                );
+                delay
+            }
+        };
+        sleep(delay).await;
     }
 }
 
-async fn send_heartbeats(server: Arc<Server>) -> Result<(), reqwest::Error> {
-    let client = Client::new();
-    let config = server.config.clone();
+fn retry_delay(failures: u32) -> Duration {
+    RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(failures))
+        .min(MAX_RETRY_DELAY)
+}
+
+// returns the play url the list answered with
+pub async fn send_heartbeat(
+    client: &Client,
+    url: &str,
+    params: &HeartbeatParams,
+) -> Result<String> {
     let response = client
-        .get(server.config.heartbeat_url.clone())
-        .query(&[
-            ("port", config.port.to_string()),
-            ("max", config.max_players.to_string()),
-            ("name", config.name.clone()),
-            ("public", config.public.to_string()),
-            ("version", "7".to_string()),
-            ("salt", server.salt.clone()),
-            ("users", server.connected_players.len().to_string()),
-            ("software", SERVER_SOFTWARE.to_string()),
-            ("web", "false".to_string()),
-        ])
+        .get(url)
+        .query(&params.query())
         .send()
-        .await?;
+        .await
+        .map_err(|e| Error::Heartbeat(e.to_string()))?;
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .map_err(|e| Error::Heartbeat(e.to_string()))?;
+    let body = body.trim();
+
+    // lists answer with the play url on success and an error message otherwise,
+    // sometimes with a 200 status
+    if !status.is_success() || !body.starts_with("http") {
+        return Err(Error::Heartbeat(format!("{}: {}", status, body)));
+    }
+    Ok(body.to_string())
+}
+
+async fn record_play_url(server: &Server, play_urls: &PlayUrls, list: &str, play_url: String) {
+    let mut play_urls = play_urls.lock().await;
+    if play_urls.get(list) == Some(&play_url) {
+        return;
+    }
+    println!("Play url: {}", play_url);
+    play_urls.insert(list.to_string(), play_url);
+
+    let contents: String = play_urls
+        .values()
+        .map(|play_url| format!("{}\n", play_url))
+        .collect();
+    let path = &server.config.play_url_file;
+    if let Err(e) = tokio::fs::write(path, contents).await {
+        eprintln!("Failed to write play url to {}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
 
-    if response.status().is_success() {
-        let response_body = response.text().await?;
-    } else {
-        println!("Heartbeat ERROR: {}", response.status());
+    // answers a single request with `status` and `body`, and hands back the request line
+    async fn stub(
+        status: &'static str,
+        body: &'static str,
+    ) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/server/heartbeat", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            let request = String::from_utf8_lossy(&request).to_string();
+            request.lines().next().unwrap_or_default().to_string()
+        });
+        (url, handle)
     }
 
-    Ok(())
+    fn params() -> HeartbeatParams {
+        HeartbeatParams {
+            port: 25565,
+            max_players: 64,
+            name: "test server".to_string(),
+            public: true,
+            salt: "abc".to_string(),
+            users: 3,
+            web: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn returns_play_url_and_sends_counts() {
+        let (url, request) = stub("200 OK", "http://www.classicube.net/server/play/abc/\n").await;
+        let play_url = send_heartbeat(&Client::new(), &url, &params())
+            .await
+            .unwrap();
+        assert_eq!(play_url, "http://www.classicube.net/server/play/abc/");
+
+        let request = request.await.unwrap();
+        assert!(request.contains("users=3"), "{}", request);
+        assert!(request.contains("web=true"), "{}", request);
+        assert!(request.contains("name=test+server"), "{}", request);
+    }
+
+    #[tokio::test]
+    async fn error_bodies_are_failures() {
+        let (url, _) = stub("200 OK", "{\"errors\":[[\"Invalid salt\"]]}").await;
+        assert!(send_heartbeat(&Client::new(), &url, &params())
+            .await
+            .is_err());
+
+        let (url, _) = stub("503 Service Unavailable", "").await;
+        assert!(send_heartbeat(&Client::new(), &url, &params())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn retry_delay_backs_off() {
+        assert_eq!(retry_delay(0), Duration::from_secs(5));
+        assert_eq!(retry_delay(3), Duration::from_secs(40));
+        assert_eq!(retry_delay(40), MAX_RETRY_DELAY);
+    }
 }
@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+
+use crate::server::game::player_registry::ConnectionId;
+
+// connections waiting for a free slot, in the order they arrived. queued
+// connections wait on `changed` and try to get in whenever a slot opens
+pub struct JoinQueue {
+    queue: Mutex<VecDeque<ConnectionId>>,
+    changed: Notify,
+}
+
+impl JoinQueue {
+    pub fn new() -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    // returns the 1-based position, or none if the queue already holds `capacity` connections
+    pub fn push(&self, connection_id: ConnectionId, capacity: usize) -> Option<usize> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= capacity {
+            return None;
+        }
+        queue.push_back(connection_id);
+        Some(queue.len())
+    }
+
+    pub fn position(&self, connection_id: ConnectionId) -> Option<usize> {
+        let queue = self.queue.lock().unwrap();
+        queue
+            .iter()
+            .position(|id| *id == connection_id)
+            .map(|index| index + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.lock().unwrap().is_empty()
+    }
+
+    pub fn remove(&self, connection_id: ConnectionId) {
+        let mut queue = self.queue.lock().unwrap();
+        let before = queue.len();
+        queue.retain(|id| *id != connection_id);
+        if queue.len() != before {
+            drop(queue);
+            self.notify();
+        }
+    }
+
+    pub fn notify(&self) {
+        self.changed.notify_waiters();
+    }
+
+    pub fn changed(&self) -> Notified<'_> {
+        self.changed.notified()
+    }
+}
+
+impl Default for JoinQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_arrival_order() {
+        let queue = JoinQueue::new();
+        assert!(queue.is_empty());
+        assert_eq!(queue.push(7, 3), Some(1));
+        assert_eq!(queue.push(3, 3), Some(2));
+        assert_eq!(queue.push(9, 3), Some(3));
+        assert_eq!(queue.push(1, 3), None);
+        assert_eq!(queue.position(7), Some(1));
+        assert_eq!(queue.position(3), Some(2));
+        assert_eq!(queue.position(9), Some(3));
+        assert_eq!(queue.position(1), None);
+    }
+
+    #[test]
+    fn moves_everyone_up_when_someone_leaves() {
+        let queue = JoinQueue::new();
+        for connection_id in [1, 2, 3, 4] {
+            queue.push(connection_id, 10);
+        }
+        queue.remove(2);
+        assert_eq!(queue.position(1), Some(1));
+        assert_eq!(queue.position(3), Some(2));
+        assert_eq!(queue.position(4), Some(3));
+        queue.remove(1);
+        assert_eq!(queue.position(3), Some(1));
+        // leaving twice changes nothing
+        queue.remove(1);
+        assert_eq!(queue.position(4), Some(2));
+        // a slot in the queue opened up again
+        assert_eq!(queue.push(5, 3), Some(3));
+    }
+
+    #[tokio::test]
+    async fn wakes_waiters_when_someone_leaves() {
+        let queue = JoinQueue::new();
+        queue.push(1, 10);
+        queue.push(2, 10);
+        let changed = queue.changed();
+        tokio::pin!(changed);
+        changed.as_mut().enable();
+        queue.remove(1);
+        tokio::time::timeout(std::time::Duration::from_secs(1), changed)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+
+use crate::server::config::ListenerConfig;
+
+const BACKLOG: i32 = 1024;
+
+// ipv6 listeners take ipv4 connections as well, unless some other listener
+// already has ipv4 on that port
+pub fn bind_all(listeners: &[ListenerConfig]) -> io::Result<Vec<TcpListener>> {
+    listeners
+        .iter()
+        .map(|listener| {
+            let addr = listener.addr;
+            let v6_only = addr.is_ipv6()
+                && listeners
+                    .iter()
+                    .any(|other| other.addr.is_ipv4() && other.addr.port() == addr.port());
+            bind(addr, v6_only)
+        })
+        .collect()
+}
+
+fn bind(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+// loopback and private ranges, the only peers a lan only listener lets in
+pub fn is_lan_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_lan_address(IpAddr::V4(ip)),
+            // fc00::/7 is unique local, fe80::/10 link local
+            None => {
+                ip.is_loopback()
+                    || (ip.segments()[0] & 0xfe00) == 0xfc00
+                    || (ip.segments()[0] & 0xffc0) == 0xfe80
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lan_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "192.168.1.20",
+            "10.1.2.3",
+            "::1",
+            "fd12::1",
+            "fe80::1",
+            "::ffff:192.168.0.1",
+        ] {
+            assert!(is_lan_address(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "203.0.113.7", "2001:db8::1", "::ffff:8.8.8.8"] {
+            assert!(!is_lan_address(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn dual_stack_takes_both_families() {
+        let listeners = bind_all(&[ListenerConfig {
+            addr: "[::]:0".parse().unwrap(),
+            lan_only: false,
+            websocket: false,
+        }])
+        .unwrap();
+        let port = listeners[0].local_addr().unwrap().port();
+
+        let v4 = tokio::net::TcpStream::connect(("127.0.0.1", port)).await;
+        assert!(v4.is_ok());
+        let (_, peer) = listeners[0].accept().await.unwrap();
+        assert!(peer.ip().to_canonical().is_ipv4());
+    }
+}
@@ -1,5 +1,12 @@
+mod commands;
 pub mod heartbeat;
+pub mod join_queue;
+pub mod listener;
 pub mod packet;
 pub mod packet_resolver;
 pub mod packet_stream;
 pub mod packets;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod session;
+pub mod websocket;
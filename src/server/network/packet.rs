@@ -1,17 +1,29 @@
-use std::sync::Arc;
+use bytes::Bytes;
 
-use async_trait::async_trait;
-use tokio::{io::WriteHalf, net::TcpStream, sync::Mutex};
+use crate::server::error::Result;
 
 use super::packet_stream::{packet_reader::PacketReader, packet_writer::PacketWriter};
 
-#[async_trait]
-pub trait PacketTrait: Send + Sync {
-    fn packet_id(&self) -> u8;
-    fn write(&mut self, writer: &mut PacketWriter);
-    fn read(&mut self, reader: &mut PacketReader);
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+// a packet only knows how to turn itself into bytes and back, sending is
+// done by whoever owns the socket so a broadcast encodes once
+pub trait Packet: Sized {
+    const ID: u8;
+    // full frame length on the wire, packet id included
+    const SIZE: usize;
+
+    fn write(&self, writer: &mut PacketWriter);
+    fn read(reader: &mut PacketReader) -> Result<Self>;
+
+    fn encode(&self) -> Bytes {
+        let mut writer = PacketWriter::new();
+        writer.write_byte(Self::ID);
+        self.write(&mut writer);
+        writer.into_bytes()
+    }
+
+    fn decode(frame: &[u8]) -> Result<Self> {
+        let mut reader = PacketReader::new(frame);
+        reader.read_byte()?;
+        Self::read(&mut reader)
+    }
 }
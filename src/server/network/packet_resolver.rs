@@ -1,59 +1,94 @@
-use super::packet::PacketTrait;
-use super::packet_stream::packet_reader::PacketReader;
-use super::packet_stream::packet_writer::PacketWriter;
+use super::join_queue::JoinQueue;
+use super::packet::Packet;
 use super::packets::clientbound::{
-    DespawnPlayerPacket, LevelDataChunkPacket, LevelFinalizePacket, LevelInitializePacket,
-    PingPacket, UpdateSetBlockPacket,
+    DespawnPlayerPacket, DisconnectPlayerPacket, ExtInfoPacket as ServerExtInfoPacket, PingPacket,
+    SendMessagePacket, ServerIdentificationPacket, SpawnPlayerPacket, UpdateSetBlockPacket,
 };
 use super::packets::serverbound::{
-    MessagePacket, PlayerIndentificationPacket, PositionAndOrientationUpdatePacket, SetBlockPacket,
-};
-use crate::server::game::dmf_map::DmfMap;
-use crate::server::game::player::Player;
-use crate::server::network::packets::clientbound::{
-    SendMessagePacket, SetPositionAndOrientationPacket, SpawnPlayerPacket,
+    ExtEntryPacket, ExtInfoPacket, MessagePacket, PlayerIndentificationPacket,
+    PositionAndOrientationUpdatePacket, SetBlockPacket,
 };
+use super::session::{ClientSocket, ConnectionState, Session};
+use crate::server::config::DuplicateLoginPolicy;
+use crate::server::error::Result;
+use crate::server::game::entity_ids::{EntityId, WireIds};
+use crate::server::game::movement::{movement_packet, EntityPosition};
+use crate::server::game::player::{Player, Rank};
+use crate::server::game::player_registry::ConnectionId;
+use crate::server::game::visibility::{can_see, distance_squared};
 use crate::server::server::Server;
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use std::collections::VecDeque;
-use std::fmt::format;
-use std::io::prelude::*;
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{self, AsyncWriteExt, WriteHalf};
-use tokio::net::TcpStream;
+use tokio::io::AsyncWriteExt;
 use tokio::sync::RwLock;
-use tokio::time::sleep;
+use tokio::time::{interval, sleep, MissedTickBehavior};
 
 const PING_TIME_MILLIS: u64 = 100;
 const PACKET_FLUSH_MILLIS: u64 = 20;
+// sent in the unused byte of the identification packet by clients that speak cpe
+const CPE_MAGIC: u8 = 0x42;
+const APP_NAME: &str = "dandelion 0.0.1";
+
+pub struct QueuedPacket {
+    // the connection that should not receive it
+    pub owner: Option<ConnectionId>,
+    // only players on this map get it, everyone if unset
+    pub map: Option<String>,
+    pub data: Bytes,
+}
 
 pub struct PacketQueue {
-    queue: Arc<RwLock<VecDeque<(Option<i8>, Box<dyn PacketTrait>)>>>,
+    queue: RwLock<VecDeque<QueuedPacket>>,
 }
 
 impl PacketQueue {
     pub fn new() -> Self {
         Self {
-            queue: Arc::new(RwLock::new(VecDeque::new())),
+            queue: RwLock::new(VecDeque::new()),
         }
     }
 
-    pub async fn enqueue(&self, owner_id: Option<i8>, packet: Box<dyn PacketTrait>) {
+    pub async fn enqueue(&self, owner_id: Option<ConnectionId>, packet: &impl Packet) {
+        let mut queue = self.queue.write().await;
+        queue.push_back(QueuedPacket {
+            owner: owner_id,
+            map: None,
+            data: packet.encode(),
+        });
+    }
+
+    pub async fn enqueue_to_map(
+        &self,
+        owner_id: Option<ConnectionId>,
+        map: &str,
+        packet: &impl Packet,
+    ) {
         let mut queue = self.queue.write().await;
-        queue.push_back((owner_id, packet));
+        queue.push_back(QueuedPacket {
+            owner: owner_id,
+            map: Some(map.to_string()),
+            data: packet.encode(),
+        });
     }
 
-    pub async fn dequeue(&self) -> Option<(Option<i8>, Box<dyn PacketTrait>)> {
+    pub async fn dequeue(&self) -> Option<QueuedPacket> {
         let mut queue = self.queue.write().await;
         queue.pop_front()
     }
 }
 
+impl Default for PacketQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct PacketResolver {
     pub server: Arc<Server>,
     pub packet_queue: PacketQueue,
+    pub join_queue: JoinQueue,
 }
 
 impl PacketResolver {
@@ -61,52 +96,381 @@ impl PacketResolver {
         Self {
             server,
             packet_queue: PacketQueue::new(),
+            join_queue: JoinQueue::new(),
         }
     }
 
-    pub async fn handle_packet(&self, data: &[u8], socket: Arc<RwLock<WriteHalf<TcpStream>>>) {
-        if data.is_empty() {
+    // frame is a single complete packet, id byte included
+    pub async fn handle_packet(&self, session: &mut Session, frame: &[u8]) {
+        if frame.is_empty() {
             eprintln!("Empty data received");
             return;
         }
 
-        let packet_id = data[0];
-        let mut reader = PacketReader::new(data);
+        let packet_id = frame[0];
+        if !session.state.accepts(packet_id) {
+            eprintln!(
+                "Connection {} sent packet {:#04x} while {:?}",
+                session.connection_id, packet_id, session.state
+            );
+            self.kick_session(session, "Unexpected packet").await;
+            return;
+        }
 
+        // a broken packet only costs its own connection
+        if let Err(e) = self.dispatch(session, packet_id, frame).await {
+            eprintln!(
+                "Connection {} sent a malformed packet {:#04x}: {}",
+                session.connection_id, packet_id, e
+            );
+            self.kick_session(session, "Malformed packet").await;
+        }
+    }
+
+    async fn dispatch(&self, session: &mut Session, packet_id: u8, frame: &[u8]) -> Result<()> {
         match packet_id {
-            0x00 => self.player_connect(&mut reader, socket).await,
-            0x05 => self.handle_set_block(&mut reader, socket).await,
-            0x08 => {
-                self.handle_position_and_orientation(&mut reader, socket)
+            PlayerIndentificationPacket::ID => {
+                self.handle_identification(session, PlayerIndentificationPacket::decode(frame)?)
+                    .await
+            }
+            ExtInfoPacket::ID => {
+                self.handle_ext_info(session, ExtInfoPacket::decode(frame)?)
+                    .await
+            }
+            ExtEntryPacket::ID => {
+                self.handle_ext_entry(session, ExtEntryPacket::decode(frame)?)
+                    .await
+            }
+            SetBlockPacket::ID => {
+                self.handle_set_block(session.connection_id, SetBlockPacket::decode(frame)?)
+                    .await
+            }
+            PositionAndOrientationUpdatePacket::ID => {
+                self.handle_position_and_orientation(
+                    session.connection_id,
+                    PositionAndOrientationUpdatePacket::decode(frame)?,
+                )
+                .await
+            }
+            MessagePacket::ID => {
+                self.handle_message(session.connection_id, MessagePacket::decode(frame)?)
                     .await
             }
-            0x0d => self.handle_message(&mut reader, socket).await,
             _ => println!("Unknown packet ID: {}", packet_id),
         }
+        Ok(())
+    }
+
+    async fn handle_identification(
+        &self,
+        session: &mut Session,
+        packet: PlayerIndentificationPacket,
+    ) {
+        if self.server.config.do_user_auth && !session.skip_auth && !self.is_verified(&packet) {
+            println!("{} failed to verify their name", packet.username);
+            self.kick_session(session, "Login failed! Close the game and sign in again.")
+                .await;
+            return;
+        }
+
+        if packet.unused != CPE_MAGIC {
+            self.finish_login(session, packet).await;
+            return;
+        }
+
+        // we don't support any extension yet, but cpe clients still expect
+        // the negotiation to happen before the level is sent
+        let ext_info = ServerExtInfoPacket::new(APP_NAME.to_string(), 0);
+        if let Err(e) = session
+            .socket
+            .write()
+            .await
+            .write_all(&ext_info.encode())
+            .await
+        {
+            eprintln!("Error sending ext info: {}", e);
+        }
+        session.identification = Some(packet);
+        session.state = ConnectionState::NegotiatingCpe;
+    }
+
+    // the server list hands clients md5(salt + name) as their key, some send
+    // it without leading zeros
+    fn is_verified(&self, packet: &PlayerIndentificationPacket) -> bool {
+        let expected = format!(
+            "{:x}",
+            md5::compute(format!("{}{}", self.server.salt, packet.username))
+        );
+        let key = packet.verification_key.trim();
+        !key.is_empty()
+            && key
+                .trim_start_matches('0')
+                .eq_ignore_ascii_case(expected.trim_start_matches('0'))
+    }
+
+    async fn handle_ext_info(&self, session: &mut Session, packet: ExtInfoPacket) {
+        if session.remaining_extensions.is_some() {
+            self.kick_session(session, "Unexpected packet").await;
+            return;
+        }
+        session.remaining_extensions = Some(packet.extension_count.max(0));
+        self.finish_negotiation_if_done(session).await;
+    }
+
+    // entries only count once ExtInfo said how many there are
+    async fn handle_ext_entry(&self, session: &mut Session, packet: ExtEntryPacket) {
+        let Some(remaining) = session.remaining_extensions.filter(|n| *n > 0) else {
+            self.kick_session(session, "Unexpected packet").await;
+            return;
+        };
+        session.extensions.push(packet);
+        session.remaining_extensions = Some(remaining - 1);
+        self.finish_negotiation_if_done(session).await;
+    }
+
+    async fn finish_negotiation_if_done(&self, session: &mut Session) {
+        if session.remaining_extensions != Some(0) {
+            return;
+        }
+        if let Some(identification) = session.identification.take() {
+            self.finish_login(session, identification).await;
+        }
+    }
+
+    async fn finish_login(&self, session: &mut Session, packet: PlayerIndentificationPacket) {
+        let rank = self.server.config.rank_of(&packet.username);
+        // taking over an existing session doesn't need another slot
+        let replacing = self
+            .server
+            .connected_players
+            .find_by_name(&packet.username)
+            .is_some();
+        // a free slot goes to whoever is first in the queue, only staff who
+        // can take a reserved slot get to walk past the queue
+        let may_skip_queue =
+            self.join_queue.is_empty() || rank.is_staff() && self.server.config.reserved_slots > 0;
+        if replacing || (may_skip_queue && self.has_free_slot(rank)) {
+            self.login(session, packet, rank).await;
+            return;
+        }
+
+        let queue_size = self.server.config.join_queue_size as usize;
+        let position = if queue_size > 0 {
+            self.join_queue.push(session.connection_id, queue_size)
+        } else {
+            None
+        };
+        let Some(position) = position else {
+            println!("{} tried to join but the server is full", packet.username);
+            self.kick_session(session, "Server is full!").await;
+            return;
+        };
+
+        println!("{} is queued at position {}", packet.username, position);
+        session.identification = Some(packet);
+        session.state = ConnectionState::Queued;
+        self.send_queue_position(session, position).await;
+    }
+
+    // called by the read loop of a queued connection whenever the queue
+    // changes or a while has passed
+    pub async fn update_queued(&self, session: &mut Session) {
+        let Some(position) = self.join_queue.position(session.connection_id) else {
+            return;
+        };
+        let Some(packet) = session.identification.as_ref() else {
+            return;
+        };
+        let rank = self.server.config.rank_of(&packet.username);
+
+        if position == 1 && self.has_free_slot(rank) {
+            self.join_queue.remove(session.connection_id);
+            if let Some(packet) = session.identification.take() {
+                self.login(session, packet, rank).await;
+            }
+            return;
+        }
+
+        if position != session.queue_position {
+            self.send_queue_position(session, position).await;
+        } else {
+            // keeps the client from timing out while it waits
+            let _ = session
+                .socket
+                .write()
+                .await
+                .write_all(&PingPacket.encode())
+                .await;
+        }
+    }
+
+    // staff can take the reserved slots, everyone else has to leave them free
+    fn has_free_slot(&self, rank: Rank) -> bool {
+        let config = &self.server.config;
+        let limit = if rank.is_staff() {
+            config.max_players
+        } else {
+            config.max_players.saturating_sub(config.reserved_slots)
+        };
+        self.server.connected_players.len() < limit as usize
+    }
+
+    async fn send_queue_position(&self, session: &mut Session, position: usize) {
+        session.queue_position = position;
+        let identification = ServerIdentificationPacket::new(
+            self.server.config.name.clone(),
+            format!("Server is full, you are #{} in the queue", position),
+        );
+        if let Err(e) = session
+            .socket
+            .write()
+            .await
+            .write_all(&identification.encode())
+            .await
+        {
+            eprintln!("Error sending queue position: {}", e);
+        }
+    }
+
+    async fn login(&self, session: &mut Session, packet: PlayerIndentificationPacket, rank: Rank) {
+        let previous = match self.server.connected_players.find_by_name(&packet.username) {
+            Some(existing) => match self.server.config.duplicate_login {
+                DuplicateLoginPolicy::RejectNew => {
+                    println!("{} is already logged in, rejecting", packet.username);
+                    self.kick_session(session, "You are already logged in!")
+                        .await;
+                    return;
+                }
+                DuplicateLoginPolicy::KickOld => self.take_over(existing).await,
+            },
+            None => None,
+        };
+
+        let player_id = self.server.allocate_entity_id();
+        session.state = ConnectionState::LoadingLevel;
+        self.player_connect(
+            session.connection_id,
+            player_id,
+            packet,
+            rank,
+            Arc::clone(&session.socket),
+            previous,
+        )
+        .await;
+        session.state = ConnectionState::Playing;
+    }
+
+    // kicks the session currently playing under a name and hands back its
+    // player so the new session can continue from there
+    async fn take_over(&self, connection_id: ConnectionId) -> Option<Player> {
+        let previous = self.server.connected_players.remove(connection_id)?;
+        println!(
+            "{} logged in from another location, replacing connection {}",
+            previous.get_name(),
+            connection_id
+        );
+
+        let disconnect = DisconnectPlayerPacket::new("Logged in from another location".to_string());
+        {
+            let mut socket = previous.socket.write().await;
+            let _ = socket.write_all(&disconnect.encode()).await;
+            let _ = socket.shutdown().await;
+        }
+        // the old read loop ends on its own, its disconnect finds nothing to remove
+        self.despawn_player(previous.get_id()).await;
+        Some(previous)
+    }
+
+    // kicks a connection that hasn't become a player yet (or is about to stop being one)
+    pub async fn kick_session(&self, session: &mut Session, reason: &str) {
+        session.state = ConnectionState::Disconnecting;
+        let disconnect = DisconnectPlayerPacket::new(reason.to_string());
+        let mut socket = session.socket.write().await;
+        let _ = socket.write_all(&disconnect.encode()).await;
+        let _ = socket.shutdown().await;
+    }
+
+    // kicks a logged in player from anywhere, their read loop notices the
+    // closed socket and ends on its own
+    pub async fn kick(&self, connection_id: ConnectionId, reason: &str) {
+        let Some(socket) = self
+            .server
+            .connected_players
+            .get(connection_id)
+            .map(|player| Arc::clone(&player.socket))
+        else {
+            return;
+        };
+
+        let disconnect = DisconnectPlayerPacket::new(reason.to_string());
+        {
+            let mut socket = socket.write().await;
+            let _ = socket.write_all(&disconnect.encode()).await;
+            let _ = socket.shutdown().await;
+        }
+        self.disconnect(connection_id).await;
+    }
+
+    // removes whatever player belongs to the connection and tells everyone,
+    // safe to call more than once
+    pub async fn disconnect(&self, connection_id: ConnectionId) {
+        let Some(player) = self.server.connected_players.remove(connection_id) else {
+            return;
+        };
+        println!("Player {} disconnected", player.get_name());
+        self.join_queue.notify();
+        self.despawn_player(player.get_id()).await;
+
+        let leave_message = format!("goodbye {}", player.get_name());
+        self.send_packet_to_all(None, &SendMessagePacket::new(-1, leave_message))
+            .await;
     }
 
     async fn player_connect(
         &self,
-        reader: &mut PacketReader<'_>,
-        socket: Arc<RwLock<WriteHalf<TcpStream>>>,
+        connection_id: ConnectionId,
+        player_id: EntityId,
+        packet: PlayerIndentificationPacket,
+        rank: Rank,
+        socket: ClientSocket,
+        previous: Option<Player>,
     ) {
-        let packet = self.read_player_identification_packet(reader);
-        let player_id = self.get_last_id().await;
+        // a replaced session picks up on the same map and spot, unless that
+        // map is gone by now
+        let (map_name, resume_at) = match &previous {
+            Some(previous) if self.server.maps.contains(&previous.current_map) => {
+                (previous.current_map.clone(), Some(previous.position()))
+            }
+            _ => (self.server.config.default_map.clone(), None),
+        };
+
         let player = self
-            .create_player(player_id, packet.username.clone(), socket.clone())
-            .await;
-        self.add_player_to_server(player_id, player.clone()).await;
-        self.send_server_identification(packet, player.socket.clone())
-            .await;
-        self.load_default_map_and_send_to_player(player.clone())
+            .create_player(
+                connection_id,
+                player_id,
+                packet.username.clone(),
+                rank,
+                map_name.clone(),
+                socket,
+            )
             .await;
+        self.add_player_to_server(player.clone()).await;
+        self.send_server_identification(&player).await;
+        self.send_map(player.clone(), &map_name, resume_at).await;
+
+        if previous.is_some() {
+            println!("{} reconnected", player.get_name());
+            player
+                .send_message("&eYou were logged in elsewhere, that session was closed")
+                .await;
+            return;
+        }
 
         println!("{} connected", player.get_name());
 
         let join_message = format!("welcome {}!", player.get_name());
-        for player in self.server.connected_players.iter() {
-            player.send_message(&join_message).await;
-        }
+        self.send_packet_to_all(None, &SendMessagePacket::new(-1, join_message))
+            .await;
 
         player.send_message("&fwelcome to this silly server!").await;
         player
@@ -121,122 +485,144 @@ impl PacketResolver {
             .await;
     }
 
-    fn read_player_identification_packet(
-        &self,
-        reader: &mut PacketReader<'_>,
-    ) -> PlayerIndentificationPacket {
-        let mut packet = PlayerIndentificationPacket::new(
-            self.server.config.name.clone(),
-            self.server.config.motd.clone(),
-        );
-        packet.read(reader);
-        packet
-    }
-
     async fn create_player(
         &self,
-        player_id: i8,
+        connection_id: ConnectionId,
+        player_id: EntityId,
         username: String,
-        socket: Arc<RwLock<WriteHalf<TcpStream>>>,
+        rank: Rank,
+        map_name: String,
+        socket: ClientSocket,
     ) -> Player {
         Player::new(
+            connection_id,
             player_id,
             username,
-            "default".to_string(),
+            rank,
+            map_name,
             Arc::clone(&socket),
         )
     }
 
-    async fn add_player_to_server(&self, player_id: i8, player: Player) {
-        self.server.connected_players.insert(player_id, player);
+    async fn add_player_to_server(&self, player: Player) {
+        self.server.connected_players.insert(player);
+    }
+
+    async fn send_server_identification(&self, player: &Player) {
+        let server_identification = ServerIdentificationPacket::new(
+            self.server.config.name.clone(),
+            self.server.config.motd.clone(),
+        );
+
+        if let Err(e) = player.send_packet(&server_identification).await {
+            eprintln!("Error resolving server identification: {}", e);
+        }
     }
 
-    async fn send_server_identification(
+    // sends the level and places the player at its spawn, or at `resume_at`
+    async fn send_map(
         &self,
-        packet: PlayerIndentificationPacket,
-        socket: Arc<RwLock<WriteHalf<TcpStream>>>,
-    ) {
-        let resolve_result = {
-            let mut socket = socket.write().await;
-            packet.resolve(&mut socket).await
+        mut player: Player,
+        map_name: &str,
+        resume_at: Option<EntityPosition>,
+    ) -> bool {
+        let map = match self.server.maps.get(map_name).await {
+            Ok(map) => map,
+            Err(e) => {
+                eprintln!("Error: map '{}' could not be loaded: {}", map_name, e);
+                return false;
+            }
         };
 
-        if let Err(e) = resolve_result {
-            eprintln!("Error resolving server identification: {}", e);
+        if let Err(e) = player.send_to_level(&map).await {
+            eprintln!("Error sending level to {}: {}", player.get_name(), e);
+            return false;
         }
+        let position = resume_at.unwrap_or_else(|| map.spawn_position());
+        self.spawn_player(&mut player, position).await;
+        true
     }
 
-    async fn load_default_map_and_send_to_player(&self, mut player: Player) {
-        let default_map_name = self.server.config.default_map.clone();
-        let map = self
-            .server
-            .loaded_maps
-            .get(&default_map_name)
-            .map(|e| e.clone());
-
-        if let Some(mut map) = map {
-            player.send_to_level(&map).await;
-            map.set_spawn_point(4718, 1171, 4166);
-            self.spawn_player(&mut player, &map).await;
-        } else {
-            eprintln!("Error: Default map '{}' not found.", default_map_name);
+    // moves a player to another map: everyone on the old map loses them,
+    // they get the new level and the tracking loop spawns them there
+    pub async fn change_map(&self, connection_id: ConnectionId, map_name: &str) -> bool {
+        // load before touching the player so a broken map leaves them where they are
+        if let Err(e) = self.server.maps.load(map_name).await {
+            eprintln!("Error: map '{}' could not be loaded: {}", map_name, e);
+            return false;
+        }
+        let Some((player, wire_ids)) =
+            self.server
+                .connected_players
+                .update(connection_id, |player| {
+                    player.current_map = map_name.to_string();
+                    player.spawned = false;
+                    let wire_ids: Vec<i8> = player
+                        .entity_ids
+                        .entities()
+                        .filter_map(|entity_id| player.entity_ids.get(entity_id))
+                        .collect();
+                    player.entity_ids = WireIds::new();
+                    (player.clone(), wire_ids)
+                })
+        else {
+            return false;
+        };
+
+        self.despawn_player(player.get_id()).await;
+        for wire_id in wire_ids {
+            let _ = player
+                .send(&DespawnPlayerPacket::new(wire_id).encode())
+                .await;
         }
+        self.send_map(player, map_name, None).await
     }
 
-    async fn spawn_player(&self, player: &mut Player, map: &DmfMap) {
+    // any map in the index, loaded or not
+    pub async fn find_map(&self, name: &str) -> Option<String> {
+        self.server.maps.find(name).await
+    }
+
+    async fn spawn_player(&self, player: &mut Player, position: EntityPosition) {
         player
-            .teleport(map.x_spawn, map.y_spawn, map.z_spawn, 0, 0)
+            .teleport(
+                position.x,
+                position.y,
+                position.z,
+                position.pitch,
+                position.yaw,
+            )
             .await;
 
-        let spawn_player = SpawnPlayerPacket::new(
-            player.get_id(),
-            player.get_name().to_string(),
-            player.x,
-            player.y,
-            player.z,
-            0,
-            0,
-        );
-        self.send_packet_to_all(Some(player), spawn_player).await;
-        self.spawn_online_players(player).await;
-    }
-
-    async fn spawn_online_players(&self, new_player: &Player) {
-        let mut socket = new_player.socket.write().await;
-        for player in self.server.connected_players.iter() {
-            if player.get_id() != new_player.get_id() {
-                let mut spawn_player = SpawnPlayerPacket::new(
-                    player.get_id(),
-                    player.get_name().to_string(),
-                    player.x,
-                    player.y,
-                    player.z,
-                    player.yaw,
-                    player.pitch,
-                );
-                let mut packet_writer = PacketWriter::new();
-                spawn_player.write(&mut packet_writer);
-                spawn_player.resolve(&mut socket).await;
-            }
-        }
+        // entity_tracking_loop spawns them for whoever is in range
+        self.server
+            .connected_players
+            .update(player.get_connection_id(), |stored| {
+                stored.set_pos(player.x, player.y, player.z, player.pitch, player.yaw);
+                stored.last_broadcast = stored.position();
+                stored.spawned = true;
+            });
     }
 
     async fn handle_set_block(
         &self,
-        reader: &mut PacketReader<'_>,
-        socket: Arc<RwLock<WriteHalf<TcpStream>>>,
+        connection_id: ConnectionId,
+        set_block_packet: SetBlockPacket,
     ) {
-        let player = self
-            .get_player_by_socket(Arc::clone(&socket))
-            .await
-            .unwrap();
-
-        let mut set_block_packet = SetBlockPacket::new();
-        set_block_packet.read(reader);
+        let Some(map_name) = self
+            .server
+            .connected_players
+            .get(connection_id)
+            .filter(|player| player.spawned)
+            .map(|player| player.current_map.clone())
+        else {
+            return;
+        };
 
-        let map_name = "default";
-        let mut maps = self.server.loaded_maps.clone();
-        let mut map = maps.get_mut(map_name).unwrap();
+        let Some(mut map) = self.server.maps.get_mut(&map_name) else {
+            eprintln!("Map '{}' is not loaded, ignoring block change", map_name);
+            return;
+        };
         let block = if set_block_packet.mode == 0x00 {
             0x00
         } else {
@@ -256,158 +642,542 @@ impl PacketResolver {
             set_block_packet.z,
             block,
         );
+        drop(map);
         self.packet_queue
-            .enqueue(Some(player.get_id()), Box::new(update_set_block))
+            .enqueue_to_map(Some(connection_id), &map_name, &update_set_block)
             .await;
     }
 
     async fn handle_position_and_orientation(
         &self,
-        reader: &mut PacketReader<'_>,
-        socket: Arc<RwLock<WriteHalf<TcpStream>>>,
+        connection_id: ConnectionId,
+        position_packet: PositionAndOrientationUpdatePacket,
     ) {
-        let mut player = self
-            .get_player_by_socket(Arc::clone(&socket))
-            .await
-            .unwrap();
+        // only store it, entity_tracking_loop sends it out at its own pace
+        self.server
+            .connected_players
+            .update(connection_id, |player| {
+                player.set_pos(
+                    position_packet.x,
+                    position_packet.y,
+                    position_packet.z,
+                    position_packet.pitch,
+                    position_packet.yaw,
+                )
+            });
+    }
 
-        let mut position_packet = PositionAndOrientationUpdatePacket::new();
-        position_packet.read(reader);
+    async fn handle_message(&self, connection_id: ConnectionId, message_packet: MessagePacket) {
+        if message_packet.message.starts_with('/') {
+            self.handle_command(connection_id, &message_packet.message)
+                .await;
+            return;
+        }
 
-        player.set_pos(
-            position_packet.x,
-            position_packet.y,
-            position_packet.z,
-            position_packet.pitch,
-            position_packet.yaw,
-        );
+        let Some((map_name, message)) =
+            self.server
+                .connected_players
+                .get(connection_id)
+                .map(|player| {
+                    let message = format!("{}: {}", player.get_name(), message_packet.message);
+                    (player.current_map.clone(), message)
+                })
+        else {
+            return;
+        };
 
-        let mut set_position_packet = SetPositionAndOrientationPacket::new(
-            player.get_id(),
-            player.x,
-            player.y,
-            player.z,
-            player.yaw,
-            player.pitch,
-        );
-        self.send_packet_to_all(Some(&player), set_position_packet)
+        // the sender's wire id differs for every viewer, so chat goes out as plain chat
+        let send_message_packet = SendMessagePacket::new(0, message);
+
+        self.packet_queue
+            .enqueue_to_map(None, &map_name, &send_message_packet)
             .await;
     }
 
-    async fn handle_message(
-        &self,
-        reader: &mut PacketReader<'_>,
-        socket: Arc<RwLock<WriteHalf<TcpStream>>>,
-    ) {
-        let mut player = self
-            .get_player_by_socket(Arc::clone(&socket))
-            .await
-            .unwrap();
+    pub async fn send_packet_to_all(&self, owner: Option<&Player>, packet: &impl Packet) {
+        self.send_to_all(
+            owner.map(|owner| owner.get_connection_id()),
+            &packet.encode(),
+        )
+        .await;
+    }
 
-        let mut message_packet = MessagePacket::new();
-        message_packet.read(reader);
+    async fn send_to_all(&self, owner_id: Option<ConnectionId>, data: &Bytes) {
+        self.send_to(owner_id, None, data).await;
+    }
 
-        let message = format!("{}: {}", player.get_name(), message_packet.message);
+    async fn send_to(&self, owner_id: Option<ConnectionId>, map: Option<&str>, data: &Bytes) {
+        let players: Vec<Player> = self
+            .server
+            .connected_players
+            .iter()
+            .filter(|player| Some(player.get_connection_id()) != owner_id)
+            .filter(|player| map.is_none_or(|map| player.current_map == map))
+            .map(|player| player.clone())
+            .collect();
 
-        let mut send_message_packet = SendMessagePacket::new(player.get_id(), message);
+        for player in players {
+            let _ = player.send(data).await;
+        }
+    }
 
-        self.packet_queue
-            .enqueue(None, Box::new(send_message_packet))
-            .await;
+    // removes the entity from every client that has it spawned right away,
+    // so its wire ids can be handed out again without confusing anyone
+    pub async fn despawn_player(&self, entity_id: EntityId) {
+        let viewers: Vec<(Player, i8)> = self
+            .server
+            .connected_players
+            .iter_mut()
+            .filter_map(|mut viewer| {
+                let wire_id = viewer.entity_ids.release(entity_id)?;
+                Some((viewer.clone(), wire_id))
+            })
+            .collect();
+
+        for (viewer, wire_id) in viewers {
+            let _ = viewer
+                .send(&DespawnPlayerPacket::new(wire_id).encode())
+                .await;
+        }
     }
 
-    async fn get_player_by_socket(
-        &self,
-        socket: Arc<RwLock<WriteHalf<TcpStream>>>,
-    ) -> Option<Player> {
-        for player in self.server.connected_players.iter() {
-            if Arc::ptr_eq(&player.socket, &socket) {
-                return Some(player.clone());
+    pub async fn send_to_all_queued(&self) {
+        loop {
+            while let Some(packet) = self.packet_queue.dequeue().await {
+                self.send_to(packet.owner, packet.map.as_deref(), &packet.data)
+                    .await;
             }
+            sleep(Duration::from_millis(PACKET_FLUSH_MILLIS)).await;
         }
-        None
     }
 
-    pub async fn send_packet_to_all(&self, owner: Option<&Player>, mut packet: impl PacketTrait) {
-        let mut packet_writer = PacketWriter::new();
-        packet.write(&mut packet_writer);
+    // keeps every client's view of the other players up to date: spawns and
+    // despawns players as they come in and out of range and sends movement
+    // at most movement_broadcast_rate times a second
+    pub async fn entity_tracking_loop(&self) {
+        let rate = self.server.config.movement_broadcast_rate.clamp(1, 1000) as u64;
+        let view_distance = self.server.config.view_distance;
+        let mut ticker = interval(Duration::from_millis(1000 / rate));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-        for player in self.server.connected_players.iter() {
-            if let Some(owner) = owner {
-                if player.get_id() == owner.get_id() {
-                    continue;
+        loop {
+            ticker.tick().await;
+
+            // movement is worked out once per entity, but the packets are
+            // encoded per viewer since every viewer has its own wire ids
+            let mut movement = HashMap::new();
+            for mut player in self.server.connected_players.iter_mut() {
+                let current = player.position();
+                if current != player.last_broadcast {
+                    movement.insert(player.get_id(), (player.last_broadcast, current));
+                    player.last_broadcast = current;
                 }
             }
 
-            let mut socket = player.socket.write().await;
-            packet.resolve(&mut socket).await;
-        }
-    }
-    pub async fn despawn_player(&self, id: i8) {
-        let despawn_player = DespawnPlayerPacket::new(id);
-        self.packet_queue
-            .enqueue(None, Box::new(despawn_player))
-            .await;
-    }
-    pub async fn send_to_all_queued(&self) {
-        loop {
-            while let Some((owner_id, mut packet)) = self.packet_queue.dequeue().await {
-                let mut packet_writer = PacketWriter::new();
-                packet.write(&mut packet_writer);
-
-                for player in self.server.connected_players.iter() {
-                    if let Some(owner_id) = owner_id {
-                        if player.get_id() == owner_id {
-                            continue;
-                        }
-                    }
-
-                    let mut socket = player.socket.write().await;
-                    packet.resolve(&mut socket).await;
+            let players = self.server.connected_players.snapshot();
+
+            // the diff runs on the stored viewer while its entry is locked, so
+            // a map change or despawn in the meantime isn't overwritten
+            for viewer in &players {
+                let Some(packets) = self
+                    .server
+                    .connected_players
+                    .update(viewer.get_connection_id(), |stored| {
+                        track_entities(stored, &players, &movement, view_distance)
+                    })
+                else {
+                    continue;
+                };
+                for packet in &packets {
+                    let _ = viewer.send(packet).await;
                 }
             }
-            sleep(Duration::from_millis(PACKET_FLUSH_MILLIS)).await;
         }
     }
 
     pub async fn ping_players_loop(&self) {
+        let ping_packet = PingPacket.encode();
         loop {
             let mut remove_ids = Vec::new();
-            for player in self.server.connected_players.iter() {
-                let mut socket = player.socket.write().await;
-                let mut ping_packet = PingPacket::new();
-                let mut packet_writer = PacketWriter::new();
-                ping_packet.write(&mut packet_writer);
-
-                if let Err(er) = socket.write_all(&packet_writer.into_inner()).await {
-                    println!("Player {} disconnected", player.get_name());
-                    remove_ids.push(player.get_id());
+            for player in self.server.connected_players.snapshot() {
+                if player.send(&ping_packet).await.is_err() {
+                    remove_ids.push(player.get_connection_id());
                 }
             }
-            for id in remove_ids {
-                let leave_message = format!(
-                    "goodbye {}",
-                    self.server.connected_players.get(&id).unwrap().get_name()
-                );
-                self.server.connected_players.remove(&id);
-                self.despawn_player(id).await;
-
-                for player in self.server.connected_players.iter() {
-                    player.send_message(&leave_message).await;
-                }
+            for connection_id in remove_ids {
+                self.disconnect(connection_id).await;
             }
 
             sleep(Duration::from_millis(PING_TIME_MILLIS)).await;
         }
     }
+}
 
-    async fn get_last_id(&self) -> i8 {
-        for id in 0..=127 {
-            let id = id as i8;
-            if !self.server.connected_players.contains_key(&id) {
-                return id;
+// brings what `viewer` has spawned in line with who they can see among
+// `players`, returns the despawns, spawns and movement to send them in that
+// order
+fn track_entities(
+    viewer: &mut Player,
+    players: &[Player],
+    movement: &HashMap<EntityId, (EntityPosition, EntityPosition)>,
+    view_distance: u32,
+) -> Vec<Bytes> {
+    let mut targets: Vec<&Player> = players
+        .iter()
+        .filter(|target| can_see(viewer, target, view_distance))
+        .collect();
+
+    // out of range entities are released first so their wire ids can go to
+    // someone else in the same tick
+    let visible: HashSet<EntityId> = targets.iter().map(|target| target.get_id()).collect();
+    let gone: Vec<EntityId> = viewer
+        .entity_ids
+        .entities()
+        .filter(|entity_id| !visible.contains(entity_id))
+        .collect();
+    let mut packets: Vec<Bytes> = gone
+        .into_iter()
+        .filter_map(|entity_id| viewer.entity_ids.release(entity_id))
+        .map(|wire_id| DespawnPlayerPacket::new(wire_id).encode())
+        .collect();
+
+    // when there are more players around than wire ids the closest ones win
+    targets.sort_by_key(|target| distance_squared(viewer, target));
+
+    let mut updates = Vec::new();
+    for target in targets {
+        if let Some(wire_id) = viewer.entity_ids.get(target.get_id()) {
+            if let Some((last, current)) = movement.get(&target.get_id()) {
+                updates.extend(movement_packet(wire_id, last, current));
             }
+            continue;
         }
-        -1
+        let Some(wire_id) = viewer.entity_ids.assign(target.get_id()) else {
+            continue;
+        };
+        let position = target.last_broadcast;
+        let spawn_player = SpawnPlayerPacket::new(
+            wire_id,
+            target.get_name().to_string(),
+            position.x,
+            position.y,
+            position.z,
+            position.yaw,
+            position.pitch,
+        );
+        packets.push(spawn_player.encode());
+    }
+    packets.extend(updates);
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::config::Config;
+    use crate::server::game::player_registry::PlayerRegistry;
+    use crate::server::network::session::discard_socket;
+    use crate::server::server::test_resolver;
+
+    fn identification(username: &str) -> Vec<u8> {
+        signed_identification(username, "")
+    }
+
+    fn signed_identification(username: &str, key: &str) -> Vec<u8> {
+        PlayerIndentificationPacket {
+            protocol_version: 7,
+            username: username.to_string(),
+            verification_key: key.to_string(),
+            unused: 0,
+        }
+        .encode()
+        .to_vec()
+    }
+
+    // a fresh connection that identifies as `username`
+    async fn join(
+        resolver: &PacketResolver,
+        connection_id: ConnectionId,
+        username: &str,
+    ) -> Session {
+        let mut session = Session::new(connection_id, discard_socket(), false);
+        resolver
+            .handle_packet(&mut session, &identification(username))
+            .await;
+        session
+    }
+
+    #[tokio::test]
+    async fn verifies_names_with_the_server_salt() {
+        let resolver = test_resolver("verify-names", Config::default()).await;
+        let key = |name: &str| {
+            format!(
+                "{:x}",
+                md5::compute(format!("{}{}", resolver.server.salt, name))
+            )
+        };
+
+        let mut mallory = Session::new(1, discard_socket(), false);
+        resolver
+            .handle_packet(
+                &mut mallory,
+                &signed_identification("alice", &key("mallory")),
+            )
+            .await;
+        assert_eq!(mallory.state, ConnectionState::Disconnecting);
+
+        let mut alice = Session::new(2, discard_socket(), false);
+        resolver
+            .handle_packet(&mut alice, &signed_identification("alice", &key("alice")))
+            .await;
+        assert_eq!(alice.state, ConnectionState::Playing);
+
+        // lan listeners let players in without a key
+        let mut bob = Session::new(3, discard_socket(), true);
+        resolver
+            .handle_packet(&mut bob, &identification("bob"))
+            .await;
+        assert_eq!(bob.state, ConnectionState::Playing);
+    }
+
+    fn cpe_identification(username: &str) -> Vec<u8> {
+        let mut frame = identification(username);
+        *frame.last_mut().unwrap() = CPE_MAGIC;
+        frame
+    }
+
+    fn ext_info(extension_count: i16) -> Vec<u8> {
+        ExtInfoPacket {
+            app_name: "test".to_string(),
+            extension_count,
+        }
+        .encode()
+        .to_vec()
+    }
+
+    fn ext_entry(ext_name: &str) -> Vec<u8> {
+        ExtEntryPacket {
+            ext_name: ext_name.to_string(),
+            version: 1,
+        }
+        .encode()
+        .to_vec()
+    }
+
+    #[tokio::test]
+    async fn negotiates_extensions_in_order() {
+        let config = Config {
+            do_user_auth: false,
+            ..Config::default()
+        };
+        let resolver = test_resolver("cpe-order", config).await;
+
+        let mut alice = Session::new(1, discard_socket(), false);
+        for frame in [
+            cpe_identification("alice"),
+            ext_info(2),
+            ext_entry("EnvColors"),
+        ] {
+            resolver.handle_packet(&mut alice, &frame).await;
+            assert_eq!(alice.state, ConnectionState::NegotiatingCpe);
+        }
+        resolver
+            .handle_packet(&mut alice, &ext_entry("HeldBlock"))
+            .await;
+        assert_eq!(alice.state, ConnectionState::Playing);
+        assert_eq!(alice.extensions.len(), 2);
+
+        // an entry before ExtInfo can't finish the login early
+        let mut bob = Session::new(2, discard_socket(), false);
+        resolver
+            .handle_packet(&mut bob, &cpe_identification("bob"))
+            .await;
+        resolver
+            .handle_packet(&mut bob, &ext_entry("EnvColors"))
+            .await;
+        assert_eq!(bob.state, ConnectionState::Disconnecting);
+
+        let mut carol = Session::new(3, discard_socket(), false);
+        for frame in [cpe_identification("carol"), ext_info(1), ext_info(1)] {
+            resolver.handle_packet(&mut carol, &frame).await;
+        }
+        assert_eq!(carol.state, ConnectionState::Disconnecting);
+        assert_eq!(resolver.server.connected_players.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_reserved_slots_for_staff() {
+        let config = Config {
+            do_user_auth: false,
+            max_players: 2,
+            reserved_slots: 1,
+            ranks: HashMap::from([("boss".to_string(), Rank::Admin)]),
+            ..Config::default()
+        };
+        let resolver = test_resolver("reserved-slots", config).await;
+
+        assert_eq!(
+            join(&resolver, 1, "alice").await.state,
+            ConnectionState::Playing
+        );
+        assert_eq!(
+            join(&resolver, 2, "bob").await.state,
+            ConnectionState::Disconnecting
+        );
+        assert_eq!(
+            join(&resolver, 3, "Boss").await.state,
+            ConnectionState::Playing
+        );
+        assert_eq!(
+            join(&resolver, 4, "carol").await.state,
+            ConnectionState::Disconnecting
+        );
+        assert_eq!(resolver.server.connected_players.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn admits_the_queue_in_order() {
+        let config = Config {
+            do_user_auth: false,
+            max_players: 1,
+            join_queue_size: 5,
+            ..Config::default()
+        };
+        let resolver = test_resolver("join-queue", config).await;
+
+        join(&resolver, 1, "alice").await;
+        let mut bob = join(&resolver, 2, "bob").await;
+        assert_eq!(bob.state, ConnectionState::Queued);
+        assert_eq!(bob.queue_position, 1);
+
+        // alice leaving frees her slot, but it is bob's and not carol's
+        resolver.disconnect(1).await;
+        let mut carol = join(&resolver, 3, "carol").await;
+        assert_eq!(carol.state, ConnectionState::Queued);
+        assert_eq!(carol.queue_position, 2);
+
+        resolver.update_queued(&mut carol).await;
+        assert_eq!(carol.state, ConnectionState::Queued);
+        resolver.update_queued(&mut bob).await;
+        assert_eq!(bob.state, ConnectionState::Playing);
+        assert_eq!(
+            resolver.server.connected_players.find_by_name("bob"),
+            Some(2)
+        );
+
+        // carol moved up to the front and waits for the next slot
+        resolver.update_queued(&mut carol).await;
+        assert_eq!(carol.state, ConnectionState::Queued);
+        assert_eq!(carol.queue_position, 1);
+    }
+
+    #[tokio::test]
+    async fn a_second_login_takes_over_the_first() {
+        // the takeover doesn't need a free slot
+        let config = Config {
+            do_user_auth: false,
+            max_players: 1,
+            ..Config::default()
+        };
+        let resolver = test_resolver("take-over", config).await;
+        let players = &resolver.server.connected_players;
+
+        join(&resolver, 1, "alice").await;
+        players.update(1, |player| player.set_pos(100, 80, 60, 0, 64));
+        let first_id = players.get(1).unwrap().get_id();
+
+        let second = join(&resolver, 2, "Alice").await;
+        assert_eq!(second.state, ConnectionState::Playing);
+        assert!(!players.contains(1));
+        assert_eq!(players.find_by_name("alice"), Some(2));
+        let player = players.get(2).unwrap();
+        assert_eq!(player.position(), EntityPosition::new(100, 80, 60, 64, 0));
+        assert_eq!(player.current_map, "main");
+        assert_ne!(player.get_id(), first_id);
+    }
+
+    #[tokio::test]
+    async fn a_second_login_can_be_turned_away() {
+        let config = Config {
+            do_user_auth: false,
+            duplicate_login: DuplicateLoginPolicy::RejectNew,
+            ..Config::default()
+        };
+        let resolver = test_resolver("reject-new", config).await;
+        let players = &resolver.server.connected_players;
+
+        join(&resolver, 1, "alice").await;
+        let second = join(&resolver, 2, "ALICE").await;
+        assert_eq!(second.state, ConnectionState::Disconnecting);
+        assert_eq!(players.find_by_name("alice"), Some(1));
+        assert_eq!(players.len(), 1);
+    }
+
+    // spawned in on main at the given block
+    fn player(connection_id: ConnectionId, x: i16) -> Player {
+        let mut player = Player::new(
+            connection_id,
+            connection_id as EntityId + 100,
+            format!("player{}", connection_id),
+            Rank::Guest,
+            "main".to_string(),
+            discard_socket(),
+        );
+        player.set_pos(x * 32, 64, 0, 0, 0);
+        player.last_broadcast = player.position();
+        player.spawned = true;
+        player
+    }
+
+    #[test]
+    fn tracks_entities_in_and_out_of_range() {
+        let mut viewer = player(1, 0);
+        let mut players = vec![viewer.clone(), player(2, 10)];
+        let no_movement = HashMap::new();
+
+        let packets = track_entities(&mut viewer, &players, &no_movement, 16);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0][0], SpawnPlayerPacket::ID);
+        assert_eq!(viewer.entity_ids.get(102), Some(0));
+
+        let last = players[1].position();
+        players[1].set_pos(11 * 32, 64, 0, 0, 0);
+        let movement = HashMap::from([(102, (last, players[1].position()))]);
+        let packets = track_entities(&mut viewer, &players, &movement, 16);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0][0], 0x0a);
+
+        players[1].set_pos(40 * 32, 64, 0, 0, 0);
+        let packets = track_entities(&mut viewer, &players, &no_movement, 16);
+        assert_eq!(packets, vec![DespawnPlayerPacket::new(0).encode()]);
+        assert!(viewer.entity_ids.is_empty());
+    }
+
+    #[test]
+    fn tracking_keeps_a_map_change_made_after_the_snapshot() {
+        let registry = PlayerRegistry::new();
+        registry.insert(player(1, 0));
+        registry.insert(player(2, 1));
+        let players = registry.snapshot();
+        registry.update(1, |viewer| {
+            track_entities(viewer, &players, &HashMap::new(), 16)
+        });
+        assert_eq!(registry.get(1).unwrap().entity_ids.len(), 1);
+
+        // the viewer moves maps while the tracking loop works from a snapshot
+        // taken before that
+        let players = registry.snapshot();
+        registry.update(1, |viewer| {
+            viewer.current_map = "other".to_string();
+            viewer.spawned = false;
+            viewer.entity_ids = WireIds::new();
+        });
+        let packets = registry
+            .update(1, |viewer| {
+                track_entities(viewer, &players, &HashMap::new(), 16)
+            })
+            .unwrap();
+        assert!(packets.is_empty());
+        assert!(registry.get(1).unwrap().entity_ids.is_empty());
     }
 }
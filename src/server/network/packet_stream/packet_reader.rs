@@ -1,3 +1,5 @@
+use crate::server::error::{Error, Result};
+
 #[derive(Debug, Clone)]
 pub struct PacketReader<'a> {
     data: &'a [u8],
@@ -9,33 +11,44 @@ impl<'a> PacketReader<'a> {
         PacketReader { data, index: 0 }
     }
 
-    pub fn read_byte(&mut self) -> u8 {
-        let byte = self.data[self.index];
-        self.index += 1;
-        return byte;
+    // hands out the next `size` bytes, or an error if the frame is shorter than that
+    fn take(&mut self, size: usize) -> Result<&'a [u8]> {
+        let remaining = self.data.len().saturating_sub(self.index);
+        if remaining < size {
+            return Err(Error::TruncatedPacket {
+                needed: size,
+                remaining,
+            });
+        }
+        let bytes = &self.data[self.index..self.index + size];
+        self.index += size;
+        Ok(bytes)
     }
 
-    pub fn read_sbyte(&mut self) -> i8 {
-        let byte = self.data[self.index];
-        self.index += 1;
-        return byte as i8;
+    pub fn read_byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
     }
 
-    pub fn read_short(&mut self) -> i16 {
-        let bytes = &self.data[self.index..self.index + 2];
-        self.index += 2;
-        return i16::from_be_bytes([bytes[0], bytes[1]]);
+    pub fn read_sbyte(&mut self) -> Result<i8> {
+        Ok(self.take(1)?[0] as i8)
     }
 
-    pub fn read_string(&mut self) -> String {
-        let bytes = &self.data[self.index..self.index + 64];
-        self.index += 64;
-        return String::from_utf8_lossy(bytes).trim_end().to_string();
+    pub fn read_short(&mut self) -> Result<i16> {
+        let bytes = self.take(2)?;
+        Ok(i16::from_be_bytes([bytes[0], bytes[1]]))
     }
 
-    pub fn read_byte_array(&mut self, size: usize) -> Vec<u8> {
-        let bytes = &self.data[self.index..self.index + size];
-        self.index += size;
-        return bytes.to_vec();
+    pub fn read_int(&mut self) -> Result<i32> {
+        let bytes = self.take(4)?;
+        Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_string(&mut self) -> Result<String> {
+        let bytes = self.take(64)?;
+        Ok(String::from_utf8_lossy(bytes).trim_end().to_string())
+    }
+
+    pub fn read_byte_array(&mut self, size: usize) -> Result<Vec<u8>> {
+        Ok(self.take(size)?.to_vec())
     }
 }
@@ -1,3 +1,5 @@
+use bytes::Bytes;
+
 pub struct PacketWriter {
     data: Vec<u8>,
 }
@@ -19,6 +21,10 @@ impl PacketWriter {
         self.data.extend(&value.to_be_bytes());
     }
 
+    pub fn write_int(&mut self, value: i32) {
+        self.data.extend(&value.to_be_bytes());
+    }
+
     pub fn write_string(&mut self, value: &str) {
         let mut bytes = value.as_bytes().to_vec();
         bytes.resize(64, b' ');
@@ -31,10 +37,17 @@ impl PacketWriter {
         self.data.extend(&bytes);
     }
 
-    pub fn to_bytes(&self) -> &Vec<u8> {
-        return &self.data;
-    }
     pub fn into_inner(self) -> Vec<u8> {
         self.data
     }
+
+    pub fn into_bytes(self) -> Bytes {
+        Bytes::from(self.data)
+    }
+}
+
+impl Default for PacketWriter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -1,22 +1,19 @@
+use crate::server::error::Result;
 use crate::server::network::{
-    packet::PacketTrait,
+    packet::Packet,
     packet_stream::{packet_reader::PacketReader, packet_writer::PacketWriter},
 };
-use async_trait::async_trait;
-use tokio::io::{AsyncWriteExt, WriteHalf};
-use tokio::net::TcpStream;
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct ServerIdentificationPacket {
-    data: Vec<u8>,
-    protocol_version: u8,
-    server_name: String,
-    server_motd: String,
-    user_type: u8,
+    pub protocol_version: u8,
+    pub server_name: String,
+    pub server_motd: String,
+    pub user_type: u8,
 }
 impl ServerIdentificationPacket {
     pub fn new(server_name: String, server_motd: String) -> Self {
         Self {
-            data: Vec::new(),
             protocol_version: 0x07,
             server_name,
             server_motd,
@@ -24,179 +21,129 @@ impl ServerIdentificationPacket {
         }
     }
 }
-#[async_trait]
-impl PacketTrait for ServerIdentificationPacket {
-    fn packet_id(&self) -> u8 {
-        0x00
-    }
-    fn write(&mut self, writer: &mut PacketWriter) {
-        writer.write_byte(self.packet_id());
+impl Packet for ServerIdentificationPacket {
+    const ID: u8 = 0x00;
+    const SIZE: usize = 131;
+
+    fn write(&self, writer: &mut PacketWriter) {
         writer.write_byte(self.protocol_version);
-        writer.write_string(self.server_name.as_str());
-        writer.write_string(self.server_motd.as_str());
+        writer.write_string(&self.server_name);
+        writer.write_string(&self.server_motd);
         writer.write_byte(self.user_type);
-        self.data = writer.to_bytes().clone();
     }
-    fn read(&mut self, _reader: &mut PacketReader) {}
-
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        socket.write_all(&self.data).await?;
-        Ok(())
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            protocol_version: reader.read_byte()?,
+            server_name: reader.read_string()?,
+            server_motd: reader.read_string()?,
+            user_type: reader.read_byte()?,
+        })
     }
 }
 
-pub struct LevelInitializePacket {
-    data: Vec<u8>,
-}
-impl LevelInitializePacket {
-    pub fn new() -> Self {
-        Self { data: Vec::new() }
+#[derive(Debug, Clone, PartialEq)]
+pub struct PingPacket;
+impl Packet for PingPacket {
+    const ID: u8 = 0x01;
+    const SIZE: usize = 1;
+
+    fn write(&self, _writer: &mut PacketWriter) {}
+    fn read(_reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self)
     }
 }
-#[async_trait]
-impl PacketTrait for LevelInitializePacket {
-    fn packet_id(&self) -> u8 {
-        0x02
-    }
-    fn write(&mut self, writer: &mut PacketWriter) {
-        writer.write_byte(self.packet_id());
-        self.data = writer.to_bytes().clone();
-    }
-    fn read(&mut self, _reader: &mut PacketReader) {}
 
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        socket.write_all(&self.data).await?;
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelInitializePacket;
+impl Packet for LevelInitializePacket {
+    const ID: u8 = 0x02;
+    const SIZE: usize = 1;
 
-        Ok(())
+    fn write(&self, _writer: &mut PacketWriter) {}
+    fn read(_reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self)
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct LevelDataChunkPacket {
-    data: Vec<u8>,
-    chunk_length: i16,
-    chunk_data: Vec<u8>,
-    completed: u8,
+    pub chunk_length: i16,
+    pub chunk_data: Vec<u8>,
+    pub completed: u8,
 }
 impl LevelDataChunkPacket {
     pub fn new(chunk_length: i16, chunk_data: Vec<u8>, completed: u8) -> Self {
         Self {
-            data: Vec::new(),
             chunk_length,
             chunk_data,
             completed,
         }
     }
 }
-#[async_trait]
-impl PacketTrait for LevelDataChunkPacket {
-    fn packet_id(&self) -> u8 {
-        0x03
-    }
-    fn write(&mut self, writer: &mut PacketWriter) {
-        writer.write_byte(self.packet_id());
+impl Packet for LevelDataChunkPacket {
+    const ID: u8 = 0x03;
+    const SIZE: usize = 1028;
+
+    fn write(&self, writer: &mut PacketWriter) {
         writer.write_short(self.chunk_length);
-        writer.write_byte_array(self.chunk_data.as_slice(), 1024);
+        writer.write_byte_array(&self.chunk_data, 1024);
         writer.write_byte(self.completed);
-        self.data = writer.to_bytes().clone();
     }
-    fn read(&mut self, _reader: &mut PacketReader) {}
-
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        socket.write_all(&self.data).await?;
-        Ok(())
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        let chunk_length = reader.read_short()?;
+        let mut chunk_data = reader.read_byte_array(1024)?;
+        chunk_data.truncate(chunk_length.clamp(0, 1024) as usize);
+        Ok(Self {
+            chunk_length,
+            chunk_data,
+            completed: reader.read_byte()?,
+        })
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct LevelFinalizePacket {
-    data: Vec<u8>,
-    x_size: i16,
-    y_size: i16,
-    z_size: i16,
+    pub x_size: i16,
+    pub y_size: i16,
+    pub z_size: i16,
 }
 impl LevelFinalizePacket {
     pub fn new(x_size: i16, y_size: i16, z_size: i16) -> Self {
         Self {
-            data: Vec::new(),
             x_size,
             y_size,
             z_size,
         }
     }
 }
-#[async_trait]
-impl PacketTrait for LevelFinalizePacket {
-    fn packet_id(&self) -> u8 {
-        return 0x04;
-    }
-    fn write(&mut self, writer: &mut PacketWriter) {
-        writer.write_byte(self.packet_id());
+impl Packet for LevelFinalizePacket {
+    const ID: u8 = 0x04;
+    const SIZE: usize = 7;
+
+    fn write(&self, writer: &mut PacketWriter) {
         writer.write_short(self.x_size);
         writer.write_short(self.y_size);
         writer.write_short(self.z_size);
-        self.data = writer.to_bytes().clone();
     }
-    fn read(&mut self, _reader: &mut PacketReader) {}
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        socket.write_all(&self.data).await?;
-
-        Ok(())
-    }
-}
-
-pub struct PingPacket {
-    data: Vec<u8>,
-}
-impl PingPacket {
-    pub fn new() -> Self {
-        Self { data: Vec::new() }
-    }
-}
-#[async_trait]
-impl PacketTrait for PingPacket {
-    fn packet_id(&self) -> u8 {
-        0x01
-    }
-
-    fn write(&mut self, writer: &mut PacketWriter) {
-        writer.write_byte(self.packet_id());
-        self.data = writer.to_bytes().clone();
-    }
-
-    fn read(&mut self, _reader: &mut PacketReader) {}
-
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        socket.write_all(&self.data).await?;
-
-        Ok(())
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            x_size: reader.read_short()?,
+            y_size: reader.read_short()?,
+            z_size: reader.read_short()?,
+        })
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct UpdateSetBlockPacket {
-    data: Vec<u8>,
-    x: i16,
-    y: i16,
-    z: i16,
-    block_type: u8,
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+    pub block_type: u8,
 }
 impl UpdateSetBlockPacket {
     pub fn new(x: i16, y: i16, z: i16, block_type: u8) -> Self {
         Self {
-            data: Vec::new(),
             x,
             y,
             z,
@@ -204,41 +151,35 @@ impl UpdateSetBlockPacket {
         }
     }
 }
-#[async_trait]
-impl PacketTrait for UpdateSetBlockPacket {
-    fn packet_id(&self) -> u8 {
-        0x06
-    }
+impl Packet for UpdateSetBlockPacket {
+    const ID: u8 = 0x06;
+    const SIZE: usize = 8;
 
-    fn write(&mut self, writer: &mut PacketWriter) {
-        writer.write_byte(self.packet_id());
+    fn write(&self, writer: &mut PacketWriter) {
         writer.write_short(self.x);
         writer.write_short(self.y);
         writer.write_short(self.z);
         writer.write_byte(self.block_type);
-        self.data = writer.to_bytes().clone();
     }
-
-    fn read(&mut self, _reader: &mut PacketReader) {}
-
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        socket.write_all(&self.data).await?;
-        Ok(())
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            x: reader.read_short()?,
+            y: reader.read_short()?,
+            z: reader.read_short()?,
+            block_type: reader.read_byte()?,
+        })
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct SpawnPlayerPacket {
-    data: Vec<u8>,
-    player_id: i8,
-    player_name: String,
-    x: i16,
-    y: i16,
-    z: i16,
-    yaw: u8,
-    pitch: u8,
+    pub player_id: i8,
+    pub player_name: String,
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+    pub yaw: u8,
+    pub pitch: u8,
 }
 impl SpawnPlayerPacket {
     pub fn new(
@@ -251,7 +192,6 @@ impl SpawnPlayerPacket {
         pitch: u8,
     ) -> Self {
         Self {
-            data: Vec::new(),
             player_id,
             player_name,
             x,
@@ -262,14 +202,11 @@ impl SpawnPlayerPacket {
         }
     }
 }
-#[async_trait]
-impl PacketTrait for SpawnPlayerPacket {
-    fn packet_id(&self) -> u8 {
-        0x07
-    }
+impl Packet for SpawnPlayerPacket {
+    const ID: u8 = 0x07;
+    const SIZE: usize = 74;
 
-    fn write(&mut self, writer: &mut PacketWriter) {
-        writer.write_byte(self.packet_id());
+    fn write(&self, writer: &mut PacketWriter) {
         writer.write_sbyte(self.player_id);
         writer.write_string(&self.player_name);
         writer.write_short(self.x);
@@ -277,33 +214,32 @@ impl PacketTrait for SpawnPlayerPacket {
         writer.write_short(self.z);
         writer.write_byte(self.yaw);
         writer.write_byte(self.pitch);
-        self.data = writer.to_bytes().clone();
     }
-
-    fn read(&mut self, _reader: &mut PacketReader) {}
-
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        socket.write_all(&self.data).await?;
-        Ok(())
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            player_id: reader.read_sbyte()?,
+            player_name: reader.read_string()?,
+            x: reader.read_short()?,
+            y: reader.read_short()?,
+            z: reader.read_short()?,
+            yaw: reader.read_byte()?,
+            pitch: reader.read_byte()?,
+        })
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct SetPositionAndOrientationPacket {
-    data: Vec<u8>,
-    player_id: i8,
-    x: i16,
-    y: i16,
-    z: i16,
-    yaw: u8,
-    pitch: u8,
+    pub player_id: i8,
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+    pub yaw: u8,
+    pub pitch: u8,
 }
 impl SetPositionAndOrientationPacket {
     pub fn new(player_id: i8, x: i16, y: i16, z: i16, yaw: u8, pitch: u8) -> Self {
         Self {
-            data: Vec::new(),
             player_id,
             x,
             y,
@@ -313,173 +249,402 @@ impl SetPositionAndOrientationPacket {
         }
     }
 }
-#[async_trait]
-impl PacketTrait for SetPositionAndOrientationPacket {
-    fn packet_id(&self) -> u8 {
-        0x08
-    }
+impl Packet for SetPositionAndOrientationPacket {
+    const ID: u8 = 0x08;
+    const SIZE: usize = 10;
 
-    fn write(&mut self, writer: &mut PacketWriter) {
-        writer.write_byte(self.packet_id());
+    fn write(&self, writer: &mut PacketWriter) {
         writer.write_sbyte(self.player_id);
         writer.write_short(self.x);
         writer.write_short(self.y);
         writer.write_short(self.z);
         writer.write_byte(self.yaw);
         writer.write_byte(self.pitch);
-        self.data = writer.to_bytes().clone();
     }
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            player_id: reader.read_sbyte()?,
+            x: reader.read_short()?,
+            y: reader.read_short()?,
+            z: reader.read_short()?,
+            yaw: reader.read_byte()?,
+            pitch: reader.read_byte()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdatePositionAndOrientationPacket {
+    pub player_id: i8,
+    pub dx: i8,
+    pub dy: i8,
+    pub dz: i8,
+    pub yaw: u8,
+    pub pitch: u8,
+}
+impl UpdatePositionAndOrientationPacket {
+    pub fn new(player_id: i8, dx: i8, dy: i8, dz: i8, yaw: u8, pitch: u8) -> Self {
+        Self {
+            player_id,
+            dx,
+            dy,
+            dz,
+            yaw,
+            pitch,
+        }
+    }
+}
+impl Packet for UpdatePositionAndOrientationPacket {
+    const ID: u8 = 0x09;
+    const SIZE: usize = 7;
 
-    fn read(&mut self, _reader: &mut PacketReader) {}
-
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        socket.write_all(&self.data).await?;
-        Ok(())
+    fn write(&self, writer: &mut PacketWriter) {
+        writer.write_sbyte(self.player_id);
+        writer.write_sbyte(self.dx);
+        writer.write_sbyte(self.dy);
+        writer.write_sbyte(self.dz);
+        writer.write_byte(self.yaw);
+        writer.write_byte(self.pitch);
+    }
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            player_id: reader.read_sbyte()?,
+            dx: reader.read_sbyte()?,
+            dy: reader.read_sbyte()?,
+            dz: reader.read_sbyte()?,
+            yaw: reader.read_byte()?,
+            pitch: reader.read_byte()?,
+        })
     }
 }
 
-pub struct DespawnPlayerPacket {
-    data: Vec<u8>,
-    player_id: i8,
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdatePositionPacket {
+    pub player_id: i8,
+    pub dx: i8,
+    pub dy: i8,
+    pub dz: i8,
 }
-impl DespawnPlayerPacket {
-    pub fn new(player_id: i8) -> Self {
+impl UpdatePositionPacket {
+    pub fn new(player_id: i8, dx: i8, dy: i8, dz: i8) -> Self {
         Self {
-            data: Vec::new(),
             player_id,
+            dx,
+            dy,
+            dz,
         }
     }
 }
-#[async_trait]
-impl PacketTrait for DespawnPlayerPacket {
-    fn packet_id(&self) -> u8 {
-        0x0c
+impl Packet for UpdatePositionPacket {
+    const ID: u8 = 0x0a;
+    const SIZE: usize = 5;
+
+    fn write(&self, writer: &mut PacketWriter) {
+        writer.write_sbyte(self.player_id);
+        writer.write_sbyte(self.dx);
+        writer.write_sbyte(self.dy);
+        writer.write_sbyte(self.dz);
+    }
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            player_id: reader.read_sbyte()?,
+            dx: reader.read_sbyte()?,
+            dy: reader.read_sbyte()?,
+            dz: reader.read_sbyte()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateOrientationPacket {
+    pub player_id: i8,
+    pub yaw: u8,
+    pub pitch: u8,
+}
+impl UpdateOrientationPacket {
+    pub fn new(player_id: i8, yaw: u8, pitch: u8) -> Self {
+        Self {
+            player_id,
+            yaw,
+            pitch,
+        }
     }
+}
+impl Packet for UpdateOrientationPacket {
+    const ID: u8 = 0x0b;
+    const SIZE: usize = 4;
 
-    fn write(&mut self, writer: &mut PacketWriter) {
-        writer.write_byte(self.packet_id());
+    fn write(&self, writer: &mut PacketWriter) {
         writer.write_sbyte(self.player_id);
-        self.data = writer.to_bytes().clone();
+        writer.write_byte(self.yaw);
+        writer.write_byte(self.pitch);
+    }
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            player_id: reader.read_sbyte()?,
+            yaw: reader.read_byte()?,
+            pitch: reader.read_byte()?,
+        })
     }
+}
 
-    fn read(&mut self, _reader: &mut PacketReader) {}
+#[derive(Debug, Clone, PartialEq)]
+pub struct DespawnPlayerPacket {
+    pub player_id: i8,
+}
+impl DespawnPlayerPacket {
+    pub fn new(player_id: i8) -> Self {
+        Self { player_id }
+    }
+}
+impl Packet for DespawnPlayerPacket {
+    const ID: u8 = 0x0c;
+    const SIZE: usize = 2;
 
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        socket.write_all(&self.data).await?;
-        Ok(())
+    fn write(&self, writer: &mut PacketWriter) {
+        writer.write_sbyte(self.player_id);
+    }
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            player_id: reader.read_sbyte()?,
+        })
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct SendMessagePacket {
-    data: Vec<u8>,
-    player_id: i8,
-    message: String,
+    pub player_id: i8,
+    pub message: String,
 }
 impl SendMessagePacket {
     pub fn new(player_id: i8, message: String) -> Self {
-        Self {
-            data: Vec::new(),
-            player_id,
-            message,
-        }
+        Self { player_id, message }
     }
 }
-#[async_trait]
-impl PacketTrait for SendMessagePacket {
-    fn packet_id(&self) -> u8 {
-        0x0d
-    }
+impl Packet for SendMessagePacket {
+    const ID: u8 = 0x0d;
+    const SIZE: usize = 66;
 
-    fn write(&mut self, writer: &mut PacketWriter) {
-        writer.write_byte(self.packet_id());
+    fn write(&self, writer: &mut PacketWriter) {
         writer.write_sbyte(self.player_id);
         writer.write_string(&self.message);
-        self.data = writer.to_bytes().clone();
     }
-
-    fn read(&mut self, _reader: &mut PacketReader) {}
-
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        socket.write_all(&self.data).await?;
-        Ok(())
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            player_id: reader.read_sbyte()?,
+            message: reader.read_string()?,
+        })
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct DisconnectPlayerPacket {
-    data: Vec<u8>,
-    reason: String,
+    pub reason: String,
 }
 impl DisconnectPlayerPacket {
     pub fn new(reason: String) -> Self {
-        Self {
-            data: Vec::new(),
-            reason,
-        }
+        Self { reason }
     }
 }
-#[async_trait]
-impl PacketTrait for DisconnectPlayerPacket {
-    fn packet_id(&self) -> u8 {
-        0x0e
-    }
+impl Packet for DisconnectPlayerPacket {
+    const ID: u8 = 0x0e;
+    const SIZE: usize = 65;
 
-    fn write(&mut self, writer: &mut PacketWriter) {
-        writer.write_byte(self.packet_id());
+    fn write(&self, writer: &mut PacketWriter) {
         writer.write_string(&self.reason);
-        self.data = writer.to_bytes().clone();
     }
-
-    fn read(&mut self, _reader: &mut PacketReader) {}
-
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        socket.write_all(&self.data).await?;
-        Ok(())
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            reason: reader.read_string()?,
+        })
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct UpdateUserTypePacket {
-    data: Vec<u8>,
-    user_type: u8,
+    pub user_type: u8,
 }
 impl UpdateUserTypePacket {
     pub fn new(user_type: u8) -> Self {
+        Self { user_type }
+    }
+}
+impl Packet for UpdateUserTypePacket {
+    const ID: u8 = 0x0f;
+    const SIZE: usize = 2;
+
+    fn write(&self, writer: &mut PacketWriter) {
+        writer.write_byte(self.user_type);
+    }
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            user_type: reader.read_byte()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtInfoPacket {
+    pub app_name: String,
+    pub extension_count: i16,
+}
+impl ExtInfoPacket {
+    pub fn new(app_name: String, extension_count: i16) -> Self {
         Self {
-            data: Vec::new(),
-            user_type,
+            app_name,
+            extension_count,
         }
     }
 }
-#[async_trait]
-impl PacketTrait for UpdateUserTypePacket {
-    fn packet_id(&self) -> u8 {
-        0x0f
+impl Packet for ExtInfoPacket {
+    const ID: u8 = 0x10;
+    const SIZE: usize = 67;
+
+    fn write(&self, writer: &mut PacketWriter) {
+        writer.write_string(&self.app_name);
+        writer.write_short(self.extension_count);
+    }
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            app_name: reader.read_string()?,
+            extension_count: reader.read_short()?,
+        })
     }
+}
 
-    fn write(&mut self, writer: &mut PacketWriter) {
-        writer.write_byte(self.packet_id());
-        writer.write_byte(self.user_type);
-        self.data = writer.to_bytes().clone();
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtEntryPacket {
+    pub ext_name: String,
+    pub version: i32,
+}
+impl ExtEntryPacket {
+    pub fn new(ext_name: String, version: i32) -> Self {
+        Self { ext_name, version }
+    }
+}
+impl Packet for ExtEntryPacket {
+    const ID: u8 = 0x11;
+    const SIZE: usize = 69;
+
+    fn write(&self, writer: &mut PacketWriter) {
+        writer.write_string(&self.ext_name);
+        writer.write_int(self.version);
     }
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            ext_name: reader.read_string()?,
+            version: reader.read_int()?,
+        })
+    }
+}
 
-    fn read(&mut self, _reader: &mut PacketReader) {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<P: Packet + PartialEq + std::fmt::Debug>(packet: P) {
+        let bytes = packet.encode();
+        assert_eq!(bytes.len(), P::SIZE);
+        assert_eq!(bytes[0], P::ID);
+        assert_eq!(P::decode(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn server_identification() {
+        round_trip(ServerIdentificationPacket::new(
+            "dandelion".to_string(),
+            "hello there".to_string(),
+        ));
+    }
+
+    #[test]
+    fn ping() {
+        round_trip(PingPacket);
+    }
+
+    #[test]
+    fn level_initialize() {
+        round_trip(LevelInitializePacket);
+    }
+
+    #[test]
+    fn level_data_chunk() {
+        round_trip(LevelDataChunkPacket::new(3, vec![1, 2, 3], 50));
+        round_trip(LevelDataChunkPacket::new(1024, vec![7; 1024], 100));
+    }
+
+    #[test]
+    fn level_finalize() {
+        round_trip(LevelFinalizePacket::new(256, 64, 512));
+    }
+
+    #[test]
+    fn update_set_block() {
+        round_trip(UpdateSetBlockPacket::new(10, 20, 30, 0x31));
+    }
+
+    #[test]
+    fn spawn_player() {
+        round_trip(SpawnPlayerPacket::new(
+            -1,
+            "flafmg".to_string(),
+            4718,
+            1171,
+            -4166,
+            128,
+            64,
+        ));
+    }
+
+    #[test]
+    fn set_position_and_orientation() {
+        round_trip(SetPositionAndOrientationPacket::new(5, -32, 64, 96, 255, 0));
+    }
+
+    #[test]
+    fn update_position_and_orientation() {
+        round_trip(UpdatePositionAndOrientationPacket::new(
+            3, -128, 0, 127, 64, 192,
+        ));
+    }
+
+    #[test]
+    fn update_position() {
+        round_trip(UpdatePositionPacket::new(3, 1, -1, 32));
+    }
+
+    #[test]
+    fn update_orientation() {
+        round_trip(UpdateOrientationPacket::new(3, 10, 250));
+    }
+
+    #[test]
+    fn despawn_player() {
+        round_trip(DespawnPlayerPacket::new(126));
+    }
+
+    #[test]
+    fn send_message() {
+        round_trip(SendMessagePacket::new(-1, "&ehello world".to_string()));
+    }
+
+    #[test]
+    fn disconnect_player() {
+        round_trip(DisconnectPlayerPacket::new("kicked".to_string()));
+    }
+
+    #[test]
+    fn update_user_type() {
+        round_trip(UpdateUserTypePacket::new(0x64));
+    }
+
+    #[test]
+    fn ext_info() {
+        round_trip(ExtInfoPacket::new("dandelion 0.0.1".to_string(), 2));
+    }
 
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        socket.write_all(&self.data).await?;
-        Ok(())
+    #[test]
+    fn ext_entry() {
+        round_trip(ExtEntryPacket::new("CustomBlocks".to_string(), 1));
     }
 }
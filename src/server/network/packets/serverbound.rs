@@ -1,60 +1,51 @@
+use crate::server::error::Result;
 use crate::server::network::{
-    packet::PacketTrait,
+    packet::Packet,
     packet_stream::{packet_reader::PacketReader, packet_writer::PacketWriter},
 };
-use async_trait::async_trait;
-use tokio::{io::WriteHalf, net::TcpStream};
 
-use super::clientbound::ServerIdentificationPacket;
+// how many bytes the frame starting with this packet id takes, none if the
+// id is not something a client is allowed to send
+pub fn frame_length(packet_id: u8) -> Option<usize> {
+    match packet_id {
+        PlayerIndentificationPacket::ID => Some(PlayerIndentificationPacket::SIZE),
+        SetBlockPacket::ID => Some(SetBlockPacket::SIZE),
+        PositionAndOrientationUpdatePacket::ID => Some(PositionAndOrientationUpdatePacket::SIZE),
+        MessagePacket::ID => Some(MessagePacket::SIZE),
+        ExtInfoPacket::ID => Some(ExtInfoPacket::SIZE),
+        ExtEntryPacket::ID => Some(ExtEntryPacket::SIZE),
+        _ => None,
+    }
+}
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct PlayerIndentificationPacket {
     pub protocol_version: u8,
     pub username: String,
     pub verification_key: String,
-
-    server_name: String,
-    server_motd: String,
+    pub unused: u8,
 }
-impl PlayerIndentificationPacket {
-    pub fn new(server_name: String, server_motd: String) -> Self {
-        return Self {
-            protocol_version: 0,
-            username: String::new(),
-            verification_key: String::new(),
+impl Packet for PlayerIndentificationPacket {
+    const ID: u8 = 0x00;
+    const SIZE: usize = 131;
 
-            server_name,
-            server_motd,
-        };
+    fn write(&self, writer: &mut PacketWriter) {
+        writer.write_byte(self.protocol_version);
+        writer.write_string(&self.username);
+        writer.write_string(&self.verification_key);
+        writer.write_byte(self.unused);
     }
-}
-
-#[async_trait]
-impl PacketTrait for PlayerIndentificationPacket {
-    fn packet_id(&self) -> u8 {
-        return 0x00;
-    }
-    fn write(&mut self, writer: &mut PacketWriter) {}
-
-    fn read(&mut self, reader: &mut PacketReader) {
-        reader.read_byte();
-        self.protocol_version = reader.read_byte();
-        self.username = reader.read_string();
-        self.verification_key = reader.read_string();
-    }
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let mut resolve_packet_response =
-            ServerIdentificationPacket::new(self.server_name.clone(), self.server_motd.clone());
-        let mut paclet_writer = PacketWriter::new();
-        resolve_packet_response.write(&mut paclet_writer);
-        resolve_packet_response.resolve(socket).await?;
-
-        Ok(())
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            protocol_version: reader.read_byte()?,
+            username: reader.read_string()?,
+            verification_key: reader.read_string()?,
+            unused: reader.read_byte()?,
+        })
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct SetBlockPacket {
     pub x: i16,
     pub y: i16,
@@ -62,44 +53,29 @@ pub struct SetBlockPacket {
     pub mode: u8,
     pub block_type: u8,
 }
+impl Packet for SetBlockPacket {
+    const ID: u8 = 0x05;
+    const SIZE: usize = 9;
 
-impl SetBlockPacket {
-    pub fn new() -> Self {
-        Self {
-            x: 0,
-            y: 0,
-            z: 0,
-            mode: 0,
-            block_type: 0,
-        }
+    fn write(&self, writer: &mut PacketWriter) {
+        writer.write_short(self.x);
+        writer.write_short(self.y);
+        writer.write_short(self.z);
+        writer.write_byte(self.mode);
+        writer.write_byte(self.block_type);
     }
-}
-
-#[async_trait]
-impl PacketTrait for SetBlockPacket {
-    fn packet_id(&self) -> u8 {
-        0x05
-    }
-
-    fn write(&mut self, _writer: &mut PacketWriter) {}
-
-    fn read(&mut self, reader: &mut PacketReader) {
-        reader.read_byte();
-        self.x = reader.read_short();
-        self.y = reader.read_short();
-        self.z = reader.read_short();
-        self.mode = reader.read_byte();
-        self.block_type = reader.read_byte();
-    }
-
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        Ok(())
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            x: reader.read_short()?,
+            y: reader.read_short()?,
+            z: reader.read_short()?,
+            mode: reader.read_byte()?,
+            block_type: reader.read_byte()?,
+        })
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct PositionAndOrientationUpdatePacket {
     pub player_id: i8,
     pub x: i16,
@@ -108,78 +84,174 @@ pub struct PositionAndOrientationUpdatePacket {
     pub yaw: u8,
     pub pitch: u8,
 }
+impl Packet for PositionAndOrientationUpdatePacket {
+    const ID: u8 = 0x08;
+    const SIZE: usize = 10;
 
-impl PositionAndOrientationUpdatePacket {
-    pub fn new() -> Self {
-        Self {
-            player_id: -1,
-            x: 0,
-            y: 0,
-            z: 0,
-            yaw: 0,
-            pitch: 0,
-        }
+    fn write(&self, writer: &mut PacketWriter) {
+        writer.write_sbyte(self.player_id);
+        writer.write_short(self.x);
+        writer.write_short(self.y);
+        writer.write_short(self.z);
+        writer.write_byte(self.yaw);
+        writer.write_byte(self.pitch);
     }
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            player_id: reader.read_sbyte()?,
+            x: reader.read_short()?,
+            y: reader.read_short()?,
+            z: reader.read_short()?,
+            yaw: reader.read_byte()?,
+            pitch: reader.read_byte()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessagePacket {
+    pub player_id: i8,
+    pub message: String,
 }
+impl Packet for MessagePacket {
+    const ID: u8 = 0x0d;
+    const SIZE: usize = 66;
 
-#[async_trait]
-impl PacketTrait for PositionAndOrientationUpdatePacket {
-    fn packet_id(&self) -> u8 {
-        0x08
+    fn write(&self, writer: &mut PacketWriter) {
+        writer.write_sbyte(self.player_id);
+        writer.write_string(&self.message);
     }
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            player_id: reader.read_sbyte()?,
+            message: reader.read_string()?,
+        })
+    }
+}
 
-    fn write(&mut self, _writer: &mut PacketWriter) {}
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtInfoPacket {
+    pub app_name: String,
+    pub extension_count: i16,
+}
+impl Packet for ExtInfoPacket {
+    const ID: u8 = 0x10;
+    const SIZE: usize = 67;
 
-    fn read(&mut self, reader: &mut PacketReader) {
-        reader.read_byte();
-        self.player_id = reader.read_sbyte();
-        self.x = reader.read_short();
-        self.y = reader.read_short();
-        self.z = reader.read_short();
-        self.yaw = reader.read_byte();
-        self.pitch = reader.read_byte();
+    fn write(&self, writer: &mut PacketWriter) {
+        writer.write_string(&self.app_name);
+        writer.write_short(self.extension_count);
     }
-
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        Ok(())
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            app_name: reader.read_string()?,
+            extension_count: reader.read_short()?,
+        })
     }
 }
 
-pub struct MessagePacket {
-    player_id: i8,
-    pub message: String,
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtEntryPacket {
+    pub ext_name: String,
+    pub version: i32,
 }
+impl Packet for ExtEntryPacket {
+    const ID: u8 = 0x11;
+    const SIZE: usize = 69;
 
-impl MessagePacket {
-    pub fn new() -> Self {
-        Self {
-            player_id: -1,
-            message: String::new(),
-        }
+    fn write(&self, writer: &mut PacketWriter) {
+        writer.write_string(&self.ext_name);
+        writer.write_int(self.version);
+    }
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        Ok(Self {
+            ext_name: reader.read_string()?,
+            version: reader.read_int()?,
+        })
     }
 }
 
-#[async_trait]
-impl PacketTrait for MessagePacket {
-    fn packet_id(&self) -> u8 {
-        0x0d
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<P: Packet + PartialEq + std::fmt::Debug>(packet: P) {
+        let bytes = packet.encode();
+        assert_eq!(bytes.len(), P::SIZE);
+        assert_eq!(frame_length(bytes[0]), Some(P::SIZE));
+        assert_eq!(P::decode(&bytes).unwrap(), packet);
     }
 
-    fn write(&mut self, _writer: &mut PacketWriter) {}
+    #[test]
+    fn player_identification() {
+        round_trip(PlayerIndentificationPacket {
+            protocol_version: 0x07,
+            username: "flafmg".to_string(),
+            verification_key: "0123456789abcdef".to_string(),
+            unused: 0x42,
+        });
+    }
+
+    #[test]
+    fn set_block() {
+        round_trip(SetBlockPacket {
+            x: 12,
+            y: 34,
+            z: 56,
+            mode: 0x01,
+            block_type: 0x04,
+        });
+    }
+
+    #[test]
+    fn position_and_orientation_update() {
+        round_trip(PositionAndOrientationUpdatePacket {
+            player_id: -1,
+            x: 4718,
+            y: 1171,
+            z: -4166,
+            yaw: 200,
+            pitch: 12,
+        });
+    }
+
+    #[test]
+    fn message() {
+        round_trip(MessagePacket {
+            player_id: -1,
+            message: "hello from the other side".to_string(),
+        });
+    }
+
+    #[test]
+    fn truncated_frame_is_an_error() {
+        let bytes = MessagePacket {
+            player_id: -1,
+            message: "cut short".to_string(),
+        }
+        .encode();
+        assert!(MessagePacket::decode(&bytes[..10]).is_err());
+        assert!(MessagePacket::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn unknown_packet_has_no_frame() {
+        assert_eq!(frame_length(0x42), None);
+    }
 
-    fn read(&mut self, reader: &mut PacketReader) {
-        reader.read_byte();
-        self.player_id = reader.read_sbyte();
-        self.message = reader.read_string();
+    #[test]
+    fn ext_info() {
+        round_trip(ExtInfoPacket {
+            app_name: "ClassiCube 1.3.6".to_string(),
+            extension_count: 40,
+        });
     }
 
-    async fn resolve(
-        &self,
-        socket: &mut WriteHalf<TcpStream>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        Ok(())
+    #[test]
+    fn ext_entry() {
+        round_trip(ExtEntryPacket {
+            ext_name: "EnvMapAspect".to_string(),
+            version: 2,
+        });
     }
 }
@@ -0,0 +1,178 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::server::error::{Error, Result};
+
+// HAProxy PROXY protocol, sent by load balancers in front of the server
+// before any client data: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+const V1_PREFIX: &[u8] = b"PROXY ";
+// longest possible v1 header, crlf included
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: [u8; 12] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+
+// reads exactly the header off the stream and returns the address of the
+// real client, none when the proxy says the connection is its own (health
+// checks and the like)
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>> {
+    // both versions are at least this long, so this never eats client data
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).await?;
+        return parse_v2(&header, &body);
+    }
+
+    if !start.starts_with(V1_PREFIX) {
+        return Err(invalid("connection did not start with a proxy header"));
+    }
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("v1 header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(&line)
+}
+
+// "PROXY TCP4 <src ip> <dst ip> <src port> <dst port>\r\n" or "PROXY UNKNOWN ...\r\n"
+pub fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("v1 header is not ascii"))?;
+    let line = line
+        .strip_suffix("\r\n")
+        .ok_or_else(|| invalid("v1 header does not end in crlf"))?;
+    let mut parts = line.split(' ');
+    if parts.next() != Some("PROXY") {
+        return Err(invalid("v1 header does not start with PROXY"));
+    }
+
+    let protocol = parts
+        .next()
+        .ok_or_else(|| invalid("v1 header has no protocol"))?;
+    if protocol == "UNKNOWN" {
+        return Ok(None);
+    }
+    if protocol != "TCP4" && protocol != "TCP6" {
+        return Err(invalid("v1 header has an unknown protocol"));
+    }
+
+    let fields: Vec<&str> = parts.collect();
+    let [source_ip, _, source_port, _] = fields[..] else {
+        return Err(invalid("v1 header has the wrong number of fields"));
+    };
+    let ip: IpAddr = source_ip
+        .parse()
+        .map_err(|_| invalid("v1 header has a bad source address"))?;
+    if ip.is_ipv4() != (protocol == "TCP4") {
+        return Err(invalid("v1 source address does not match the protocol"));
+    }
+    let port: u16 = source_port
+        .parse()
+        .map_err(|_| invalid("v1 header has a bad source port"))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+// `header` is the 4 bytes after the signature: version/command, family and length
+pub fn parse_v2(header: &[u8; 4], body: &[u8]) -> Result<Option<SocketAddr>> {
+    let version = header[0] >> 4;
+    let command = header[0] & 0x0f;
+    if version != 2 {
+        return Err(invalid("unsupported v2 version"));
+    }
+    match command {
+        // LOCAL, the proxy talking to us itself
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => return Err(invalid("unknown v2 command")),
+    }
+
+    let family = header[1] >> 4;
+    match family {
+        // AF_INET: src addr, dst addr, src port, dst port
+        0x1 => {
+            if body.len() < 12 {
+                return Err(invalid("v2 ipv4 address block is too short"));
+            }
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6
+        0x2 => {
+            if body.len() < 36 {
+                return Err(invalid("v2 ipv6 address block is too short"));
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        // AF_UNSPEC or unix sockets, no address we could use
+        _ => Ok(None),
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::ProxyProtocol(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1_tcp4() {
+        let addr = parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 25565\r\n").unwrap();
+        assert_eq!(addr, Some("203.0.113.7:51234".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_tcp6_and_unknown() {
+        let addr = parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 25565\r\n").unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4000".parse().unwrap()));
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn v1_rejects_garbage() {
+        assert!(parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 2001:db8::1 10.0.0.1 1 2\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 25565").is_err());
+    }
+
+    #[test]
+    fn v2_ipv4() {
+        let body = [203, 0, 113, 7, 10, 0, 0, 1, 0xc8, 0x22, 0x63, 0xdd];
+        let addr = parse_v2(&[0x21, 0x11, 0x00, 12], &body).unwrap();
+        assert_eq!(addr, Some("203.0.113.7:51234".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_local_has_no_address() {
+        assert_eq!(parse_v2(&[0x20, 0x00, 0x00, 0x00], &[]).unwrap(), None);
+        assert!(parse_v2(&[0x11, 0x11, 0x00, 12], &[0; 12]).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_only_the_header() {
+        let mut stream: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 25565\r\n\x00\x07";
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(stream, b"\x00\x07");
+
+        let mut stream: &[u8] = b"\x00\x07 no header here at all";
+        assert!(read_header(&mut stream).await.is_err());
+    }
+}
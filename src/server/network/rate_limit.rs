@@ -0,0 +1,282 @@
+use std::net::IpAddr;
+
+use dashmap::DashMap;
+use tokio::time::{Duration, Instant};
+
+use crate::server::config::Config;
+use crate::server::network::packet::Packet;
+use crate::server::network::packets::serverbound::MessagePacket;
+
+// past this many tracked addresses, idle ones are forgotten on the next connect
+const PRUNE_THRESHOLD: usize = 256;
+
+// refills `rate` tokens a second up to `capacity`, every allowed action takes one
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    rate: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            capacity,
+            tokens: capacity,
+            rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_at(Instant::now())
+    }
+
+    pub fn try_take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    pub fn is_full_at(&self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens + elapsed * self.rate >= self.capacity
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rejection {
+    Blocked,
+    TooManyConnections,
+    ConnectingTooFast,
+}
+
+impl Rejection {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Rejection::Blocked => "You are temporarily blocked",
+            Rejection::TooManyConnections => "Too many connections from your address",
+            Rejection::ConnectingTooFast => "You are connecting too fast",
+        }
+    }
+}
+
+struct IpState {
+    connections: u32,
+    connects: TokenBucket,
+    // shared by every connection from the address, so opening more of them
+    // doesn't buy more packets or chat
+    packets: Option<TokenBucket>,
+    chat: Option<TokenBucket>,
+    blocked_until: Option<Instant>,
+}
+
+// per address bookkeeping shared by every connection
+pub struct IpLimiter {
+    ips: DashMap<IpAddr, IpState>,
+    max_connections: u32,
+    connections_per_minute: u32,
+    // rate and burst, a rate of 0 turns that bucket off
+    packets: (u32, u32),
+    chat: (u32, u32),
+    block_time: Duration,
+}
+
+impl IpLimiter {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            ips: DashMap::new(),
+            max_connections: config.max_connections_per_ip,
+            connections_per_minute: config.connections_per_minute,
+            packets: (config.packets_per_second, config.packet_burst),
+            chat: (config.chat_per_second, config.chat_burst),
+            block_time: Duration::from_secs(config.ip_block_secs),
+        }
+    }
+
+    // counts the connection against the address, every accepted connection
+    // has to be released again once it closes
+    pub fn connect(&self, ip: IpAddr) -> Result<(), Rejection> {
+        let now = Instant::now();
+        if self.ips.len() > PRUNE_THRESHOLD {
+            self.prune(now);
+        }
+
+        let per_minute = self.connections_per_minute as f64;
+        let bucket = |(rate, burst): (u32, u32)| {
+            (rate > 0).then(|| TokenBucket::new(rate as f64, rate.max(burst) as f64))
+        };
+        let mut state = self.ips.entry(ip).or_insert_with(|| IpState {
+            connections: 0,
+            connects: TokenBucket::new(per_minute / 60.0, per_minute),
+            packets: bucket(self.packets),
+            chat: bucket(self.chat),
+            blocked_until: None,
+        });
+
+        if state.blocked_until.is_some_and(|until| until > now) {
+            return Err(Rejection::Blocked);
+        }
+        state.blocked_until = None;
+
+        if self.max_connections > 0 && state.connections >= self.max_connections {
+            return Err(Rejection::TooManyConnections);
+        }
+        if self.connections_per_minute > 0 && !state.connects.try_take_at(now) {
+            drop(state);
+            self.block(ip);
+            return Err(Rejection::ConnectingTooFast);
+        }
+
+        state.connections += 1;
+        Ok(())
+    }
+
+    // the kick reason if this packet takes the address over one of the
+    // limits, addresses that never connected aren't limited
+    pub fn check(&self, ip: IpAddr, packet_id: u8) -> Option<&'static str> {
+        self.check_at(ip, packet_id, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, packet_id: u8, now: Instant) -> Option<&'static str> {
+        let mut state = self.ips.get_mut(&ip)?;
+        if let Some(packets) = &mut state.packets {
+            if !packets.try_take_at(now) {
+                return Some("Too many packets");
+            }
+        }
+        if packet_id == MessagePacket::ID {
+            if let Some(chat) = &mut state.chat {
+                if !chat.try_take_at(now) {
+                    return Some("You are chatting too fast");
+                }
+            }
+        }
+        None
+    }
+
+    pub fn release(&self, ip: IpAddr) {
+        if let Some(mut state) = self.ips.get_mut(&ip) {
+            state.connections = state.connections.saturating_sub(1);
+        }
+    }
+
+    pub fn block(&self, ip: IpAddr) {
+        if let Some(mut state) = self.ips.get_mut(&ip) {
+            state.blocked_until = Some(Instant::now() + self.block_time);
+            println!("Blocked {} for {}s", ip, self.block_time.as_secs());
+        }
+    }
+
+    fn prune(&self, now: Instant) {
+        self.ips.retain(|_, state| {
+            state.connections > 0
+                || state.blocked_until.is_some_and(|until| until > now)
+                || !state.connects.is_full_at(now)
+                || [&state.packets, &state.chat]
+                    .into_iter()
+                    .flatten()
+                    .any(|bucket| !bucket.is_full_at(now))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_burst_then_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 3.0);
+        assert!(bucket.try_take_at(start));
+        assert!(bucket.try_take_at(start));
+        assert!(bucket.try_take_at(start));
+        assert!(!bucket.try_take_at(start));
+
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_take_at(later));
+        assert!(!bucket.try_take_at(later));
+        assert!(bucket.is_full_at(later + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn limits_connections_per_address() {
+        let config = Config {
+            max_connections_per_ip: 2,
+            connections_per_minute: 0,
+            ..Config::default()
+        };
+        let limiter = IpLimiter::new(&config);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        assert_eq!(limiter.connect(ip), Ok(()));
+        assert_eq!(limiter.connect(ip), Ok(()));
+        assert_eq!(limiter.connect(ip), Err(Rejection::TooManyConnections));
+        limiter.release(ip);
+        assert_eq!(limiter.connect(ip), Ok(()));
+        assert_eq!(limiter.connect("10.0.0.2".parse().unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn connecting_too_fast_blocks_the_address() {
+        let config = Config {
+            max_connections_per_ip: 0,
+            connections_per_minute: 2,
+            ..Config::default()
+        };
+        let limiter = IpLimiter::new(&config);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        assert_eq!(limiter.connect(ip), Ok(()));
+        assert_eq!(limiter.connect(ip), Ok(()));
+        assert_eq!(limiter.connect(ip), Err(Rejection::ConnectingTooFast));
+        limiter.release(ip);
+        assert_eq!(limiter.connect(ip), Err(Rejection::Blocked));
+    }
+
+    #[test]
+    fn connections_from_one_address_share_their_limits() {
+        let config = Config {
+            max_connections_per_ip: 0,
+            connections_per_minute: 0,
+            packets_per_second: 4,
+            packet_burst: 4,
+            chat_per_second: 1,
+            chat_burst: 2,
+            ..Config::default()
+        };
+        let limiter = IpLimiter::new(&config);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        // two connections from the same address draw from the same bucket
+        assert_eq!(limiter.connect(ip), Ok(()));
+        assert_eq!(limiter.connect(ip), Ok(()));
+        assert_eq!(limiter.connect(other), Ok(()));
+        assert_eq!(limiter.check_at(ip, MessagePacket::ID, now), None);
+        assert_eq!(limiter.check_at(ip, MessagePacket::ID, now), None);
+        assert_eq!(
+            limiter.check_at(ip, MessagePacket::ID, now),
+            Some("You are chatting too fast")
+        );
+        assert_eq!(limiter.check_at(ip, 0x08, now), None);
+        assert_eq!(limiter.check_at(ip, 0x08, now), Some("Too many packets"));
+
+        // a second later there is room again, and other addresses never ran out
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check_at(ip, 0x08, later), None);
+        assert_eq!(limiter.check_at(other, MessagePacket::ID, now), None);
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use tokio::io::AsyncWrite;
+use tokio::sync::RwLock;
+
+use super::packet::Packet;
+use super::packets::serverbound::{
+    ExtEntryPacket, ExtInfoPacket, MessagePacket, PlayerIndentificationPacket,
+    PositionAndOrientationUpdatePacket, SetBlockPacket,
+};
+use crate::server::game::player_registry::ConnectionId;
+
+// the sending side of a connection, a tcp stream or the bridge to a websocket
+pub trait ClientWrite: AsyncWrite + Send + Sync + Unpin + Debug {}
+impl<T: AsyncWrite + Send + Sync + Unpin + Debug> ClientWrite for T {}
+
+pub type ClientSocket = Arc<RwLock<Box<dyn ClientWrite>>>;
+
+// Handshaking -> NegotiatingCpe (cpe clients only) -> Queued (server full only)
+// -> LoadingLevel -> Playing, any state can go to Disconnecting
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Handshaking,
+    NegotiatingCpe,
+    Queued,
+    LoadingLevel,
+    Playing,
+    Disconnecting,
+}
+
+impl ConnectionState {
+    pub fn accepts(&self, packet_id: u8) -> bool {
+        match self {
+            ConnectionState::Handshaking => packet_id == PlayerIndentificationPacket::ID,
+            ConnectionState::NegotiatingCpe => {
+                matches!(packet_id, ExtInfoPacket::ID | ExtEntryPacket::ID)
+            }
+            ConnectionState::Playing => matches!(
+                packet_id,
+                SetBlockPacket::ID | PositionAndOrientationUpdatePacket::ID | MessagePacket::ID
+            ),
+            ConnectionState::Queued
+            | ConnectionState::LoadingLevel
+            | ConnectionState::Disconnecting => false,
+        }
+    }
+
+    // still logging in and falls under the login timeout, queued connections
+    // are exempt since they wait for someone else to leave
+    pub fn is_logging_in(&self) -> bool {
+        matches!(
+            self,
+            ConnectionState::Handshaking
+                | ConnectionState::NegotiatingCpe
+                | ConnectionState::LoadingLevel
+        )
+    }
+}
+
+// per connection state owned by the read loop in handle_client
+pub struct Session {
+    pub connection_id: ConnectionId,
+    pub state: ConnectionState,
+    pub socket: ClientSocket,
+    // came in on a lan only listener, the name is taken as is
+    pub skip_auth: bool,
+    // kept while cpe is negotiated, the login finishes once all entries arrived
+    pub identification: Option<PlayerIndentificationPacket>,
+    // last join queue position the client was told about
+    pub queue_position: usize,
+    // none until the client's ExtInfo says how many entries follow
+    pub remaining_extensions: Option<i16>,
+    pub extensions: Vec<ExtEntryPacket>,
+}
+
+impl Session {
+    pub fn new(connection_id: ConnectionId, socket: ClientSocket, skip_auth: bool) -> Self {
+        Self {
+            connection_id,
+            state: ConnectionState::Handshaking,
+            socket,
+            skip_auth,
+            identification: None,
+            queue_position: 0,
+            remaining_extensions: None,
+            extensions: Vec::new(),
+        }
+    }
+}
+
+// a socket that throws away everything written to it, for tests that need a
+// player or a session
+#[cfg(test)]
+pub fn discard_socket() -> ClientSocket {
+    Arc::new(RwLock::new(Box::new(tokio::io::sink())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_state_accepts_its_own_packets() {
+        use ConnectionState::*;
+        let accepted = |state: ConnectionState| -> Vec<u8> {
+            (0..=0xFF).filter(|id| state.accepts(*id)).collect()
+        };
+        assert_eq!(accepted(Handshaking), vec![0x00]);
+        assert_eq!(accepted(NegotiatingCpe), vec![0x10, 0x11]);
+        assert_eq!(accepted(Playing), vec![0x05, 0x08, 0x0d]);
+        for state in [Queued, LoadingLevel, Disconnecting] {
+            assert!(accepted(state).is_empty());
+        }
+    }
+
+    #[test]
+    fn queued_and_playing_connections_have_no_login_timeout() {
+        use ConnectionState::*;
+        for state in [Handshaking, NegotiatingCpe, LoadingLevel] {
+            assert!(state.is_logging_in());
+        }
+        for state in [Queued, Playing, Disconnecting] {
+            assert!(!state.is_logging_in());
+        }
+    }
+}
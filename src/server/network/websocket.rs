@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{duplex, split, AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::server::error::{Error, Result};
+
+// the web client asks for this subprotocol and gives up if it isn't echoed back
+const SUBPROTOCOL: &str = "ClassiCube";
+const BRIDGE_BUFFER: usize = 64 * 1024;
+const PEEK_RETRY: Duration = Duration::from_millis(10);
+
+// classic clients open with packet 0x00, web clients with an http upgrade
+pub async fn is_websocket(stream: &TcpStream) -> Result<bool> {
+    let mut start = [0u8; 4];
+    // a peek can come back short, wait until the whole method is there. the
+    // socket stays readable while those bytes sit unread, so back off between
+    // peeks instead of spinning, the caller's timeout bounds the wait
+    loop {
+        let read = stream.peek(&mut start).await?;
+        if read == 0 || start[..read] != b"GET "[..read] {
+            return Ok(false);
+        }
+        if read == start.len() {
+            return Ok(true);
+        }
+        sleep(PEEK_RETRY).await;
+    }
+}
+
+// does the websocket handshake and returns a stream that carries the raw
+// protocol, so the connection can be handled like any tcp client
+pub async fn accept(stream: TcpStream) -> Result<DuplexStream> {
+    let websocket = tokio_tungstenite::accept_hdr_async(stream, echo_subprotocol)
+        .await
+        .map_err(|e| Error::WebSocket(e.to_string()))?;
+
+    let (client_side, server_side) = duplex(BRIDGE_BUFFER);
+    tokio::spawn(async move {
+        let (mut sink, mut messages) = websocket.split();
+        let (mut bridge_reader, mut bridge_writer) = split(server_side);
+        let mut buf = vec![0u8; BRIDGE_BUFFER];
+        loop {
+            tokio::select! {
+                message = messages.next() => match message {
+                    Some(Ok(Message::Binary(data))) => {
+                        if bridge_writer.write_all(&data).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    // pings are answered by tungstenite, text isn't part of the protocol
+                    Some(Ok(_)) => {}
+                },
+                read = bridge_reader.read(&mut buf) => match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if sink.send(Message::Binary(buf[..n].to_vec())).await.is_err() {
+                            break;
+                        }
+                    }
+                },
+            }
+        }
+        let _ = sink.close().await;
+        let _ = bridge_writer.shutdown().await;
+    });
+
+    Ok(client_side)
+}
+
+// the error type is fixed by tungstenite, nothing to box here
+#[allow(clippy::result_large_err)]
+fn echo_subprotocol(
+    request: &Request,
+    mut response: Response,
+) -> std::result::Result<Response, ErrorResponse> {
+    let wants_classicube = request
+        .headers()
+        .get("Sec-WebSocket-Protocol")
+        .and_then(|protocols| protocols.to_str().ok())
+        .is_some_and(|protocols| protocols.split(',').any(|p| p.trim() == SUBPROTOCOL));
+    if wants_classicube {
+        response.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static(SUBPROTOCOL),
+        );
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn connected() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_method_that_arrives_in_pieces() {
+        let (mut client, server) = connected().await;
+        client.write_all(b"GE").await.unwrap();
+        let check = tokio::spawn(async move { is_websocket(&server).await.unwrap() });
+        sleep(PEEK_RETRY * 3).await;
+        client.write_all(b"T / HTTP/1.1\r\n").await.unwrap();
+        assert!(check.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn tells_classic_clients_apart() {
+        let (mut client, server) = connected().await;
+        client.write_all(&[0x00, 0x07]).await.unwrap();
+        assert!(!is_websocket(&server).await.unwrap());
+    }
+}
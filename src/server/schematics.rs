@@ -0,0 +1,121 @@
+use crate::server::error::{Error, Result};
+use crate::server::files::write_atomically;
+use crate::server::formats::palette::Palette;
+use crate::server::formats::schematic::Schematic;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const EXTENSION: &str = "schem";
+const MAX_NAME_LENGTH: usize = 32;
+
+// prefabs saved with /schem, one .schem file each in a single folder
+#[derive(Debug, Clone)]
+pub struct Schematics {
+    dir: PathBuf,
+    palette: Arc<Palette>,
+}
+
+impl Schematics {
+    pub fn new(dir: impl Into<PathBuf>, palette: Palette) -> Self {
+        Self {
+            dir: dir.into(),
+            palette: Arc::new(palette),
+        }
+    }
+
+    // names without the extension, sorted
+    pub fn list(&self) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_none_or(|extension| extension != EXTENSION)
+            {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                names.push(name.to_string());
+            }
+        }
+        names.sort_unstable_by_key(|name| name.to_ascii_lowercase());
+        Ok(names)
+    }
+
+    pub fn save(&self, name: &str, schematic: &Schematic) -> Result<()> {
+        if !is_valid_name(name) {
+            return Err(Error::InvalidMap(format!(
+                "{} is not a valid schematic name",
+                name
+            )));
+        }
+        fs::create_dir_all(&self.dir)?;
+        let data = schematic.write(&self.palette)?;
+        let path = self.dir.join(format!("{}.{}", name, EXTENSION));
+        write_atomically(&path, |file| file.write_all(&data))?;
+        Ok(())
+    }
+
+    // the listed name matching ignoring case, only names that were listed
+    // can be loaded so a player can't point us outside the folder
+    pub fn find(&self, name: &str) -> io::Result<Option<String>> {
+        Ok(self
+            .list()?
+            .into_iter()
+            .find(|listed| listed.eq_ignore_ascii_case(name)))
+    }
+
+    pub fn load(&self, name: &str) -> Result<Schematic> {
+        let Some(name) = self.find(name)? else {
+            return Err(Error::InvalidMap(format!(
+                "there is no schematic called {}",
+                name
+            )));
+        };
+        let path = self.dir.join(format!("{}.{}", name, EXTENSION));
+        Schematic::read(BufReader::new(File::open(path)?), &self.palette)
+    }
+}
+
+// letters, numbers, _ and -, so the name is also a safe file name
+pub fn is_valid_name(name: &str) -> bool {
+    (1..=MAX_NAME_LENGTH).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::game::dmf_map::DmfMap;
+
+    #[test]
+    fn saves_lists_and_loads() {
+        let dir = std::env::temp_dir().join(format!("dandelion-schematics-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let schematics = Schematics::new(&dir, Palette::default());
+        assert!(schematics.list().unwrap().is_empty());
+
+        let mut map = DmfMap::new(0, 0, 0, 4, 4, 4);
+        map.set_block(1, 1, 1, 45);
+        let schematic = Schematic::copy(&map, (0, 0, 0), (2, 2, 2), (0, 0, 0)).unwrap();
+        schematics.save("House", &schematic).unwrap();
+        schematics.save("tower", &schematic).unwrap();
+        assert_eq!(schematics.list().unwrap(), vec!["House", "tower"]);
+        assert_eq!(schematics.find("house").unwrap().as_deref(), Some("House"));
+        assert_eq!(schematics.load("house").unwrap(), schematic);
+
+        assert!(schematics.save("../house", &schematic).is_err());
+        assert!(schematics.save("", &schematic).is_err());
+        assert!(schematics.load("../House").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,48 +1,106 @@
+use crate::server::network::packets::serverbound;
+use crate::server::network::proxy_protocol;
+use crate::server::network::rate_limit::IpLimiter;
+use crate::server::network::session::{ClientSocket, ConnectionState, Session};
 use crate::server::network::{heartbeat::start_heartbeat_loop, packet_resolver::PacketResolver};
-use dashmap::DashMap;
+use crate::server::network::{listener, websocket};
 use rand::Rng;
+use std::fmt::Debug;
 use std::iter::repeat_with;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::fs;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt, WriteHalf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::RwLock;
+use tokio::time::{sleep, timeout, timeout_at, Duration, Instant};
 
-use super::config::Config;
-use super::game::dmf_map::DmfMap;
-use super::game::player::Player;
-use super::map_builder::{Dimensions, MapBuilder, NoiseLayer, PresetParams};
-use super::maps;
+use super::config::{Config, ListenerConfig};
+use super::formats::palette::Palette;
+use super::game::entity_ids::EntityId;
+use super::game::player_registry::{ConnectionId, PlayerRegistry};
+use super::maps::MapManager;
+use super::schematics::Schematics;
+
+// how often queued connections re-check the queue even if nobody left
+const QUEUE_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
 
 pub struct Server {
-    pub connected_players: Arc<DashMap<i8, Player>>,
-    pub loaded_maps: Arc<DashMap<String, DmfMap>>,
+    pub connected_players: Arc<PlayerRegistry>,
+    pub maps: Arc<MapManager>,
+    pub schematics: Schematics,
     pub config: Arc<Config>,
     pub salt: String,
+    next_connection_id: AtomicU64,
+    next_entity_id: AtomicU32,
+    pub ip_limiter: IpLimiter,
 }
 
 impl Server {
     pub async fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let config = Arc::new(Config::load("server-config.yml")?);
+        let config = Config::load("server-config.yml")?;
+        Ok(Self::with_config(config, "maps", "schematics"))
+    }
+
+    pub fn with_config(
+        config: Config,
+        maps_dir: impl Into<PathBuf>,
+        schematics_dir: impl Into<PathBuf>,
+    ) -> Self {
+        let config = Arc::new(config);
         let salt = generate_salt(16);
-        let server = Server {
-            connected_players: Arc::new(DashMap::new()),
-            loaded_maps: Arc::new(DashMap::new()),
+        let ip_limiter = IpLimiter::new(&config);
+        Server {
+            connected_players: Arc::new(PlayerRegistry::new()),
+            maps: Arc::new(MapManager::new(maps_dir, &config)),
+            schematics: Schematics::new(
+                schematics_dir,
+                Palette::new(&config.block_palette).with_mapping(&config.modern_block_mapping),
+            ),
             config,
             salt,
-        };
+            next_connection_id: AtomicU64::new(1),
+            next_entity_id: AtomicU32::new(1),
+            ip_limiter,
+        }
+    }
 
-        Ok(server)
+    // whether web clients can reach us, server lists show this to players
+    pub fn accepts_websockets(&self) -> bool {
+        self.config
+            .public_listener()
+            .is_some_and(|listener| listener.websocket)
+    }
+
+    pub fn allocate_entity_id(&self) -> EntityId {
+        self.next_entity_id.fetch_add(1, Ordering::Relaxed)
     }
 
     pub async fn start(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
         println!("Initializing server...");
 
-        maps::load_all_maps_in("maps", Arc::clone(&self.loaded_maps)).await?;
+        self.maps.refresh_index()?;
+        self.maps.load_pinned().await;
+        if !self.maps.is_loaded(&self.config.default_map) {
+            return Err(format!(
+                "default map '{}' could not be loaded",
+                self.config.default_map
+            )
+            .into());
+        }
+        tokio::spawn({
+            let maps = Arc::clone(&self.maps);
+            async move {
+                maps.save_all_loop().await;
+            }
+        });
         tokio::spawn({
-            let maps = Arc::clone(&self.loaded_maps);
+            let maps = Arc::clone(&self.maps);
+            let players = Arc::clone(&self.connected_players);
             async move {
-                maps::save_all_loop(maps).await;
+                maps.unload_idle_loop(players).await;
             }
         });
 
@@ -66,32 +124,210 @@ impl Server {
                 resolver_clone.send_to_all_queued().await;
             }
         });
+        tokio::spawn({
+            let resolver_clone = Arc::clone(&resolver);
+            async move {
+                resolver_clone.entity_tracking_loop().await;
+            }
+        });
+
+        let listeners = listener::bind_all(&self.config.listeners)?;
+        if listeners.is_empty() {
+            return Err("no listeners configured".into());
+        }
+
+        let mut accept_loops = Vec::new();
+        for (listener, listener_config) in listeners.into_iter().zip(&self.config.listeners) {
+            println!(
+                "Server started on {}{}{}",
+                listener_config.addr,
+                if listener_config.lan_only {
+                    " (lan only)"
+                } else {
+                    ""
+                },
+                if listener_config.websocket {
+                    " (websocket)"
+                } else {
+                    ""
+                },
+            );
+            accept_loops.push(tokio::spawn(Arc::clone(&self).accept_loop(
+                listener,
+                Arc::new(listener_config.clone()),
+                Arc::clone(&resolver),
+            )));
+        }
+        tokio::select! {
+            _ = async {
+                for accept_loop in accept_loops {
+                    let _ = accept_loop.await;
+                }
+            } => {}
+            _ = shutdown_signal() => println!("Shutting down..."),
+        }
+
+        // whatever changed since the last save loop would be lost otherwise
+        self.maps.save_all().await;
 
-        let address = format!("{}:{}", self.config.addr, self.config.port);
-        let listener = TcpListener::bind(&address).await?;
-        println!("Server started on {}", &address);
+        Ok(())
+    }
 
+    // every listener feeds the same resolver
+    async fn accept_loop(
+        self: Arc<Self>,
+        listener: TcpListener,
+        listener_config: Arc<ListenerConfig>,
+        resolver: Arc<PacketResolver>,
+    ) {
         loop {
-            let (socket, addr) = listener.accept().await?;
-            println!("Client connected: {}", addr);
+            let (socket, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // usually out of file descriptors, which sorts itself out
+                    eprintln!("Error accepting on {}: {}", listener_config.addr, e);
+                    sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            let connection_id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
 
-            let resolver_clone = Arc::clone(&resolver);
-            tokio::spawn(handle_client(socket, resolver_clone));
+            tokio::spawn(accept_client(
+                connection_id,
+                socket,
+                addr,
+                Arc::clone(&listener_config),
+                Arc::clone(&resolver),
+            ));
+        }
+    }
+}
+
+// works out who is really on the other end and how they talk before
+// handing the connection over
+async fn accept_client(
+    connection_id: ConnectionId,
+    mut socket: TcpStream,
+    addr: SocketAddr,
+    listener: Arc<ListenerConfig>,
+    resolver: Arc<PacketResolver>,
+) {
+    let config = &resolver.server.config;
+    let header_timeout = Duration::from_secs(config.login_timeout_secs);
+    let mut client_addr = addr;
+    if config.proxy_protocol {
+        match timeout(header_timeout, proxy_protocol::read_header(&mut socket)).await {
+            Ok(Ok(Some(real_addr))) => client_addr = real_addr,
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => {
+                println!("Dropped connection from {}: {}", addr, e);
+                return;
+            }
+            Err(_) => {
+                println!("Dropped connection from {}: no proxy header in time", addr);
+                return;
+            }
         }
     }
+
+    let ip = client_addr.ip().to_canonical();
+    if listener.lan_only && !listener::is_lan_address(ip) {
+        println!(
+            "Dropped connection from {}: {} is lan only",
+            client_addr, listener.addr
+        );
+        return;
+    }
+
+    if client_addr == addr {
+        println!("Client connected: {} (connection {})", addr, connection_id);
+    } else {
+        println!(
+            "Client connected: {} via {} (connection {})",
+            client_addr, addr, connection_id
+        );
+    }
+
+    let is_websocket = listener.websocket
+        && matches!(
+            timeout(header_timeout, websocket::is_websocket(&socket)).await,
+            Ok(Ok(true))
+        );
+    let result = if is_websocket {
+        match websocket::accept(socket).await {
+            Ok(stream) => {
+                handle_client(connection_id, stream, ip, listener.lan_only, resolver).await
+            }
+            Err(e) => {
+                println!("Dropped connection {}: {}", connection_id, e);
+                return;
+            }
+        }
+    } else {
+        handle_client(connection_id, socket, ip, listener.lan_only, resolver).await
+    };
+    if let Err(e) = result {
+        eprintln!("Connection {} failed: {}", connection_id, e);
+    }
 }
 
-async fn handle_client(
-    socket: TcpStream,
+async fn handle_client<S>(
+    connection_id: ConnectionId,
+    socket: S,
+    ip: IpAddr,
+    lan_only: bool,
     resolver: Arc<PacketResolver>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let (mut reader, mut writer) = tokio::io::split(socket);
-    let reader = Arc::new(RwLock::new(reader));
-    let writer = Arc::new(RwLock::new(writer));
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin + Debug + 'static,
+{
+    let (mut reader, writer) = tokio::io::split(socket);
+    let writer: ClientSocket = Arc::new(RwLock::new(Box::new(writer)));
+    let mut session = Session::new(connection_id, Arc::clone(&writer), lan_only);
+
+    let ip_limiter = &resolver.server.ip_limiter;
+    if let Err(rejection) = ip_limiter.connect(ip) {
+        println!(
+            "Rejected connection {} from {}: {:?}",
+            connection_id, ip, rejection
+        );
+        resolver
+            .kick_session(&mut session, rejection.reason())
+            .await;
+        return Ok(());
+    }
+    let login_deadline =
+        Instant::now() + Duration::from_secs(resolver.server.config.login_timeout_secs);
     let mut buf = [0; 1024];
+    let mut pending: Vec<u8> = Vec::new();
+
+    'read: loop {
+        let read = if session.state == ConnectionState::Queued {
+            tokio::select! {
+                read = reader.read(&mut buf) => read,
+                _ = resolver.join_queue.changed() => {
+                    resolver.update_queued(&mut session).await;
+                    continue;
+                }
+                _ = sleep(QUEUE_UPDATE_INTERVAL) => {
+                    resolver.update_queued(&mut session).await;
+                    continue;
+                }
+            }
+        } else if session.state.is_logging_in() {
+            match timeout_at(login_deadline, reader.read(&mut buf)).await {
+                Ok(read) => read,
+                Err(_) => {
+                    println!("Connection {} timed out while logging in", connection_id);
+                    resolver.kick_session(&mut session, "Login timed out").await;
+                    break;
+                }
+            }
+        } else {
+            reader.read(&mut buf).await
+        };
 
-    loop {
-        let n = match reader.write().await.read(&mut buf).await {
+        let n = match read {
             Ok(0) => {
                 println!("Connection closed");
                 break;
@@ -102,10 +338,41 @@ async fn handle_client(
                 break;
             }
         };
+        pending.extend_from_slice(&buf[..n]);
 
-        resolver.handle_packet(&buf[..n], Arc::clone(&writer)).await;
+        // a single read can hold several packets or only part of one
+        while let Some(&packet_id) = pending.first() {
+            let Some(length) = serverbound::frame_length(packet_id) else {
+                eprintln!("Unknown packet ID: {}, dropping connection", packet_id);
+                resolver.kick_session(&mut session, "Unknown packet").await;
+                break 'read;
+            };
+            if pending.len() < length {
+                break;
+            }
+            // floods are cut off here, before the resolver does any work
+            if let Some(reason) = ip_limiter.check(ip, packet_id) {
+                println!(
+                    "Connection {} from {} flooded: {}",
+                    connection_id, ip, reason
+                );
+                ip_limiter.block(ip);
+                resolver.kick_session(&mut session, reason).await;
+                break 'read;
+            }
+            let frame: Vec<u8> = pending.drain(..length).collect();
+            resolver.handle_packet(&mut session, &frame).await;
+            if session.state == ConnectionState::Disconnecting {
+                break 'read;
+            }
+        }
     }
 
+    session.state = ConnectionState::Disconnecting;
+    resolver.join_queue.remove(connection_id);
+    resolver.disconnect(connection_id).await;
+    ip_limiter.release(ip);
+
     if let Err(e) = writer.write().await.shutdown().await {
         eprintln!("Error closing writer: {}", e);
     }
@@ -113,6 +380,50 @@ async fn handle_client(
     Ok(())
 }
 
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => eprintln!("Error listening for SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        eprintln!("Error listening for ctrl-c: {}", e);
+        // without a signal there is nothing to wait for
+        std::future::pending::<()>().await;
+    }
+}
+
+// a server in a fresh folder with a small map called main as the default,
+// for tests that go through the resolver
+#[cfg(test)]
+pub(crate) async fn test_resolver(test: &str, mut config: Config) -> Arc<PacketResolver> {
+    use super::game::dmf_map::DmfMap;
+
+    let dir =
+        std::env::temp_dir().join(format!("dandelion-server-{}-{}", test, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let maps = dir.join("maps");
+    std::fs::create_dir_all(&maps).unwrap();
+    DmfMap::new(4, 2, 4, 8, 8, 8)
+        .save_file(&maps.join("main.dmf").to_string_lossy())
+        .unwrap();
+    config.default_map = "main".to_string();
+
+    let server = Server::with_config(config, maps, dir.join("schematics"));
+    server.maps.refresh_index().unwrap();
+    server.maps.load_pinned().await;
+    Arc::new(PacketResolver::new(Arc::new(server)))
+}
+
 fn generate_salt(length: usize) -> String {
     const BASE62: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
 
@@ -122,3 +433,102 @@ fn generate_salt(length: usize) -> String {
         .take(length)
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::network::packet::Packet;
+    use crate::server::network::packets::clientbound::DisconnectPlayerPacket;
+    use crate::server::network::packets::serverbound::{
+        PlayerIndentificationPacket, SetBlockPacket,
+    };
+    use std::net::Ipv4Addr;
+    use tokio::io::DuplexStream;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+
+    type Handler = tokio::task::JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>>;
+
+    // the client's end of a connection handle_client is running on
+    fn connect(
+        resolver: &Arc<PacketResolver>,
+        connection_id: ConnectionId,
+    ) -> (DuplexStream, Handler) {
+        let (client, connection) = tokio::io::duplex(1 << 20);
+        let handler = tokio::spawn(handle_client(
+            connection_id,
+            connection,
+            IP,
+            false,
+            Arc::clone(resolver),
+        ));
+        (client, handler)
+    }
+
+    async fn kick_reason(client: &mut DuplexStream) -> String {
+        let mut sent = Vec::new();
+        client.read_to_end(&mut sent).await.unwrap();
+        DisconnectPlayerPacket::decode(&sent).unwrap().reason
+    }
+
+    #[tokio::test]
+    async fn kicks_clients_that_dont_log_in_in_time() {
+        let config = Config {
+            login_timeout_secs: 1,
+            ..Config::default()
+        };
+        let resolver = test_resolver("login-timeout", config).await;
+        let (mut client, handler) = connect(&resolver, 1);
+
+        assert_eq!(kick_reason(&mut client).await, "Login timed out");
+        handler.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn kicks_packets_sent_out_of_order() {
+        let resolver = test_resolver("out-of-state", Config::default()).await;
+        let (mut client, handler) = connect(&resolver, 1);
+
+        let set_block = SetBlockPacket {
+            x: 1,
+            y: 1,
+            z: 1,
+            mode: 1,
+            block_type: 1,
+        };
+        client.write_all(&set_block.encode()).await.unwrap();
+        assert_eq!(kick_reason(&mut client).await, "Unexpected packet");
+        handler.await.unwrap().unwrap();
+        assert!(resolver.server.connected_players.is_empty());
+    }
+
+    #[tokio::test]
+    async fn cleans_up_when_the_client_goes_away() {
+        let config = Config {
+            do_user_auth: false,
+            ..Config::default()
+        };
+        let resolver = test_resolver("client-gone", config).await;
+        let (mut client, handler) = connect(&resolver, 1);
+
+        let identification = PlayerIndentificationPacket {
+            protocol_version: 7,
+            username: "alice".to_string(),
+            verification_key: String::new(),
+            unused: 0,
+        };
+        client.write_all(&identification.encode()).await.unwrap();
+        let players = &resolver.server.connected_players;
+        timeout(Duration::from_secs(5), async {
+            while players.find_by_name("alice").is_none() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        drop(client);
+        handler.await.unwrap().unwrap();
+        assert!(players.is_empty());
+    }
+}